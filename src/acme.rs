@@ -0,0 +1,758 @@
+//! Obtaining and renewing certificates from an ACME (RFC 8555) CA, such as Let's Encrypt.
+//!
+//! Certificates with `source = "acme"` are issued for their `domains` and written to their
+//! `certificate_chain_path` and `private_key_path`, from where they are loaded like any other
+//! certificate. An existing certificate is only replaced once it is within `renew_before_days`
+//! of expiring, or if it no longer covers all of the `domains`.
+//!
+//! ```toml
+//! [acme]
+//! account_key_path = "acme-account.pem"
+//! agree_tos = true
+//! contact = ["admin@example.net"]
+//! challenge.type = "http-01"
+//!
+//! [certs.default]
+//! source = "acme"
+//! domains = ["router.example.net"]
+//! certificate_chain_path = "router.pem"
+//! private_key_path = "router.key"
+//! ```
+
+use std::{collections::HashMap, fs, net::SocketAddr, path::Path, sync::{Arc, Mutex}, time::Duration};
+
+use anyhow::{anyhow, bail, Context, Result};
+use figment::Figment;
+use openssl::{asn1::Asn1Time, bn::{BigNum, BigNumContext}, ec::{EcGroup, EcKey}, ecdsa::EcdsaSig, hash::MessageDigest, nid::Nid, pkey::{PKey, Private}, sha::sha256, stack::Stack, x509::{extension::SubjectAlternativeName, X509NameBuilder, X509ReqBuilder, X509}};
+use reqwest::{header::{HeaderMap, CONTENT_TYPE, LOCATION}, Response};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use tokio::{io::AsyncWriteExt, net::TcpListener, process::Command, task::JoinHandle};
+use tracing::{debug, info, warn};
+use url::Url;
+
+use crate::{config::CredentialPathBuf, file::{write_atomic, write_atomic_all}};
+
+/// The `[acme]` table
+#[derive(Deserialize, Debug)]
+pub struct Config {
+    /// The CA's directory URL. Defaults to the Let's Encrypt production directory.
+    #[serde(default = "Config::default_directory")]
+    directory: Url,
+
+    /// The account key (ECDSA P-256, PEM). Created if it doesn't exist.
+    account_key_path: CredentialPathBuf,
+
+    /// Email addresses for the account, e.g. for expiry notices
+    #[serde(default)]
+    contact: Vec<String>,
+
+    /// Agree to the CA's terms of service, which registering an account requires
+    #[serde(default)]
+    agree_tos: bool,
+
+    #[serde(default = "Config::default_renew_before_days")]
+    renew_before_days: u32,
+
+    challenge: Challenge,
+}
+
+impl Config {
+    fn default_directory() -> Url {
+        Url::parse("https://acme-v02.api.letsencrypt.org/directory").expect("valid directory URL")
+    }
+
+    fn default_renew_before_days() -> u32 {
+        30
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type")]
+enum Challenge {
+    /// Respond to the CA from a built-in HTTP server, which must be reachable on port 80 of every domain
+    #[serde(rename = "http-01")]
+    Http01 {
+        #[serde(default = "Challenge::default_listen")]
+        listen: SocketAddr,
+    },
+
+    /// Run `command` to create (and later remove) the `_acme-challenge` TXT record for each domain.
+    ///
+    /// `RCI_ACME_ACTION` is `present` or `cleanup`, and `RCI_ACME_DOMAIN`, `RCI_ACME_RECORD`
+    /// and `RCI_ACME_VALUE` describe the record.
+    #[serde(rename = "dns-01")]
+    Dns01 {
+        command: String,
+
+        /// How long to wait after creating the records before asking the CA to check them
+        #[serde(default)]
+        propagation_seconds: u64,
+    },
+}
+
+impl Challenge {
+    fn default_listen() -> SocketAddr {
+        "[::]:80".parse().expect("valid listen address")
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Challenge::Http01 { .. } => "http-01",
+            Challenge::Dns01 { .. } => "dns-01",
+        }
+    }
+}
+
+/// The parts of a `[certs]` entry relevant to ACME
+#[derive(Deserialize, Debug)]
+struct Certificate {
+    source: Option<String>,
+
+    #[serde(default)]
+    domains: Vec<String>,
+
+    certificate_chain_path: Option<CredentialPathBuf>,
+    private_key_path: Option<CredentialPathBuf>,
+}
+
+#[derive(Deserialize)]
+struct RawConfig {
+    acme: Option<Config>,
+
+    #[serde(rename = "certs", default)]
+    certificates: HashMap<String, Certificate>,
+
+    user_agent: Option<String>,
+}
+
+/// Issue or renew every `source = "acme"` certificate in the config that needs it
+pub async fn renew_certificates(f: &Figment) -> Result<()> {
+    let raw: RawConfig = f.extract().map_err(crate::config::config_error)?;
+
+    let mut certificates = raw.certificates.into_iter()
+        .filter_map(|(name, cert)| match cert.source.as_deref() {
+            None => None,
+            Some("acme") => Some(Ok((name, cert))),
+            Some(other) => Some(Err(anyhow!("unknown source \"{other}\" for certificate \"{name}\""))),
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    certificates.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let Some((first, _)) = certificates.first() else {
+        return Ok(());
+    };
+
+    let Some(config) = raw.acme else {
+        bail!("certificate \"{first}\" has source = \"acme\", but there is no [acme] config");
+    };
+
+    // the account is only set up if something needs renewing
+    let mut account = None;
+
+    for (name, cert) in &certificates {
+        let (Some(chain_path), Some(key_path)) = (&cert.certificate_chain_path, &cert.private_key_path) else {
+            bail!("certificate \"{name}\" has source = \"acme\", so certificate_chain_path and private_key_path are required");
+        };
+
+        if cert.domains.is_empty() {
+            bail!("certificate \"{name}\" has source = \"acme\", so at least one of `domains` is required");
+        }
+
+        if let Some(reason) = renewal_reason(chain_path, &cert.domains, config.renew_before_days)
+            .with_context(|| format!("failed to check certificate \"{name}\""))?
+        {
+            info!("obtaining certificate \"{name}\" for {} ({reason})", cert.domains.join(", "));
+        } else {
+            debug!("certificate \"{name}\" doesn't need renewing");
+            continue;
+        }
+
+        let account = match &mut account {
+            Some(account) => account,
+            None => account.insert(Account::new(&config, raw.user_agent.as_deref()).await.context("failed to set up ACME account")?),
+        };
+
+        let (chain, key) = account.issue(&cert.domains, &config.challenge).await
+            .with_context(|| format!("failed to obtain certificate \"{name}\""))?;
+
+        write_atomic_all(&[(key_path, &key, 0o600), (chain_path, &chain, 0o644)])?;
+
+        info!("obtained certificate \"{name}\"");
+    }
+
+    Ok(())
+}
+
+/// Why the certificate at `path` should be (re-)issued, or `None` if it's fine as it is
+fn renewal_reason(path: &Path, domains: &[String], renew_before_days: u32) -> Result<Option<String>> {
+    if !path.exists() {
+        return Ok(Some("no existing certificate".to_string()));
+    }
+
+    let pem = fs::read(path).with_context(|| format!("failed to read \"{}\"", path.display()))?;
+    let leaf = X509::stack_from_pem(&pem)?.into_iter().next()
+        .ok_or_else(|| anyhow!("no certificates found in \"{}\"", path.display()))?;
+
+    if leaf.not_after() < Asn1Time::days_from_now(renew_before_days)? {
+        return Ok(Some(format!("expires {}", leaf.not_after())));
+    }
+
+    let names = leaf.subject_alt_names()
+        .map(|names| names.iter().filter_map(|n| n.dnsname().map(str::to_ascii_lowercase)).collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    if let Some(missing) = domains.iter().find(|d| !names.contains(&d.to_ascii_lowercase())) {
+        return Ok(Some(format!("doesn't cover {missing}")));
+    }
+
+    Ok(None)
+}
+
+/// base64url without padding, as used throughout JWS
+fn b64(data: &[u8]) -> String {
+    openssl::base64::encode_block(data)
+        .trim_end_matches('=')
+        .replace('+', "-")
+        .replace('/', "_")
+}
+
+fn generate_key() -> Result<EcKey<Private>> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+
+    Ok(EcKey::generate(&group)?)
+}
+
+fn load_or_create_account_key(path: &Path) -> Result<EcKey<Private>> {
+    if path.exists() {
+        let pem = fs::read(path).with_context(|| format!("failed to read \"{}\"", path.display()))?;
+
+        return EcKey::private_key_from_pem(&pem)
+            .with_context(|| format!("failed to load ACME account key \"{}\" (it must be an ECDSA P-256 key)", path.display()));
+    }
+
+    info!("creating ACME account key \"{}\"", path.display());
+    let key = generate_key()?;
+    write_atomic(path, &key.private_key_to_pem()?, 0o600)?;
+
+    Ok(key)
+}
+
+/// The JWK (RFC 7517) of the public half of a P-256 key, with its members in the order
+/// required for the thumbprint (RFC 7638)
+fn jwk(key: &EcKey<Private>) -> Result<String> {
+    let mut ctx = BigNumContext::new()?;
+    let (mut x, mut y) = (BigNum::new()?, BigNum::new()?);
+    key.public_key().affine_coordinates(key.group(), &mut x, &mut y, &mut ctx)?;
+
+    Ok(format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, b64(&x.to_vec_padded(32)?), b64(&y.to_vec_padded(32)?)))
+}
+
+/// An ES256 signature: the raw `r || s`, not DER
+fn sign(key: &EcKey<Private>, data: &[u8]) -> Result<Vec<u8>> {
+    let signature = EcdsaSig::sign(&sha256(data), key)?;
+
+    let mut raw = signature.r().to_vec_padded(32)?;
+    raw.extend(signature.s().to_vec_padded(32)?);
+
+    Ok(raw)
+}
+
+fn csr(domains: &[String], key: &PKey<Private>) -> Result<Vec<u8>> {
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, &domains[0])?;
+
+    let mut builder = X509ReqBuilder::new()?;
+    builder.set_subject_name(&name.build())?;
+    builder.set_pubkey(key)?;
+
+    let mut san = SubjectAlternativeName::new();
+    for domain in domains {
+        san.dns(domain);
+    }
+
+    let mut extensions = Stack::new()?;
+    extensions.push(san.build(&builder.x509v3_context(None))?)?;
+    builder.add_extensions(&extensions)?;
+
+    builder.sign(key, MessageDigest::sha256())?;
+
+    Ok(builder.build().to_der()?)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+
+    #[serde(default)]
+    meta: DirectoryMeta,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct DirectoryMeta {
+    terms_of_service: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct Problem {
+    #[serde(rename = "type", default)]
+    kind: String,
+
+    #[serde(default)]
+    detail: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+    error: Option<Problem>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    challenges: Vec<AuthorizationChallenge>,
+}
+
+#[derive(Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct AuthorizationChallenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: String,
+    error: Option<Problem>,
+}
+
+/// How long to wait for the CA to validate challenges and issue the certificate
+const POLL_ATTEMPTS: u32 = 60;
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+struct Account {
+    http: reqwest::Client,
+    directory: Directory,
+    key: EcKey<Private>,
+    jwk: String,
+
+    /// the account URL, once registered
+    kid: Option<String>,
+
+    nonce: Option<String>,
+}
+
+impl Account {
+    /// Register (or look up the existing registration of) the account key, identifying as `user_agent`
+    async fn new(config: &Config, user_agent: Option<&str>) -> Result<Self> {
+        let http = crate::http::client_builder(user_agent)
+            .build().context("failed to build a Client")?;
+
+        let directory: Directory = http.get(config.directory.clone())
+            .send().await.context("failed to send request")?
+            .error_for_status().context("failed to fetch ACME directory")?
+            .json().await.context("failed to decode ACME directory")?;
+
+        // only the user can agree to them
+        if !config.agree_tos {
+            let terms = match &directory.meta.terms_of_service {
+                Some(url) => format!("the CA's terms of service ({url})"),
+                None => "the CA's terms of service".to_string(),
+            };
+
+            bail!("registering an ACME account requires agreeing to {terms}: read them and set `acme.agree_tos = true`");
+        }
+
+        let key = load_or_create_account_key(&config.account_key_path)?;
+
+        let mut account = Account { http, directory, jwk: jwk(&key)?, key, kid: None, nonce: None };
+
+        let contact = config.contact.iter().map(|c| format!("mailto:{c}")).collect::<Vec<_>>();
+        let url = account.directory.new_account.clone();
+        let response = account.post(&url, Some(&json!({ "termsOfServiceAgreed": true, "contact": contact }))).await?;
+
+        account.kid = Some(location(response.headers())?);
+
+        Ok(account)
+    }
+
+    async fn new_nonce(&self) -> Result<String> {
+        let response = self.http.head(&self.directory.new_nonce)
+            .send().await.context("failed to send request")?
+            .error_for_status().context("failed to get a new nonce")?;
+
+        replay_nonce(response.headers()).ok_or_else(|| anyhow!("no Replay-Nonce in response"))
+    }
+
+    /// POST a JWS-signed `payload`, or a POST-as-GET if `payload` is `None`
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<Response> {
+        // a nonce can be rejected as stale, in which case the error carries a fresh one
+        for attempt in 0..2 {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?,
+            };
+
+            let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+            match &self.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = serde_json::from_str(&self.jwk)?,
+            }
+
+            let protected = b64(&serde_json::to_vec(&protected)?);
+            let payload = match payload {
+                Some(payload) => b64(&serde_json::to_vec(payload)?),
+                None => String::new(),
+            };
+            let signature = b64(&sign(&self.key, format!("{protected}.{payload}").as_bytes())?);
+
+            let body = json!({ "protected": protected, "payload": payload, "signature": signature });
+
+            let response = self.http.post(url)
+                .header(CONTENT_TYPE, "application/jose+json")
+                .body(serde_json::to_vec(&body)?)
+                .send().await.context("failed to send request")?;
+
+            self.nonce = replay_nonce(response.headers());
+
+            if response.status().is_success() {
+                return Ok(response);
+            }
+
+            let status = response.status();
+            let problem: Problem = response.json().await.unwrap_or_default();
+
+            if problem.kind == "urn:ietf:params:acme:error:badNonce" && attempt == 0 {
+                debug!("nonce rejected, retrying");
+                continue;
+            }
+
+            bail!("ACME request to {url} failed ({status}): {} ({})", problem.detail, problem.kind);
+        }
+
+        unreachable!("the last attempt returns")
+    }
+
+    async fn get<T: DeserializeOwned>(&mut self, url: &str) -> Result<T> {
+        self.post(url, None).await?
+            .json().await.with_context(|| format!("failed to decode response from {url}"))
+    }
+
+    /// POST-as-GET `url` until `done` is true of the resource
+    async fn poll<T: DeserializeOwned>(&mut self, url: &str, done: impl Fn(&T) -> bool) -> Result<T> {
+        for _ in 0..POLL_ATTEMPTS {
+            let resource = self.get(url).await?;
+
+            if done(&resource) {
+                return Ok(resource);
+            }
+
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+
+        bail!("timed out waiting for {url}")
+    }
+
+    fn key_authorization(&self, token: &str) -> String {
+        format!("{token}.{}", b64(&sha256(self.jwk.as_bytes())))
+    }
+
+    /// Order, validate and download a certificate for `domains`, returning the PEM chain and PEM private key
+    async fn issue(&mut self, domains: &[String], challenge: &Challenge) -> Result<(Vec<u8>, Vec<u8>)> {
+        let identifiers = domains.iter().map(|d| json!({ "type": "dns", "value": d })).collect::<Vec<_>>();
+
+        let url = self.directory.new_order.clone();
+        let response = self.post(&url, Some(&json!({ "identifiers": identifiers }))).await?;
+        let order_url = location(response.headers())?;
+        let order: Order = response.json().await.context("failed to decode order")?;
+
+        let responder = match challenge {
+            Challenge::Http01 { listen } => {
+                let listener = TcpListener::bind(listen).await
+                    .with_context(|| format!("failed to listen on {listen} for http-01 challenges"))?;
+
+                Some(HttpResponder::start(listener))
+            },
+            Challenge::Dns01 { .. } => None,
+        };
+
+        for authorization_url in &order.authorizations {
+            let authorization: Authorization = self.get(authorization_url).await?;
+            let domain = authorization.identifier.value;
+
+            if authorization.status == "valid" {
+                debug!("{domain} is already authorized");
+                continue;
+            }
+
+            let offered = authorization.challenges.into_iter()
+                .find(|c| c.kind == challenge.name())
+                .ok_or_else(|| anyhow!("the CA didn't offer a {} challenge for {domain}", challenge.name()))?;
+
+            let key_authorization = self.key_authorization(&offered.token);
+            let record = format!("_acme-challenge.{}", domain.trim_start_matches("*."));
+            let value = b64(&sha256(key_authorization.as_bytes()));
+
+            match (challenge, &responder) {
+                (Challenge::Http01 { .. }, Some(responder)) => responder.insert(&offered.token, &key_authorization),
+                (Challenge::Dns01 { command, propagation_seconds }, _) => {
+                    run_dns_command(command, "present", &domain, &record, &value).await?;
+
+                    if *propagation_seconds > 0 {
+                        info!("waiting {propagation_seconds}s for DNS propagation");
+                        tokio::time::sleep(Duration::from_secs(*propagation_seconds)).await;
+                    }
+                },
+                _ => unreachable!("a responder is started for http-01"),
+            }
+
+            info!("validating {domain} with {}", challenge.name());
+            let result = async {
+                self.post(&offered.url, Some(&json!({}))).await?;
+
+                self.poll(authorization_url, |a: &Authorization| a.status != "pending" && a.status != "processing").await
+            }.await;
+
+            if let Challenge::Dns01 { command, .. } = challenge {
+                if let Err(e) = run_dns_command(command, "cleanup", &domain, &record, &value).await {
+                    warn!("{e:#}");
+                }
+            }
+
+            let authorization = result?;
+            if authorization.status != "valid" {
+                let detail = authorization.challenges.iter()
+                    .find(|c| c.kind == challenge.name())
+                    .and_then(|c| c.error.as_ref())
+                    .map(|e| e.detail.as_str())
+                    .unwrap_or("no details given");
+
+                bail!("{} challenge for {domain} failed ({}): {detail}", challenge.name(), authorization.status);
+            }
+        }
+
+        drop(responder);
+
+        let key = PKey::from_ec_key(generate_key()?)?;
+
+        info!("finalizing order");
+        self.post(&order.finalize, Some(&json!({ "csr": b64(&csr(domains, &key)?) }))).await?;
+
+        let order: Order = self.poll(&order_url, |o: &Order| o.status != "pending" && o.status != "processing" && o.status != "ready").await?;
+        if order.status != "valid" {
+            let detail = order.error.map(|e| e.detail).unwrap_or_else(|| "no details given".to_string());
+            bail!("order failed ({}): {detail}", order.status);
+        }
+
+        let certificate_url = order.certificate.ok_or_else(|| anyhow!("valid order has no certificate URL"))?;
+        let chain = self.post(&certificate_url, None).await?
+            .bytes().await.context("failed to download certificate")?;
+
+        Ok((chain.to_vec(), key.private_key_to_pem_pkcs8()?))
+    }
+}
+
+fn replay_nonce(headers: &HeaderMap) -> Option<String> {
+    headers.get("Replay-Nonce")?.to_str().ok().map(str::to_string)
+}
+
+fn location(headers: &HeaderMap) -> Result<String> {
+    headers.get(LOCATION)
+        .and_then(|l| l.to_str().ok())
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("no Location in response"))
+}
+
+async fn run_dns_command(command: &str, action: &str, domain: &str, record: &str, value: &str) -> Result<()> {
+    info!("running dns-01 {action} command for {domain}");
+
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("RCI_ACME_ACTION", action)
+        .env("RCI_ACME_DOMAIN", domain)
+        .env("RCI_ACME_RECORD", record)
+        .env("RCI_ACME_VALUE", value)
+        .status().await
+        .with_context(|| format!("failed to run dns-01 {action} command for {domain}"))?;
+
+    if !status.success() {
+        bail!("dns-01 {action} command for {domain} failed ({status})");
+    }
+
+    Ok(())
+}
+
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Serves `/.well-known/acme-challenge/<token>` for http-01 challenges until dropped
+struct HttpResponder {
+    tokens: Arc<Mutex<HashMap<String, String>>>,
+    task: JoinHandle<()>,
+}
+
+impl HttpResponder {
+    fn start(listener: TcpListener) -> Self {
+        let tokens = Arc::new(Mutex::new(HashMap::new()));
+
+        let task = tokio::spawn({
+            let tokens = tokens.clone();
+
+            async move {
+                while let Ok((mut stream, peer)) = listener.accept().await {
+                    let tokens = tokens.clone();
+
+                    tokio::spawn(async move {
+                        if let Err(e) = Self::respond(&mut stream, &tokens).await {
+                            debug!("http-01 request from {peer} failed: {e}");
+                        }
+                    });
+                }
+            }
+        });
+
+        HttpResponder { tokens, task }
+    }
+
+    fn insert(&self, token: &str, key_authorization: &str) {
+        self.tokens.lock().expect("tokens lock").insert(token.to_string(), key_authorization.to_string());
+    }
+
+    async fn respond(stream: &mut tokio::net::TcpStream, tokens: &Mutex<HashMap<String, String>>) -> Result<()> {
+        let request = crate::http::read_request_head(stream, MAX_REQUEST_SIZE).await?;
+
+        let request = String::from_utf8_lossy(&request);
+        let path = request.split_whitespace().nth(1).unwrap_or_default();
+
+        let body = path.strip_prefix("/.well-known/acme-challenge/")
+            .and_then(|token| tokens.lock().expect("tokens lock").get(token).cloned());
+
+        debug!("http-01 request for {path}");
+
+        let response = match body {
+            Some(body) => format!("HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len()),
+            None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+        };
+
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await?;
+
+        Ok(())
+    }
+}
+
+impl Drop for HttpResponder {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use openssl::ecdsa::EcdsaSig;
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    #[test]
+    fn test_sign() {
+        let key = generate_key().unwrap();
+
+        let signature = sign(&key, b"header.payload").unwrap();
+        assert_eq!(signature.len(), 64);
+
+        let r = BigNum::from_slice(&signature[..32]).unwrap();
+        let s = BigNum::from_slice(&signature[32..]).unwrap();
+        let signature = EcdsaSig::from_private_components(r, s).unwrap();
+        assert!(signature.verify(&sha256(b"header.payload"), &key).unwrap());
+
+        let jwk: Value = serde_json::from_str(&jwk(&key).unwrap()).unwrap();
+        assert_eq!(jwk["kty"], "EC");
+        assert_eq!(jwk["x"].as_str().unwrap().len(), 43);
+
+        assert_eq!(b64(&[0xfb, 0xff]), "-_8");
+    }
+
+    #[test]
+    fn test_renewal_reason() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fullchain.pem");
+        let domains = ["device.example.net".to_string()];
+
+        assert!(renewal_reason(&path, &domains, 30).unwrap().is_some());
+
+        // test certificates are valid for 90 days
+        let pair = crate::test_util::certificate_pair(&["device.example.net"]);
+        fs::write(&path, pair.fullchain_certificate_pem_string().unwrap()).unwrap();
+
+        assert_eq!(renewal_reason(&path, &domains, 30).unwrap(), None);
+        assert!(renewal_reason(&path, &domains, 400).unwrap().unwrap().starts_with("expires"));
+
+        let more = ["device.example.net".to_string(), "other.example.net".to_string()];
+        assert_eq!(renewal_reason(&path, &more, 30).unwrap().as_deref(), Some("doesn't cover other.example.net"));
+    }
+
+    #[tokio::test]
+    async fn test_agree_tos_required() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let directory = format!("http://{}/directory", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            crate::http::read_request_head(&mut stream, MAX_REQUEST_SIZE).await.unwrap();
+
+            let body = r#"{"newNonce": "n", "newAccount": "a", "newOrder": "o", "meta": {"termsOfService": "https://ca.example.net/terms.pdf"}}"#;
+            let response = format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}", body.len());
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let config: Config = Figment::from(figment::providers::Serialized::defaults(json!({
+            "directory": directory,
+            "account_key_path": dir.path().join("account.pem"),
+            "challenge": { "type": "http-01" },
+        }))).extract().unwrap();
+
+        let e = Account::new(&config, None).await.err().unwrap();
+        assert_eq!(e.to_string(), "registering an ACME account requires agreeing to the CA's terms of service \
+            (https://ca.example.net/terms.pdf): read them and set `acme.agree_tos = true`");
+
+        // nothing is created until the terms are agreed to
+        assert!(!dir.path().join("account.pem").exists());
+    }
+
+    #[tokio::test]
+    async fn test_http_responder() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let responder = HttpResponder::start(listener);
+        responder.insert("token", "token.thumbprint");
+
+        let get = |path: &'static str| async move {
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            stream.write_all(format!("GET {path} HTTP/1.1\r\nHost: example.net\r\n\r\n").as_bytes()).await.unwrap();
+
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+
+        let response = get("/.well-known/acme-challenge/token").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(response.ends_with("\r\n\r\ntoken.thumbprint"), "{response}");
+
+        let response = get("/.well-known/acme-challenge/other").await;
+        assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+    }
+}
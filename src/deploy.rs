@@ -0,0 +1,436 @@
+//! Replacing files on a remote over SSH, such that they change together.
+//!
+//! Each file is first uploaded alongside its destination (so it is on the same filesystem),
+//! then all are renamed into place in a single command once every upload has completed.
+//! The previous files are kept as backups until the caller either [`Staged::finish`]es
+//! or [`Staged::rollback`]s -- e.g., after a failed config check or reload.
+
+use anyhow::{anyhow, bail, Context, Result};
+use russh::client::Handle;
+use serde::{Deserialize, Serialize, Serializer};
+use tracing::{debug, info, warn};
+
+use crate::{error::RciError, sftp::{self, FileAttributes}, ssh::{exec, shell_quote, ClientHandler}, timing};
+
+/// A file to be written to the remote
+pub struct File<'a> {
+    pub path: &'a str,
+    pub contents: &'a [u8],
+    pub attrs: FileAttributes,
+
+    /// `chown` argument (`owner`, `owner:group` or `:group`), applied before the file is moved into place
+    pub owner: Option<String>,
+
+    pub preserve: Preserve,
+}
+
+/// What the new file keeps of the file it replaces, if there is one
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Preserve {
+    /// The mode, rather than the one in `attrs`
+    pub mode: bool,
+
+    /// The owner and group. Only a warning if it can't be, e.g. as the file belongs to another user
+    pub owner: bool,
+}
+
+/// How files are uploaded, set by `ssh.transfer`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Transfer {
+    Sftp,
+
+    /// Sent to `scp -t` over an exec channel, for hosts with only the legacy SCP protocol.
+    /// Only the mode of the file's attributes is applied
+    Scp,
+
+    /// Piped to `cat` over an exec channel, for hosts without an SFTP server or `scp` (e.g., OpenWrt's dropbear).
+    /// Only the mode of the file's attributes is applied
+    ExecCat,
+}
+
+/// The `chown` argument for an optional owner and group
+pub fn chown_spec(owner: Option<&str>, group: Option<&str>) -> Option<String> {
+    match (owner, group) {
+        (Some(owner), Some(group)) => Some(format!("{owner}:{group}")),
+        (Some(owner), None) => Some(owner.to_string()),
+        (None, Some(group)) => Some(format!(":{group}")),
+        (None, None) => None,
+    }
+}
+
+/// Check a configured file mode is valid, and that a private key wouldn't be readable by anyone
+pub fn check_mode(key: &str, mode: u32, private: bool) -> Result<()> {
+    if mode > 0o7777 {
+        bail!("`{key}` {mode:o} isn't a valid file mode");
+    }
+
+    if private && mode & 0o007 != 0 {
+        bail!("`{key}` {mode:04o} would let any user access the private key");
+    }
+
+    Ok(())
+}
+
+/// Serialize a configured file mode in octal, as it would be written in the config
+pub fn serialize_mode<S: Serializer>(mode: &Option<u32>, serializer: S) -> Result<S::Ok, S::Error> {
+    match mode {
+        Some(mode) => serializer.serialize_str(&format!("{mode:04o}")),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Files that have been uploaded but not yet moved into place
+pub struct Staged<'a> {
+    handle: &'a Handle<ClientHandler>,
+    paths: Vec<String>,
+}
+
+fn new_path(path: &str) -> String {
+    format!("{path}.rci-new")
+}
+
+fn backup_path(path: &str) -> String {
+    format!("{path}.rci-backup")
+}
+
+/// Marks a file that didn't exist before [`Staged::commit`], so a rollback removes it
+fn absent_path(path: &str) -> String {
+    format!("{path}.rci-absent")
+}
+
+/// Run a script of shell commands on the remote via `sh -c`
+async fn run(handle: &Handle<ClientHandler>, script: &str, what: &str) -> Result<()> {
+    debug!("running {what}: {script}");
+
+    exec(handle, &format!("sh -c {}", shell_quote(script)), &[]).await?
+        .check(what)?;
+
+    Ok(())
+}
+
+/// The shell commands that write stdin to `path` with `mode`, without it ever being readable by others beforehand
+fn shell_upload_script(path: &str, mode: u32) -> String {
+    let path = shell_quote(path);
+
+    format!("umask 077 && cat > {path} && chmod {mode:o} {path}")
+}
+
+/// The shell commands that give the upload for `path` the mode of the file at `path`, if there is one.
+/// `stat -f` is the BSD equivalent of GNU and busybox `stat -c`
+fn preserve_mode_script(path: &str) -> String {
+    let (new, path) = (shell_quote(&new_path(path)), shell_quote(path));
+
+    format!("if [ -e {path} ]; then chmod \"$(stat -c %a {path} 2>/dev/null || stat -f %Lp {path})\" {new}; fi")
+}
+
+/// The shell commands that give the upload for `path` the owner and group of the file at `path`, if there is one
+fn preserve_owner_script(path: &str) -> String {
+    let (new, path) = (shell_quote(&new_path(path)), shell_quote(path));
+
+    format!("if [ -e {path} ]; then chown \"$(stat -c %u:%g {path} 2>/dev/null || stat -f %u:%g {path})\" {new}; fi")
+}
+
+/// The shell commands that receive a file sent with the `scp -t` protocol as `path`. Any leftover upload
+/// is removed first, as `scp` leaves the mode of an existing file as it is
+fn scp_script(path: &str) -> String {
+    let path = shell_quote(path);
+
+    format!("rm -f {path} && scp -t {path}")
+}
+
+/// `contents` as sent to `scp -t`: a line with the mode, length and name, the contents, then a zero byte.
+/// As the target is a file, the name is ignored
+fn scp_message(path: &str, contents: &[u8], mode: u32) -> Vec<u8> {
+    let name = path.rsplit('/').next().unwrap_or(path);
+
+    let mut message = format!("C{:04o} {} {name}\n", mode & 0o7777, contents.len()).into_bytes();
+    message.extend_from_slice(contents);
+    message.push(0);
+
+    message
+}
+
+/// The error `scp -t` reported, if any. Each step is acknowledged with a zero byte, or refused with
+/// 1 (a warning) or 2 (fatal) followed by a message line
+fn scp_error(stdout: &[u8]) -> Option<String> {
+    let i = stdout.iter().position(|&b| b != 0)?;
+
+    let message = match stdout[i] {
+        1 | 2 => &stdout[i + 1..],
+        _ => &stdout[i..],
+    };
+    let message = message.split(|&b| b == b'\n').next().unwrap_or_default();
+
+    Some(String::from_utf8_lossy(message).trim().to_string())
+}
+
+async fn upload(handle: &Handle<ClientHandler>, file: &File<'_>, transfer: Transfer) -> Result<()> {
+    let path = new_path(file.path);
+
+    match transfer {
+        Transfer::Sftp => sftp::upload(handle, &path, file.contents, file.attrs).await,
+        Transfer::Scp => {
+            let script = scp_script(&path);
+            let output = exec(handle, &format!("sh -c {}", shell_quote(&script)), &scp_message(&path, file.contents, file.attrs.mode)).await?;
+
+            if let Some(message) = scp_error(&output.stdout) {
+                return Err(RciError::RemoteRejected(anyhow!("scp refused to write {path}: {message}")).into());
+            }
+
+            output.check(&format!("writing {path}"))?;
+
+            Ok(())
+        },
+        Transfer::ExecCat => {
+            let script = shell_upload_script(&path, file.attrs.mode);
+            exec(handle, &format!("sh -c {}", shell_quote(&script)), file.contents).await?
+                .check(&format!("writing {path}"))?;
+
+            Ok(())
+        },
+    }
+}
+
+/// Upload `files` with `transfer` next to their destinations, without replacing anything yet
+pub async fn stage<'a>(handle: &'a Handle<ClientHandler>, files: &[File<'_>], transfer: Transfer) -> Result<Staged<'a>> {
+    let staged = Staged { handle, paths: files.iter().map(|f| f.path.to_string()).collect() };
+
+    for file in files {
+        info!("uploading {}", file.path);
+
+        if let Err(e) = timing::phase("upload", upload(handle, file, transfer)).await {
+            staged.discard().await;
+            return Err(e);
+        }
+
+        if file.preserve.mode {
+            if let Err(e) = run(handle, &preserve_mode_script(file.path), "keeping the file mode").await {
+                staged.discard().await;
+                return Err(e.context(format!("failed to keep the mode of {}", file.path)));
+            }
+        }
+
+        if file.preserve.owner {
+            if let Err(e) = run(handle, &preserve_owner_script(file.path), "keeping the file owner").await {
+                warn!("failed to keep the owner of {}, set `owner` and `group` to choose one: {e:#}", file.path);
+            }
+        }
+
+        // before the file is in place, so it's never there with the wrong owner
+        if let Some(owner) = &file.owner {
+            let script = format!("chown {} {}", shell_quote(owner), shell_quote(&new_path(file.path)));
+
+            if let Err(e) = run(handle, &script, "changing file ownership").await {
+                staged.discard().await;
+                return Err(e.context(format!("failed to change the owner of {} to {owner}", file.path)));
+            }
+        }
+    }
+
+    Ok(staged)
+}
+
+/// The shell commands that back up each existing file and move the uploaded files into place
+fn commit_script(paths: &[String]) -> String {
+    let mut script = vec!["umask 077".to_string(), "set -e".to_string()];
+
+    for path in paths {
+        let (path, backup, absent) = (shell_quote(path), shell_quote(&backup_path(path)), shell_quote(&absent_path(path)));
+        script.push(format!("if [ -e {path} ]; then cp -p {path} {backup} && rm -f {absent}; else rm -f {backup} && : > {absent}; fi"));
+    }
+
+    // the renames come last and together, so the files change as close to simultaneously as possible
+    for path in paths {
+        script.push(format!("mv -f {} {}", shell_quote(&new_path(path)), shell_quote(path)));
+    }
+
+    script.join("\n")
+}
+
+fn rollback_script(paths: &[String]) -> String {
+    paths.iter()
+        .map(|path| {
+            let (path, backup, absent) = (shell_quote(path), shell_quote(&backup_path(path)), shell_quote(&absent_path(path)));
+            format!("if [ -e {backup} ]; then mv -f {backup} {path}; elif [ -e {absent} ]; then rm -f {path} {absent}; fi")
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// `rm -f` each of `paths`
+fn remove_script(paths: impl Iterator<Item = String>) -> String {
+    paths.map(|path| format!("rm -f {}", shell_quote(&path)))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+impl Staged<'_> {
+    /// Back up the current files and move the uploaded files into place
+    pub async fn commit(&self) -> Result<()> {
+        if let Err(e) = run(self.handle, &commit_script(&self.paths), "moving files into place").await {
+            // some files may have been moved already
+            self.rollback().await;
+            self.discard().await;
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Put back the files that were replaced by [`Staged::commit`].
+    ///
+    /// Files that didn't exist beforehand are removed. Failures are only logged,
+    /// since this is already cleaning up after an error.
+    pub async fn rollback(&self) {
+        warn!("restoring previous {}", self.paths.join(", "));
+
+        if let Err(e) = run(self.handle, &rollback_script(&self.paths), "restoring previous files").await {
+            warn!("failed to restore previous files: {e:#}");
+        }
+    }
+
+    /// Remove any uploaded files that weren't moved into place
+    async fn discard(&self) {
+        let script = remove_script(self.paths.iter().map(|p| new_path(p)));
+
+        if let Err(e) = run(self.handle, &script, "removing uploaded files").await {
+            warn!("failed to remove uploaded files: {e:#}");
+        }
+    }
+
+    /// Remove the backups, once the new files are known to be good
+    pub async fn finish(self) -> Result<()> {
+        let script = remove_script(self.paths.iter().flat_map(|p| [backup_path(p), absent_path(p)]));
+
+        run(self.handle, &script, "removing backups").await
+            .context("failed to remove backups")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::Path, process::Command};
+
+    use super::*;
+
+    fn sh(script: &str) -> bool {
+        Command::new("sh").arg("-c").arg(script).status().unwrap().success()
+    }
+
+    #[test]
+    fn test_commit_and_rollback_scripts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+
+        let paths = vec![path("cert.pem"), path("key.pem"), path("new.pem")];
+
+        fs::write(path("cert.pem"), "old cert").unwrap();
+        fs::write(path("key.pem"), "old key").unwrap();
+        for p in &paths {
+            fs::write(new_path(p), "new").unwrap();
+        }
+
+        assert!(sh(&commit_script(&paths)));
+        for p in &paths {
+            assert_eq!(fs::read_to_string(p).unwrap(), "new");
+            assert!(!Path::new(&new_path(p)).exists());
+        }
+        assert_eq!(fs::read_to_string(backup_path(&path("key.pem"))).unwrap(), "old key");
+        assert!(!Path::new(&backup_path(&path("new.pem"))).exists());
+
+        assert!(sh(&rollback_script(&paths)));
+        assert_eq!(fs::read_to_string(path("cert.pem")).unwrap(), "old cert");
+        assert_eq!(fs::read_to_string(path("key.pem")).unwrap(), "old key");
+
+        // there was nothing to restore, so the new file is removed
+        assert!(!Path::new(&path("new.pem")).exists());
+        assert!(!Path::new(&absent_path(&path("new.pem"))).exists());
+
+        // nothing is moved if an upload is missing
+        assert!(!sh(&commit_script(&paths)));
+        assert_eq!(fs::read_to_string(path("cert.pem")).unwrap(), "old cert");
+    }
+
+    #[test]
+    fn test_shell_upload_script() {
+        use std::{io::Write, os::unix::fs::PermissionsExt, process::Stdio};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("it's.key").to_str().unwrap().to_string();
+
+        let mut child = Command::new("sh").arg("-c").arg(shell_upload_script(&path, 0o640)).stdin(Stdio::piped()).spawn().unwrap();
+        child.stdin.take().unwrap().write_all(b"key").unwrap();
+        assert!(child.wait().unwrap().success());
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "key");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o7777, 0o640);
+    }
+
+    #[test]
+    fn test_scp_upload() {
+        use std::{io::Write, os::unix::fs::PermissionsExt, process::Stdio};
+
+        let dir = tempfile::tempdir().unwrap();
+
+        let scp = |path: &str, contents: &[u8], mode: u32| {
+            let mut child = Command::new("sh").arg("-c").arg(scp_script(path))
+                .stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+            child.stdin.take().unwrap().write_all(&scp_message(path, contents, mode)).unwrap();
+            child.wait_with_output().unwrap()
+        };
+
+        // replacing a leftover upload that others could read
+        let path = dir.path().join("it's.key.rci-new").to_str().unwrap().to_string();
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let output = scp(&path, b"key\n", 0o600);
+        assert!(output.status.success());
+        assert_eq!(scp_error(&output.stdout), None);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "key\n");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o7777, 0o600);
+
+        let output = scp(dir.path().join("missing/cert.pem").to_str().unwrap(), b"cert", 0o644);
+        assert!(!output.status.success());
+        assert!(scp_error(&output.stdout).unwrap().contains("No such file or directory"), "{:?}", output.stdout);
+
+        assert_eq!(scp_error(b"\0\x02scp: /etc/ssl/key.pem: Read-only file system\n").as_deref(), Some("scp: /etc/ssl/key.pem: Read-only file system"));
+    }
+
+    #[test]
+    fn test_preserve_scripts() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("it's.key").to_str().unwrap().to_string();
+
+        // nothing to keep from a file that doesn't exist yet
+        fs::write(new_path(&path), "new").unwrap();
+        fs::set_permissions(new_path(&path), fs::Permissions::from_mode(0o600)).unwrap();
+        assert!(sh(&preserve_mode_script(&path)));
+        assert!(sh(&preserve_owner_script(&path)));
+        assert_eq!(fs::metadata(new_path(&path)).unwrap().permissions().mode() & 0o7777, 0o600);
+
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        assert!(sh(&preserve_mode_script(&path)));
+        assert!(sh(&preserve_owner_script(&path)));
+
+        let (old, new) = (fs::metadata(&path).unwrap(), fs::metadata(new_path(&path)).unwrap());
+        assert_eq!(new.permissions().mode() & 0o7777, 0o640);
+        assert_eq!((new.uid(), new.gid()), (old.uid(), old.gid()));
+    }
+
+    #[test]
+    fn test_ownership_and_modes() {
+        assert_eq!(chown_spec(Some("haproxy"), Some("ssl-cert")).as_deref(), Some("haproxy:ssl-cert"));
+        assert_eq!(chown_spec(None, Some("ssl-cert")).as_deref(), Some(":ssl-cert"));
+        assert_eq!(chown_spec(None, None), None);
+
+        check_mode("private_key_mode", 0o640, true).unwrap();
+        check_mode("certificate_chain_mode", 0o644, false).unwrap();
+        assert!(check_mode("private_key_mode", 0o644, true).unwrap_err().to_string().contains("0644"));
+        assert!(check_mode("certificate_chain_mode", 0o10644, false).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+//! The error type returned by the public API.
+//!
+//! Internally everything is `anyhow` so context can be attached freely. At the public boundary
+//! errors are classified into an [`RciError`] so callers can decide whether to retry or alert.
+//! Code that knows what went wrong (e.g., SSH authentication) raises the appropriate variant
+//! directly; anything else is classified by what its cause chain contains.
+
+use std::io;
+
+use reqwest::StatusCode;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RciError {
+    /// The configuration couldn't be loaded or is invalid
+    #[error(transparent)]
+    Config(anyhow::Error),
+
+    /// The remote couldn't be reached (DNS, TCP, TLS, SSH transport, timeouts)
+    #[error(transparent)]
+    Connect(anyhow::Error),
+
+    /// The remote rejected the credentials offered
+    #[error(transparent)]
+    Auth(anyhow::Error),
+
+    /// The certificate failed validation
+    #[error(transparent)]
+    Verify(anyhow::Error),
+
+    /// The remote refused or failed to install the certificate
+    #[error(transparent)]
+    RemoteRejected(anyhow::Error),
+
+    /// Anything else
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl RciError {
+    /// Classify `e`, preferring a variant already raised somewhere in its chain
+    pub(crate) fn classify(e: anyhow::Error) -> Self {
+        let variant = match e.downcast_ref::<RciError>() {
+            Some(RciError::Config(_)) => RciError::Config,
+            Some(RciError::Connect(_)) => RciError::Connect,
+            Some(RciError::Auth(_)) => RciError::Auth,
+            Some(RciError::Verify(_)) => RciError::Verify,
+            Some(RciError::RemoteRejected(_)) => RciError::RemoteRejected,
+            Some(RciError::Other(_)) => RciError::Other,
+            None => Self::classify_chain(&e),
+        };
+
+        variant(e)
+    }
+
+    fn classify_chain(e: &anyhow::Error) -> fn(anyhow::Error) -> Self {
+        for cause in e.chain() {
+            if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+                return match e.status() {
+                    Some(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) => RciError::Auth,
+                    Some(_) => RciError::RemoteRejected,
+                    None => RciError::Connect,
+                };
+            }
+
+            if cause.is::<russh::Error>() || cause.is::<openssl::ssl::Error>() || cause.is::<openssl::error::ErrorStack>() {
+                return RciError::Connect;
+            }
+
+            // others, e.g., a local file that couldn't be written, aren't the remote's doing
+            if cause.downcast_ref::<io::Error>().is_some_and(is_network_error) {
+                return RciError::Connect;
+            }
+        }
+
+        RciError::Other
+    }
+
+    /// The underlying error, with its context
+    pub fn inner(&self) -> &anyhow::Error {
+        match self {
+            RciError::Config(e) | RciError::Connect(e) | RciError::Auth(e)
+                | RciError::Verify(e) | RciError::RemoteRejected(e) | RciError::Other(e) => e,
+        }
+    }
+}
+
+/// Whether `e` is from the network, rather than, e.g., the local filesystem
+fn is_network_error(e: &io::Error) -> bool {
+    use io::ErrorKind::*;
+
+    matches!(e.kind(), ConnectionRefused | ConnectionReset | ConnectionAborted | NotConnected | AddrInUse | AddrNotAvailable
+        | BrokenPipe | TimedOut | HostUnreachable | NetworkUnreachable | NetworkDown)
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::{anyhow, Context};
+
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        // an explicitly raised variant survives added context
+        let e = anyhow::Error::from(RciError::Auth(anyhow!("bad key")))
+            .context("connecting");
+        let e = RciError::classify(e);
+        assert!(matches!(e, RciError::Auth(_)));
+        assert_eq!(format!("{e:#}"), "connecting: bad key");
+
+        let e = Err::<(), _>(io::Error::from(io::ErrorKind::ConnectionRefused))
+            .context("connecting")
+            .unwrap_err();
+        assert!(matches!(RciError::classify(e), RciError::Connect(_)));
+
+        let e = Err::<(), _>(io::Error::from(io::ErrorKind::PermissionDenied))
+            .context("writing /etc/ssl/private/device.key")
+            .unwrap_err();
+        assert!(matches!(RciError::classify(e), RciError::Other(_)));
+
+        assert!(matches!(RciError::classify(anyhow!("something else")), RciError::Other(_)));
+    }
+}
@@ -0,0 +1,83 @@
+//! Local file helpers
+
+use std::{fs, io::Write, os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt}, path::{Path, PathBuf}};
+
+use anyhow::{Context, Result};
+use tracing::warn;
+
+/// Write `contents` to a temporary file next to `path` and rename it into place,
+/// so readers see either the old or the new contents and never a partial write
+pub fn write_atomic(path: &Path, contents: &[u8], mode: u32) -> Result<()> {
+    write_atomic_with(path, contents, mode, |_| Ok(()))
+}
+
+/// Like [`write_atomic`], but the new file keeps the owner and group of the file it replaces, and its mode
+/// unless `mode` is given. A new file gets `mode`, or `default_mode`
+pub fn write_atomic_preserving(path: &Path, contents: &[u8], mode: Option<u32>, default_mode: u32) -> Result<()> {
+    let existing = fs::metadata(path).ok();
+    let mode = mode.or(existing.as_ref().map(|metadata| metadata.mode() & 0o7777)).unwrap_or(default_mode);
+
+    write_atomic_with(path, contents, mode, |temp| {
+        // the mode the file was created with was reduced by the umask
+        fs::set_permissions(temp, fs::Permissions::from_mode(mode))
+            .with_context(|| format!("failed to set the mode of \"{}\"", temp.display()))?;
+
+        if let Some(existing) = &existing {
+            if let Err(e) = std::os::unix::fs::chown(temp, Some(existing.uid()), Some(existing.gid())) {
+                warn!("failed to keep the owner of \"{}\": {e}", path.display());
+            }
+        }
+
+        Ok(())
+    })
+}
+
+/// Like [`write_atomic`] for several files, which are only renamed into place once every one of them has been
+/// written, so that a failed write leaves them all as they were (e.g., a new key is never left beside an old certificate)
+pub fn write_atomic_all(files: &[(&Path, &[u8], u32)]) -> Result<()> {
+    let mut temps = Vec::new();
+
+    for &(path, contents, mode) in files {
+        match write_temp(path, contents, mode) {
+            Ok(temp) => temps.push(temp),
+            Err(e) => {
+                for temp in &temps {
+                    let _ = fs::remove_file(temp);
+                }
+
+                return Err(e);
+            }
+        }
+    }
+
+    for (temp, &(path, _, _)) in temps.iter().zip(files) {
+        fs::rename(temp, path).with_context(|| format!("failed to write \"{}\"", path.display()))?;
+    }
+
+    Ok(())
+}
+
+/// [`write_atomic`], calling `prepare` with the temporary file before it's renamed into place
+fn write_atomic_with(path: &Path, contents: &[u8], mode: u32, prepare: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+    let temp = write_temp(path, contents, mode)?;
+
+    prepare(&temp)?;
+
+    fs::rename(&temp, path).with_context(|| format!("failed to write \"{}\"", path.display()))
+}
+
+/// Write `contents` to a new file next to `path`, returning its path
+fn write_temp(path: &Path, contents: &[u8], mode: u32) -> Result<PathBuf> {
+    // appended, rather than replacing the extension, so that `x.key` and `x.pem` don't share one
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".rci-new");
+    let temp = PathBuf::from(temp);
+
+    let mut file = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(mode).open(&temp)
+        .with_context(|| format!("failed to create \"{}\"", temp.display()))?;
+    file.write_all(contents)
+        .and_then(|()| file.sync_all())
+        .with_context(|| format!("failed to write \"{}\"", temp.display()))?;
+
+    Ok(temp)
+}
@@ -0,0 +1,46 @@
+//! Local commands run after a remote has been updated
+
+use anyhow::{bail, Context, Result};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::process::Command;
+use tracing::info;
+
+use crate::config::CertificatePair;
+
+/// Run `command` with `sh -c`, with the remote name (`RCI_REMOTE`) and the RFC 3339 expiry of
+/// the installed certificate (`RCI_CERT_NOT_AFTER`) in its environment
+pub async fn run_post_update_command(command: &str, name: &str, certificate: &CertificatePair) -> Result<()> {
+    let not_after = OffsetDateTime::from(certificate.not_after()?)
+        .format(&Rfc3339)
+        .context("failed to format certificate expiry")?;
+
+    info!("running post-update command for {name}");
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("RCI_REMOTE", name)
+        .env("RCI_CERT_NOT_AFTER", not_after)
+        .status().await
+        .with_context(|| format!("failed to run post-update command for {name}"))?;
+
+    if !status.success() {
+        bail!("post-update command for {name} failed ({status})");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_run_post_update_command() {
+        let pair = crate::test_util::certificate_pair(&["device.example.net"]);
+
+        run_post_update_command(r#"test "$RCI_REMOTE" = haproxy.lb && test -n "$RCI_CERT_NOT_AFTER""#, "haproxy.lb", &pair).await.unwrap();
+
+        let e = run_post_update_command("exit 3", "haproxy.lb", &pair).await.unwrap_err();
+        assert!(e.to_string().contains("exit status: 3"), "{e}");
+    }
+}
@@ -1,7 +1,460 @@
-use serde::Deserialize;
+//! Settings shared by remotes managed over HTTP(S)
 
+use std::{fmt, net::{IpAddr, SocketAddr}};
 
-#[derive(Deserialize, Debug)]
+use anyhow::{anyhow, bail, Context, Result};
+use openssl::{pkcs12::Pkcs12, pkey::{PKey, Private}, x509::X509};
+use reqwest::{Client, ClientBuilder, Identity, Response, Url};
+use serde::{de::{self, DeserializeOwned}, Deserialize, Deserializer, Serialize, Serializer};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::config::{CredentialPathBuf, SecretSource};
+
+/// Sent with every request, unless the config's `user_agent` or a remote's `http.user_agent` says otherwise
+pub const DEFAULT_USER_AGENT: &str = concat!("rci/", env!("CARGO_PKG_VERSION"));
+
+/// The largest response body read from a remote, unless its `http.max_response_bytes` says otherwise
+pub const DEFAULT_MAX_RESPONSE_BYTES: u64 = 4 * 1024 * 1024;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct Config {
+    /// A client certificate to authenticate to the remote with.
+    /// This is unrelated to the certificate being installed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_identity: Option<ClientIdentity>,
+
+    /// `User-Agent` to send to this remote, for devices that only work with particular clients
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+
+    /// Refuse response bodies larger than this, rather than reading them into memory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_response_bytes: Option<u64>,
+
+    /// The oldest TLS version to accept, e.g. `1.0` for management cards that support nothing newer
+    #[serde(default, deserialize_with = "deserialize_min_tls_version", skip_serializing_if = "Option::is_none")]
+    pub min_tls_version: Option<TlsVersion>,
+
+    /// The newest TLS version to offer, for firmware that fails to negotiate down from newer ones
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tls_version: Option<TlsVersion>,
+
+    /// Whether to send the hostname with SNI, which some firmware rejects. Defaults to true
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sni: Option<bool>,
+
+    /// The config's top-level `user_agent`, set when it's loaded
+    #[serde(skip)]
+    pub(crate) default_user_agent: Option<String>,
+}
+
+/// A TLS protocol version
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    #[serde(rename = "1.0")]
+    Tls1_0,
+    #[serde(rename = "1.1")]
+    Tls1_1,
+    #[serde(rename = "1.2")]
+    Tls1_2,
+    #[serde(rename = "1.3")]
+    Tls1_3,
+}
+
+impl From<TlsVersion> for reqwest::tls::Version {
+    fn from(version: TlsVersion) -> Self {
+        match version {
+            TlsVersion::Tls1_0 => reqwest::tls::Version::TLS_1_0,
+            TlsVersion::Tls1_1 => reqwest::tls::Version::TLS_1_1,
+            TlsVersion::Tls1_2 => reqwest::tls::Version::TLS_1_2,
+            TlsVersion::Tls1_3 => reqwest::tls::Version::TLS_1_3,
+        }
+    }
+}
+
+/// The TLS library (through native-tls) can't be limited to TLS 1.3, so it's refused when the config is loaded
+fn deserialize_min_tls_version<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<TlsVersion>, D::Error> {
+    match Option::<TlsVersion>::deserialize(deserializer)? {
+        Some(TlsVersion::Tls1_3) => Err(de::Error::custom("a `min_tls_version` of 1.3 isn't supported (the highest is 1.2)")),
+        version => Ok(version),
+    }
+}
+
+impl Config {
+    pub fn is_empty(&self) -> bool {
+        self.client_identity.is_none() && self.user_agent.is_none() && self.max_response_bytes.is_none()
+            && self.min_tls_version.is_none() && self.max_tls_version.is_none() && self.sni.is_none()
+    }
+
+    pub fn max_response_bytes(&self) -> u64 {
+        self.max_response_bytes.unwrap_or(DEFAULT_MAX_RESPONSE_BYTES)
+    }
+
+    /// The `User-Agent` to send, if not [`DEFAULT_USER_AGENT`]
+    pub fn user_agent(&self) -> Option<&str> {
+        self.user_agent.as_deref().or(self.default_user_agent.as_deref())
+    }
+
+    /// A `Client` builder for talking to the remote
+    pub fn client_builder(&self) -> ClientBuilder {
+        self.apply(client_builder(self.user_agent()))
+    }
+
+    /// Configure a `Client` (from [`client_builder`]) for talking to the remote
+    pub fn apply(&self, mut builder: ClientBuilder) -> ClientBuilder {
+        if let Some(identity) = &self.client_identity {
+            builder = builder.identity(identity.identity.clone());
+        }
+
+        if let Some(version) = self.min_tls_version {
+            builder = builder.min_tls_version(version.into());
+        }
+
+        // native-tls can't set a maximum of TLS 1.3, which is the newest anyway
+        if let Some(version) = self.max_tls_version.filter(|&version| version < TlsVersion::Tls1_3) {
+            builder = builder.max_tls_version(version.into());
+        }
+
+        if let Some(sni) = self.sni {
+            builder = builder.tls_sni(sni);
+        }
+
+        match self.user_agent() {
+            Some(user_agent) => builder.user_agent(user_agent),
+            None => builder,
+        }
+    }
+}
+
+/// A `Client` builder that identifies itself with `user_agent` (or [`DEFAULT_USER_AGENT`], if `None`)
+pub fn client_builder(user_agent: Option<&str>) -> ClientBuilder {
+    Client::builder().user_agent(user_agent.unwrap_or(DEFAULT_USER_AGENT))
+}
+
+/// The body of `response`, refusing one of more than `limit` bytes before it's all been read
+pub async fn bytes(mut response: Response, limit: u64) -> Result<Vec<u8>> {
+    let too_large = || anyhow!("the response is larger than {limit} bytes (see `http.max_response_bytes`)");
+
+    if response.content_length().is_some_and(|length| length > limit) {
+        return Err(too_large());
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if (body.len() + chunk.len()) as u64 > limit {
+            return Err(too_large());
+        }
+
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body)
+}
+
+/// Like [`bytes`], as text. Invalid UTF-8 is replaced rather than refused, as it's usually only logged or shown
+pub async fn text(response: Response, limit: u64) -> Result<String> {
+    Ok(String::from_utf8_lossy(&bytes(response, limit).await?).into_owned())
+}
+
+/// Like [`bytes`], decoded as JSON
+pub async fn json<T: DeserializeOwned>(response: Response, limit: u64) -> Result<T> {
+    Ok(serde_json::from_slice(&bytes(response, limit).await?)?)
+}
+
+/// Read the head of an HTTP request (up to the blank line that ends its headers) from `stream`,
+/// refusing one of more than `limit` bytes. Any body is left unread
+pub async fn read_request_head(stream: &mut (impl AsyncRead + Unpin), limit: usize) -> Result<Vec<u8>> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() > limit {
+            bail!("incomplete request");
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    Ok(request)
+}
+
+/// Connect to `address` rather than resolving the hostname of `url`.
+/// The hostname is still used for SNI and the `Host` header.
+///
+/// Without an `address`, the hostname is resolved for each new connection rather than cached.
+pub fn resolve_to(builder: ClientBuilder, url: &Url, address: Option<IpAddr>) -> ClientBuilder {
+    match (url.domain(), address, url.port_or_known_default()) {
+        (Some(domain), Some(address), Some(port)) => builder.resolve(domain, SocketAddr::new(address, port)),
+        _ => builder,
+    }
+}
+
+/// Either a PKCS#12 archive, or PEM certificate (chain) and private key files.
+/// The password decrypts the archive or the private key.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawClientIdentity {
+    pkcs12_path: Option<CredentialPathBuf>,
+
+    certificate_path: Option<CredentialPathBuf>,
+    private_key_path: Option<CredentialPathBuf>,
+
+    password_file: Option<CredentialPathBuf>,
+
+    /// environment variable containing the password, instead of `password_file`
+    password_env: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct ClientIdentity {
+    /// where the identity was loaded from, for display
+    source: String,
+
+    identity: Identity,
+}
+
+impl fmt::Debug for ClientIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientIdentity").field("source", &self.source).finish_non_exhaustive()
+    }
+}
+
+impl Serialize for ClientIdentity {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.source.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ClientIdentity {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>
+    {
+        let raw = RawClientIdentity::deserialize(deserializer)?;
+
+        ClientIdentity::load(raw).map_err(|e| de::Error::custom(format!("failed to load `client_identity` ({e:#})")))
+    }
+}
+
+fn read(path: &CredentialPathBuf) -> Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("failed to open \"{}\"", path.display()))
+}
+
+impl ClientIdentity {
+    fn load(raw: RawClientIdentity) -> Result<ClientIdentity> {
+        let password = SecretSource::from_keys(raw.password_file, raw.password_env, "password_file", "password_env")?
+            .map(|source| {
+                source.read_to_string()
+                    .map(|p| p.trim_end_matches(['\r', '\n']).to_string())
+                    .with_context(|| format!("failed to read password {source}"))
+            })
+            .transpose()?;
+
+        let (source, chain, key): (_, Vec<X509>, PKey<Private>) = match (raw.pkcs12_path, raw.certificate_path, raw.private_key_path) {
+            (Some(path), None, None) => {
+                let archive = Pkcs12::from_der(&read(&path)?)
+                    .with_context(|| format!("\"{}\" is not a PKCS#12 archive", path.display()))?
+                    .parse2(password.as_deref().unwrap_or_default())
+                    .with_context(|| format!("failed to decrypt \"{}\" (wrong password?)", path.display()))?;
+
+                let cert = archive.cert.ok_or_else(|| anyhow!("no certificate found in \"{}\"", path.display()))?;
+                let key = archive.pkey.ok_or_else(|| anyhow!("no private key found in \"{}\"", path.display()))?;
+
+                let chain = std::iter::once(cert).chain(archive.ca.into_iter().flatten()).collect();
+
+                (format!("{}", path.display()), chain, key)
+            },
+            (None, Some(cert_path), Some(key_path)) => {
+                let chain = X509::stack_from_pem(&read(&cert_path)?)
+                    .with_context(|| format!("failed to read certificates from \"{}\"", cert_path.display()))?;
+                if chain.is_empty() {
+                    bail!("no certificates found in \"{}\"", cert_path.display());
+                }
+
+                let pem = read(&key_path)?;
+                let key = match &password {
+                    Some(password) => PKey::private_key_from_pem_passphrase(&pem, password.as_bytes()),
+                    None => PKey::private_key_from_pem(&pem),
+                }.with_context(|| format!("failed to read private key from \"{}\"", key_path.display()))?;
+
+                (format!("{} + {}", cert_path.display(), key_path.display()), chain, key)
+            },
+            (None, None, None) => bail!("one of `pkcs12_path`, or `certificate_path` and `private_key_path`, is required"),
+            _ => bail!("either `pkcs12_path`, or both `certificate_path` and `private_key_path`, must be set"),
+        };
+
+        if !chain[0].public_key()?.public_eq(&key) {
+            bail!("the client certificate doesn't match the private key");
+        }
+
+        let mut chain_pem = Vec::new();
+        for cert in &chain {
+            chain_pem.extend(cert.to_pem()?);
+        }
+
+        let identity = Identity::from_pkcs8_pem(&chain_pem, &key.private_key_to_pem_pkcs8()?)?;
+
+        Ok(ClientIdentity { source, identity })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use figment::{providers::{Format, Toml}, Figment};
+
+    use super::*;
+
+    #[test]
+    #[allow(clippy::result_large_err)]
+    fn test_client_identity() {
+        let pair = crate::test_util::certificate_pair(&["client.example.net"]);
+        let other = crate::test_util::certificate_pair(&["other.example.net"]);
+
+        figment::Jail::expect_with(|jail| {
+            jail.create_binary("client.p12", &pair.pkcs12_der("client", "hunter2").unwrap())?;
+            jail.create_file("client.pem", &pair.fullchain_certificate_pem_string().unwrap())?;
+            jail.create_file("client.key", &pair.private_key_pem_string().unwrap())?;
+            jail.create_file("other.key", &other.private_key_pem_string().unwrap())?;
+            jail.create_file("password", "hunter2\n")?;
+
+            let extract = |toml: &str| Figment::new().merge(Toml::string(toml)).extract::<Config>();
+
+            let config = extract(r#"client_identity = { pkcs12_path = "client.p12", password_file = "password" }"#).unwrap();
+            assert!(config.client_identity.unwrap().source.ends_with("client.p12"));
+
+            extract(r#"client_identity = { certificate_path = "client.pem", private_key_path = "client.key" }"#).unwrap();
+
+            let e = extract(r#"client_identity = { pkcs12_path = "client.p12" }"#).unwrap_err();
+            assert!(e.to_string().contains("wrong password"), "{e}");
+
+            let e = extract(r#"client_identity = { certificate_path = "client.pem", private_key_path = "other.key" }"#).unwrap_err();
+            assert!(e.to_string().contains("doesn't match the private key"), "{e}");
+
+            let e = extract(r#"client_identity = { pkcs12_path = "client.p12", certificate_path = "client.pem" }"#).unwrap_err();
+            assert!(e.to_string().contains("either `pkcs12_path`"), "{e}");
+
+            Ok(())
+        });
+    }
+
+    #[tokio::test]
+    async fn test_user_agent() {
+        use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener};
+
+        // the User-Agent header of a request from a client configured with `config`
+        async fn user_agent(config: &Config) -> String {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}/", listener.local_addr().unwrap());
+
+            let server = async {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![0; 4096];
+                let n = stream.read(&mut request).await.unwrap();
+                stream.write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n").await.unwrap();
+
+                String::from_utf8_lossy(&request[..n]).lines()
+                    .find_map(|line| line.strip_prefix("user-agent: ")).unwrap_or_default().to_string()
+            };
+
+            let client = config.client_builder().build().unwrap();
+            let (_, user_agent) = tokio::join!(client.get(url).send(), server);
+
+            user_agent
+        }
+
+        assert_eq!(user_agent(&Config::default()).await, DEFAULT_USER_AGENT);
+
+        let config = Config { user_agent: Some("Mozilla/5.0".to_string()), ..Default::default() };
+        assert_eq!(user_agent(&config).await, "Mozilla/5.0");
+
+        // the config's `user_agent`, unless the remote has its own
+        let config = Config { default_user_agent: Some("rci-fleet".to_string()), ..Default::default() };
+        assert_eq!(user_agent(&config).await, "rci-fleet");
+        let config = Config { user_agent: Some("Mozilla/5.0".to_string()), ..config };
+        assert_eq!(user_agent(&config).await, "Mozilla/5.0");
+    }
+
+    #[tokio::test]
+    #[allow(clippy::result_large_err)]
+    async fn test_tls_versions() {
+        use openssl::ssl::{SslAcceptor, SslMethod, SslVersion};
+        use tokio::net::TcpListener;
+
+        let extract = |toml: &str| Figment::new().merge(Toml::string(toml)).extract::<Config>();
+
+        let config = extract(r#"min_tls_version = "1.0"
+            max_tls_version = "1.2"
+            sni = false"#).unwrap();
+        assert_eq!((config.min_tls_version, config.max_tls_version, config.sni), (Some(TlsVersion::Tls1_0), Some(TlsVersion::Tls1_2), Some(false)));
+
+        let e = extract(r#"min_tls_version = "1.3""#).unwrap_err();
+        assert!(e.to_string().contains("isn't supported"), "{e}");
+        assert!(extract(r#"max_tls_version = "1.4""#).is_err());
+
+        // whether a client configured with `config` completes a handshake with a server requiring TLS 1.3
+        async fn connects(config: &Config) -> bool {
+            let key = crate::test_util::generate_key();
+            let cert = crate::test_util::generate_cert("localhost", &["localhost"], &key, None, false);
+
+            let mut acceptor = SslAcceptor::mozilla_modern_v5(SslMethod::tls_server()).unwrap();
+            acceptor.set_private_key(&key).unwrap();
+            acceptor.set_certificate(&cert).unwrap();
+            acceptor.set_min_proto_version(Some(SslVersion::TLS1_3)).unwrap();
+            let acceptor = acceptor.build();
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("https://localhost:{}/", listener.local_addr().unwrap().port());
+
+            let server = async {
+                let (stream, _) = listener.accept().await.unwrap();
+                let stream = stream.into_std().unwrap();
+                stream.set_nonblocking(false).unwrap();
+                tokio::task::spawn_blocking(move || {
+                    use std::io::{Read, Write};
+                    if let Ok(mut stream) = acceptor.accept(stream) {
+                        let _ = stream.read(&mut [0; 4096]);
+                        let _ = stream.write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n");
+                    }
+                }).await.unwrap();
+            };
+
+            let client = config.client_builder().danger_accept_invalid_certs(true).build().unwrap();
+            let (response, ()) = tokio::join!(client.get(url).send(), server);
+
+            response.is_ok()
+        }
+
+        assert!(connects(&Config::default()).await);
+        assert!(connects(&Config { max_tls_version: Some(TlsVersion::Tls1_3), ..Default::default() }).await);
+        assert!(!connects(&Config { max_tls_version: Some(TlsVersion::Tls1_2), ..Default::default() }).await);
+    }
+
+    #[tokio::test]
+    async fn test_max_response_bytes() {
+        use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener};
+
+        // the body of `response`, read with a limit of 10 bytes
+        async fn read(response: &'static str) -> Result<String> {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}/", listener.local_addr().unwrap());
+
+            let server = async {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let _ = stream.read(&mut [0; 4096]).await.unwrap();
+                stream.write_all(response.as_bytes()).await.unwrap();
+            };
+
+            let (response, ()) = tokio::join!(Client::new().get(url).send(), server);
+
+            text(response.unwrap(), 10).await
+        }
+
+        assert_eq!(read("HTTP/1.1 200 OK\r\nContent-Length: 10\r\nConnection: close\r\n\r\n0123456789").await.unwrap(), "0123456789");
+
+        let e = read("HTTP/1.1 200 OK\r\nContent-Length: 11\r\nConnection: close\r\n\r\n0123456789A").await.unwrap_err();
+        assert_eq!(e.to_string(), "the response is larger than 10 bytes (see `http.max_response_bytes`)");
 
-}
\ No newline at end of file
+        // without a length, it's only found to be too large while it's read
+        read("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n6\r\n012345\r\n6\r\n6789AB\r\n0\r\n\r\n").await.unwrap_err();
+    }
+}
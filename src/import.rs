@@ -0,0 +1,126 @@
+//! Config for a remote's existing certificates, printed by the `import` subcommand to start a config from.
+//!
+//! The remote is described by the same keys as in the config, less its certificates. Nothing on it is changed.
+
+use anyhow::{bail, Context, Result};
+use figment::{providers::{Format, Toml}, Figment};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use url::Host;
+
+use crate::{remote::{megarac, pfsense}, RciError};
+
+/// The remote types that can be imported from, by their table name in the config file
+pub const IMPORT_TYPES: &[&str] = &["pfsense", "megarac-bmc"];
+
+/// A config block for each certificate on the remote of `remote_type` described by `remote`, which must include `url`
+pub async fn import(remote_type: &str, remote: toml::Table) -> std::result::Result<String, RciError> {
+    import_remote(remote_type, remote).await.map_err(RciError::classify)
+}
+
+async fn import_remote(remote_type: &str, mut remote: toml::Table) -> Result<String> {
+    let name = remote_name(&remote)?;
+
+    match remote_type {
+        "pfsense" => {
+            let certificates = pfsense::list_certificates(extract(&remote)?).await?;
+            if certificates.is_empty() {
+                return Ok("# there are no certificates in the pfSense config\n".to_string());
+            }
+
+            let single = certificates.len() == 1;
+
+            let blocks = certificates.into_iter()
+                .map(|existing| {
+                    let mut about = format!("# \"{}\" ({})", existing.descr, existing.refid);
+                    if let Some(not_after) = existing.not_after {
+                        about += &format!(", expires {}", OffsetDateTime::from_unix_timestamp(not_after)?.format(&Rfc3339)?);
+                    }
+
+                    let mut remote = remote.clone();
+                    remote.insert("refid".to_string(), existing.refid.clone().into());
+                    remote.insert("descr".to_string(), existing.descr.into());
+                    if !existing.services.is_empty() {
+                        remote.insert("services".to_string(), existing.services.iter().map(ToString::to_string).collect::<Vec<_>>().into());
+                    }
+
+                    // remotes are named after the firewall, and with several certificates, their refids too
+                    let name = match single {
+                        true => name.clone(),
+                        false => format!("{name}-{}", existing.refid),
+                    };
+
+                    Ok(format!("{about}\n{}", block(remote_type, &name, remote)?))
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            Ok(blocks.join("\n"))
+        },
+        "megarac-bmc" => {
+            // only connected to, so any certificate will do
+            remote.insert("certificate".to_string(), "default".into());
+
+            let info = megarac::certificate_info(&extract::<megarac::Config<_>>(&remote)?).await?;
+
+            let mut about = "# the BMC's current certificate:\n".to_string();
+            for (key, value) in &info {
+                about += &format!("#   {key}: {value}\n");
+            }
+
+            Ok(format!("{about}{}", block(remote_type, &name, remote)?))
+        },
+        other => bail!("can't import from remote type `{other}` (expected one of {})", IMPORT_TYPES.join(", ")),
+    }
+}
+
+/// The remote's keys as `T`, as they would be read from the config
+fn extract<T: serde::de::DeserializeOwned>(remote: &toml::Table) -> Result<T> {
+    Figment::from(Toml::string(&remote.to_string())).extract().context("invalid remote")
+}
+
+/// The first label of the remote's hostname, e.g. `router` for `ssh://admin@router.example.net`, or its address
+fn remote_name(remote: &toml::Table) -> Result<String> {
+    let url = remote.get("url").and_then(toml::Value::as_str).context("the remote has no `url`")?;
+    let url = url::Url::parse(url).with_context(|| format!("invalid URL `{url}`"))?;
+
+    match url.host() {
+        Some(Host::Domain(domain)) => Ok(domain.split('.').next().unwrap_or(domain).to_string()),
+        Some(Host::Ipv4(addr)) => Ok(addr.to_string()),
+        Some(Host::Ipv6(addr)) => Ok(addr.to_string()),
+        None => bail!("`{url}` has no hostname"),
+    }
+}
+
+/// `remote` as the `[<remote_type>.<name>]` table of a config, referring to the `default` certificate
+fn block(remote_type: &str, name: &str, mut remote: toml::Table) -> Result<String> {
+    remote.insert("certificate".to_string(), "default".into());
+
+    let table = toml::Table::from_iter([(remote_type.to_string(), toml::Table::from_iter([(name.to_string(), remote.into())]).into())]);
+
+    toml::to_string(&table).context("failed to write the config")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_block() {
+        let remote: toml::Table = toml::from_str(r#"
+            url = "ssh://admin@router.example.net"
+            ssh.private_key_file = "id_ed25519"
+            refid = "5f1a"
+        "#).unwrap();
+
+        assert_eq!(remote_name(&remote).unwrap(), "router");
+
+        let block = block("pfsense", "router", remote).unwrap();
+        let config: toml::Table = toml::from_str(&block).unwrap();
+        assert_eq!(config["pfsense"]["router"]["certificate"].as_str(), Some("default"));
+        assert_eq!(config["pfsense"]["router"]["ssh"]["private_key_file"].as_str(), Some("id_ed25519"));
+        assert!(block.starts_with("[pfsense.router]\n"), "{block}");
+
+        let remote: toml::Table = toml::from_str(r#"url = "https://[2001:db8::1]""#).unwrap();
+        assert_eq!(remote_name(&remote).unwrap(), "2001:db8::1");
+        assert!(remote_name(&toml::Table::new()).is_err());
+    }
+}
@@ -0,0 +1,93 @@
+//! Install TLS certificates on network appliances and services.
+//!
+//! The `certinstaller` binary is a thin wrapper around this crate. Embedders load a
+//! [`Config`] with [`load_config`] and drive [`update_certificate`] themselves.
+
+pub mod acme;
+pub mod config;
+pub mod error;
+pub mod hook;
+pub mod import;
+pub mod lock;
+pub mod probe;
+pub mod remote;
+pub mod sample;
+#[cfg(feature = "serve")]
+pub mod serve;
+pub mod sftp;
+pub mod ssh;
+pub mod state;
+pub mod systemd;
+pub mod timing;
+pub mod vault;
+pub mod verify;
+
+mod deploy;
+mod file;
+mod http;
+mod ntlm;
+mod socks;
+mod yaml;
+
+#[cfg(test)]
+mod test_util;
+
+pub use config::{load_config, load_config_profile, load_config_with_renewal, CertificatePair, CertificateSummary, Config, Remote, RemoteConfig};
+pub use error::RciError;
+
+/// Install the configured certificate on the remote, which uses it for `usage` (its `key_usage` option)
+pub async fn update_certificate(config: &RemoteConfig, usage: verify::Usage) -> Result<(), RciError> {
+    for certificate in config.certificates() {
+        verify::precheck_certificate(certificate, usage).map_err(RciError::Verify)?;
+    }
+
+    install_certificate(config).await
+}
+
+/// Install the configured certificate on the remote without checking it first, unlike [`update_certificate`]
+pub async fn install_certificate(config: &RemoteConfig) -> Result<(), RciError> {
+    let result = match config {
+        RemoteConfig::PfSense(config) => remote::pfsense::update_certificate(config).await,
+        RemoteConfig::Megarac(config) => remote::megarac::update_certificate(config).await,
+        RemoteConfig::UnifiController(config) => remote::unifi_controller::update_certificate(config).await,
+        RemoteConfig::Haproxy(config) => remote::haproxy::update_certificate(config).await,
+        RemoteConfig::Nginx(config) => remote::nginx::update_certificate(config).await,
+        RemoteConfig::Redfish(config) => remote::redfish::update_certificate(config).await,
+        RemoteConfig::Pkcs12File(config) => remote::file_pkcs12::update_certificate(config).await,
+        RemoteConfig::File(config) => remote::file_pem::update_certificate(config).await,
+        RemoteConfig::Caddy(config) => remote::caddy::update_certificate(config).await,
+        RemoteConfig::HomeAssistant(config) => remote::homeassistant::update_certificate(config).await,
+        RemoteConfig::OpenWrt(config) => remote::openwrt::update_certificate(config).await,
+        RemoteConfig::Iis(config) => remote::winrm::update_certificate(config).await,
+        RemoteConfig::Udm(config) => remote::udm::update_certificate(config).await,
+        RemoteConfig::Kubernetes(config) => remote::kubernetes::update_certificate(config).await,
+        RemoteConfig::Brother => todo!(),
+        RemoteConfig::Cloudkey => todo!(),
+    };
+
+    result.map_err(RciError::classify)
+}
+
+/// Check the remote is reachable and accepts its credentials, without changing anything
+pub async fn test_connection(config: &RemoteConfig) -> Result<(), RciError> {
+    let result = match config {
+        RemoteConfig::PfSense(config) => remote::pfsense::test_connection(config).await,
+        RemoteConfig::Megarac(config) => remote::megarac::test_connection(config).await,
+        RemoteConfig::UnifiController(config) => remote::unifi_controller::test_connection(config).await,
+        RemoteConfig::Haproxy(config) => remote::haproxy::test_connection(config).await,
+        RemoteConfig::Nginx(config) => remote::nginx::test_connection(config).await,
+        RemoteConfig::Redfish(config) => remote::redfish::test_connection(config).await,
+        RemoteConfig::Pkcs12File(config) => remote::file_pkcs12::test_connection(config).await,
+        RemoteConfig::File(config) => remote::file_pem::test_connection(config).await,
+        RemoteConfig::Caddy(config) => remote::caddy::test_connection(config).await,
+        RemoteConfig::HomeAssistant(config) => remote::homeassistant::test_connection(config).await,
+        RemoteConfig::OpenWrt(config) => remote::openwrt::test_connection(config).await,
+        RemoteConfig::Iis(config) => remote::winrm::test_connection(config).await,
+        RemoteConfig::Udm(config) => remote::udm::test_connection(config).await,
+        RemoteConfig::Kubernetes(config) => remote::kubernetes::test_connection(config).await,
+        RemoteConfig::Brother => todo!(),
+        RemoteConfig::Cloudkey => todo!(),
+    };
+
+    result.map_err(RciError::classify)
+}
@@ -0,0 +1,115 @@
+//! Preventing concurrent runs.
+//!
+//! Two runs updating the same remote at once can interleave their changes (e.g., both editing
+//! pfSense's `config.xml`, or both appending to `known_hosts`), so each run holds an exclusive
+//! `flock(2)` on a lock file. The lock is released by the kernel when the file is closed,
+//! including when the process panics or is killed, so a stale lock can't be left behind.
+//! The file itself is left in place; removing it would let a new run lock a different file
+//! while an old run still holds the original.
+
+use std::{fs::{File, OpenOptions, TryLockError}, io::{Read, Seek, Write}, path::{Path, PathBuf}};
+
+use anyhow::{Context, Result};
+use tracing::{debug, info};
+
+/// Another run holds the lock, and it wasn't waited for
+#[derive(Debug, thiserror::Error)]
+#[error("another instance{holder} is already running (lock file \"{}\" is held). Use --wait to wait for it to finish", path.display())]
+pub struct Held {
+    holder: String,
+    path: PathBuf,
+}
+
+/// An exclusive lock, held until dropped
+#[derive(Debug)]
+pub struct Lock {
+    path: PathBuf,
+    _file: File,
+}
+
+impl Lock {
+    /// Take the lock at `path`, creating the file if needed.
+    ///
+    /// If another run holds the lock this fails, or with `wait`, blocks until it is released.
+    pub async fn acquire(path: &Path, wait: bool) -> Result<Lock> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("failed to open lock file \"{}\"", path.display()))?;
+
+        match file.try_lock() {
+            Ok(()) => {},
+            Err(TryLockError::WouldBlock) => {
+                let holder = holder(&mut file);
+
+                if !wait {
+                    return Err(Held { holder, path: path.to_path_buf() }.into());
+                }
+
+                info!("waiting for another instance{holder} to finish");
+                file = tokio::task::spawn_blocking(move || file.lock().map(|()| file)).await
+                    .expect("lock task panicked")
+                    .with_context(|| format!("failed to lock \"{}\"", path.display()))?;
+            },
+            Err(TryLockError::Error(e)) => return Err(e).with_context(|| format!("failed to lock \"{}\"", path.display())),
+        }
+
+        // record who holds the lock, for the message above
+        let pid = std::process::id();
+        file.set_len(0)
+            .and_then(|()| file.write_all(format!("{pid}\n").as_bytes()))
+            .with_context(|| format!("failed to write lock file \"{}\"", path.display()))?;
+
+        debug!("holding lock \"{}\"", path.display());
+
+        Ok(Lock { path: path.to_path_buf(), _file: file })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// " (pid N)", if the process holding the lock recorded its pid
+fn holder(file: &mut File) -> String {
+    let mut contents = String::new();
+
+    match file.rewind().and_then(|()| file.read_to_string(&mut contents)) {
+        Ok(_) => match contents.trim().parse::<u32>() {
+            Ok(pid) => format!(" (pid {pid})"),
+            Err(_) => String::new(),
+        },
+        Err(_) => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rci.lock");
+
+        let lock = Lock::acquire(&path, false).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), format!("{}\n", std::process::id()));
+
+        let e = Lock::acquire(&path, false).await.unwrap_err();
+        assert!(e.to_string().contains(&format!("another instance (pid {}) is already running", std::process::id())), "{e}");
+
+        let waiter = tokio::spawn({
+            let path = path.clone();
+            async move { Lock::acquire(&path, true).await }
+        });
+
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(!waiter.is_finished());
+
+        drop(lock);
+        waiter.await.unwrap().unwrap();
+    }
+}
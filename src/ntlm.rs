@@ -0,0 +1,300 @@
+//! A minimal NTLMv2 client (MS-NLMP), for authenticating to WinRM with `Negotiate`
+//!
+//! Only authentication is implemented; there's no signing or sealing, so messages must be protected
+//! some other way (i.e., HTTPS) unless the server allows unencrypted traffic.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+
+const SIGNATURE: &[u8; 8] = b"NTLMSSP\0";
+
+const NEGOTIATE_MESSAGE: u32 = 1;
+const CHALLENGE_MESSAGE: u32 = 2;
+const AUTHENTICATE_MESSAGE: u32 = 3;
+
+const NEGOTIATE_UNICODE: u32 = 0x0000_0001;
+const REQUEST_TARGET: u32 = 0x0000_0004;
+const NEGOTIATE_NTLM: u32 = 0x0000_0200;
+const NEGOTIATE_ALWAYS_SIGN: u32 = 0x0000_8000;
+const NEGOTIATE_EXTENDED_SESSIONSECURITY: u32 = 0x0008_0000;
+const NEGOTIATE_TARGET_INFO: u32 = 0x0080_0000;
+const NEGOTIATE_128: u32 = 0x2000_0000;
+const NEGOTIATE_56: u32 = 0x8000_0000;
+
+const FLAGS: u32 = NEGOTIATE_UNICODE | REQUEST_TARGET | NEGOTIATE_NTLM | NEGOTIATE_ALWAYS_SIGN
+    | NEGOTIATE_EXTENDED_SESSIONSECURITY | NEGOTIATE_128 | NEGOTIATE_56;
+
+const AV_EOL: u16 = 0;
+const AV_TIMESTAMP: u16 = 7;
+
+/// Seconds between 1601 (the `FILETIME` epoch) and 1970
+const FILETIME_UNIX_EPOCH: u64 = 11_644_473_600;
+
+/// The first message of the handshake
+pub fn negotiate() -> Vec<u8> {
+    let mut message = Vec::with_capacity(32);
+    message.extend_from_slice(SIGNATURE);
+    message.extend_from_slice(&NEGOTIATE_MESSAGE.to_le_bytes());
+    message.extend_from_slice(&FLAGS.to_le_bytes());
+    // no domain or workstation
+    message.extend_from_slice(&[0; 16]);
+
+    message
+}
+
+/// The server's challenge
+struct Challenge<'a> {
+    flags: u32,
+    server_challenge: [u8; 8],
+    target_info: &'a [u8],
+}
+
+fn u16_at(message: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(message.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn u32_at(message: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(message.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+impl<'a> Challenge<'a> {
+    fn parse(message: &'a [u8]) -> Result<Self> {
+        if message.get(..8) != Some(SIGNATURE) || u32_at(message, 8) != Some(CHALLENGE_MESSAGE) {
+            bail!("not an NTLM challenge");
+        }
+
+        let parse = || {
+            let flags = u32_at(message, 20)?;
+            let server_challenge = message.get(24..32)?.try_into().ok()?;
+
+            let target_info = match flags & NEGOTIATE_TARGET_INFO {
+                0 => &[][..],
+                _ => {
+                    let len = u16_at(message, 40)? as usize;
+                    let offset = u32_at(message, 44)? as usize;
+                    message.get(offset..offset + len)?
+                },
+            };
+
+            Some(Challenge { flags, server_challenge, target_info })
+        };
+
+        parse().context("truncated NTLM challenge")
+    }
+
+    /// The server's `MsvAvTimestamp`, if it sent one
+    fn timestamp(&self) -> Option<[u8; 8]> {
+        let mut pairs = self.target_info;
+
+        while let (Some(id), Some(len)) = (u16_at(pairs, 0), u16_at(pairs, 2)) {
+            let value = pairs.get(4..4 + len as usize)?;
+
+            match id {
+                AV_EOL => break,
+                AV_TIMESTAMP => return value.try_into().ok(),
+                _ => pairs = &pairs[4 + len as usize..],
+            }
+        }
+
+        None
+    }
+}
+
+fn utf16le(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+fn hmac_md5(key: &[u8], data: &[&[u8]]) -> Result<[u8; 16]> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::md5(), &key)?;
+    for data in data {
+        signer.update(data)?;
+    }
+
+    let mut mac = [0; 16];
+    signer.sign(&mut mac)?;
+
+    Ok(mac)
+}
+
+/// `NTOWFv2`, the key for the user's responses
+fn ntowf_v2(username: &str, domain: &str, password: &str) -> Result<[u8; 16]> {
+    hmac_md5(&md4(&utf16le(password)), &[&utf16le(&username.to_uppercase()), &utf16le(domain)])
+}
+
+/// The LMv2 and NTLMv2 responses to `challenge`
+fn responses(key: &[u8; 16], challenge: &Challenge, client_challenge: &[u8; 8], timestamp: &[u8; 8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut temp = vec![1, 1, 0, 0, 0, 0, 0, 0];
+    temp.extend_from_slice(timestamp);
+    temp.extend_from_slice(client_challenge);
+    temp.extend_from_slice(&[0; 4]);
+    temp.extend_from_slice(challenge.target_info);
+    temp.extend_from_slice(&[0; 4]);
+
+    let nt_proof = hmac_md5(key, &[&challenge.server_challenge, &temp])?;
+    let nt_response = [&nt_proof[..], &temp].concat();
+
+    // when the server sends a timestamp, the LM response must be empty (all zeros)
+    let lm_response = match challenge.timestamp() {
+        Some(_) => vec![0; 24],
+        None => [&hmac_md5(key, &[&challenge.server_challenge, client_challenge])?[..], client_challenge].concat(),
+    };
+
+    Ok((lm_response, nt_response))
+}
+
+/// The final message of the handshake, answering the server's `challenge`.
+///
+/// `username` may be `DOMAIN\user` or `user@domain`.
+pub fn authenticate(challenge: &[u8], username: &str, password: &str) -> Result<Vec<u8>> {
+    let challenge = Challenge::parse(challenge)?;
+
+    let (domain, username) = username.split_once('\\').unwrap_or(("", username));
+
+    let mut client_challenge = [0; 8];
+    openssl::rand::rand_bytes(&mut client_challenge)?;
+
+    let timestamp = challenge.timestamp().unwrap_or_else(|| {
+        let since_unix = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let filetime = (since_unix.as_secs() + FILETIME_UNIX_EPOCH) * 10_000_000 + u64::from(since_unix.subsec_nanos() / 100);
+        filetime.to_le_bytes()
+    });
+
+    let key = ntowf_v2(username, domain, password)?;
+    let (lm_response, nt_response) = responses(&key, &challenge, &client_challenge, &timestamp)?;
+
+    let fields: [&[u8]; 6] = [&lm_response, &nt_response, &utf16le(domain), &utf16le(username), &[], &[]];
+
+    const HEADER_LEN: usize = 64;
+    let mut message = Vec::with_capacity(HEADER_LEN + fields.iter().map(|f| f.len()).sum::<usize>());
+    message.extend_from_slice(SIGNATURE);
+    message.extend_from_slice(&AUTHENTICATE_MESSAGE.to_le_bytes());
+
+    let mut offset = HEADER_LEN;
+    for field in &fields {
+        let len = u16::try_from(field.len()).context("NTLM field too long")?;
+        message.extend_from_slice(&len.to_le_bytes());
+        message.extend_from_slice(&len.to_le_bytes());
+        message.extend_from_slice(&(offset as u32).to_le_bytes());
+        offset += field.len();
+    }
+
+    message.extend_from_slice(&(FLAGS & challenge.flags).to_le_bytes());
+
+    for field in &fields {
+        message.extend_from_slice(field);
+    }
+
+    Ok(message)
+}
+
+/// MD4 (RFC 1320), which the NT hash is built from. OpenSSL 3 only provides it in the legacy provider
+fn md4(data: &[u8]) -> [u8; 16] {
+    let mut state: [u32; 4] = [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476];
+
+    let mut padded = data.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend_from_slice(&((data.len() as u64) * 8).to_le_bytes());
+
+    for block in padded.chunks_exact(64) {
+        let x: Vec<u32> = block.chunks_exact(4).map(|w| u32::from_le_bytes(w.try_into().expect("4 bytes"))).collect();
+        let [mut a, mut b, mut c, mut d] = state;
+
+        let f = |x: u32, y: u32, z: u32| (x & y) | (!x & z);
+        let g = |x: u32, y: u32, z: u32| (x & y) | (x & z) | (y & z);
+        let h = |x: u32, y: u32, z: u32| x ^ y ^ z;
+
+        for &i in &[0, 4, 8, 12] {
+            a = a.wrapping_add(f(b, c, d)).wrapping_add(x[i]).rotate_left(3);
+            d = d.wrapping_add(f(a, b, c)).wrapping_add(x[i + 1]).rotate_left(7);
+            c = c.wrapping_add(f(d, a, b)).wrapping_add(x[i + 2]).rotate_left(11);
+            b = b.wrapping_add(f(c, d, a)).wrapping_add(x[i + 3]).rotate_left(19);
+        }
+
+        for &i in &[0, 1, 2, 3] {
+            a = a.wrapping_add(g(b, c, d)).wrapping_add(x[i]).wrapping_add(0x5a82_7999).rotate_left(3);
+            d = d.wrapping_add(g(a, b, c)).wrapping_add(x[i + 4]).wrapping_add(0x5a82_7999).rotate_left(5);
+            c = c.wrapping_add(g(d, a, b)).wrapping_add(x[i + 8]).wrapping_add(0x5a82_7999).rotate_left(9);
+            b = b.wrapping_add(g(c, d, a)).wrapping_add(x[i + 12]).wrapping_add(0x5a82_7999).rotate_left(13);
+        }
+
+        for &i in &[0, 2, 1, 3] {
+            a = a.wrapping_add(h(b, c, d)).wrapping_add(x[i]).wrapping_add(0x6ed9_eba1).rotate_left(3);
+            d = d.wrapping_add(h(a, b, c)).wrapping_add(x[i + 8]).wrapping_add(0x6ed9_eba1).rotate_left(9);
+            c = c.wrapping_add(h(d, a, b)).wrapping_add(x[i + 4]).wrapping_add(0x6ed9_eba1).rotate_left(11);
+            b = b.wrapping_add(h(c, d, a)).wrapping_add(x[i + 12]).wrapping_add(0x6ed9_eba1).rotate_left(15);
+        }
+
+        for (s, v) in state.iter_mut().zip([a, b, c, d]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+
+    let mut digest = [0; 16];
+    for (chunk, s) in digest.chunks_exact_mut(4).zip(state) {
+        chunk.copy_from_slice(&s.to_le_bytes());
+    }
+
+    digest
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    #[test]
+    fn test_ntlmv2() {
+        // RFC 1320
+        assert_eq!(hex(&md4(b"")), "31d6cfe0d16ae931b73c59d7e0c089c0");
+        assert_eq!(hex(&md4(b"abc")), "a448017aaf21d8525fc10ae87aa6729d");
+        assert_eq!(hex(&md4(b"12345678901234567890123456789012345678901234567890123456789012345678901234567890")), "e33b4ddc9c38f2199c3e7b164fcc0536");
+
+        // MS-NLMP 4.2.4
+        assert_eq!(hex(&md4(&utf16le("Password"))), "a4f49c406510bdcab6824ee7c30fd852");
+
+        let key = ntowf_v2("User", "Domain", "Password").unwrap();
+        assert_eq!(hex(&key), "0c868a403bfd7a93a3001ef22ef02e3f");
+
+        let target_info = [
+            &[0x02, 0x00, 0x0c, 0x00][..], &utf16le("Domain"),
+            &[0x01, 0x00, 0x0c, 0x00], &utf16le("Server"),
+            &[0x00, 0x00, 0x00, 0x00],
+        ].concat();
+        let challenge = Challenge { flags: 0, server_challenge: [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef], target_info: &target_info };
+        assert_eq!(challenge.timestamp(), None);
+
+        let (lm_response, nt_response) = responses(&key, &challenge, &[0xaa; 8], &[0; 8]).unwrap();
+        assert_eq!(hex(&lm_response), "86c35097ac9cec102554764a57cccc19aaaaaaaaaaaaaaaa");
+        assert_eq!(hex(&nt_response[..16]), "68cd0ab851e51c96aabc927bebef6a1c");
+
+        // a challenge as a server sends it, with a timestamp
+        let target_info = [&[0x07, 0x00, 0x08, 0x00][..], &[1, 2, 3, 4, 5, 6, 7, 8], &[0, 0, 0, 0]].concat();
+        let mut message = [&SIGNATURE[..], &CHALLENGE_MESSAGE.to_le_bytes(), &[0; 8], &(FLAGS | NEGOTIATE_TARGET_INFO).to_le_bytes(), &[9; 8], &[0; 8]].concat();
+        message.extend_from_slice(&(target_info.len() as u16).to_le_bytes());
+        message.extend_from_slice(&(target_info.len() as u16).to_le_bytes());
+        message.extend_from_slice(&48u32.to_le_bytes());
+        message.extend_from_slice(&target_info);
+
+        let challenge = Challenge::parse(&message).unwrap();
+        assert_eq!(challenge.server_challenge, [9; 8]);
+        assert_eq!(challenge.timestamp(), Some([1, 2, 3, 4, 5, 6, 7, 8]));
+
+        let authenticate = authenticate(&message, r"DOMAIN\user", "Password").unwrap();
+        assert_eq!(&authenticate[..12], &[&SIGNATURE[..], &AUTHENTICATE_MESSAGE.to_le_bytes()].concat());
+        // the domain is the third field
+        let (len, offset) = (u16_at(&authenticate, 28).unwrap() as usize, u32_at(&authenticate, 32).unwrap() as usize);
+        assert_eq!(authenticate[offset..offset + len], utf16le("DOMAIN"));
+
+        assert!(Challenge::parse(&negotiate()).is_err());
+        assert!(Challenge::parse(&message[..30]).is_err());
+    }
+}
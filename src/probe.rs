@@ -0,0 +1,94 @@
+//! Pre-flight reachability checks.
+//!
+//! A TCP connection to the port each remote is managed through, made before the certificates are
+//! checked, so that a remote which is down or firewalled off is reported straight away rather than
+//! partway through a run. Nothing is sent over the connection.
+
+use std::{fmt, net::IpAddr, time::Duration};
+
+use anyhow::{anyhow, Result};
+use tokio::net::TcpStream;
+use url::Url;
+
+/// Where a remote is connected to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    /// The hostname or IP address, without brackets for IPv6 addresses
+    pub host: String,
+    pub port: u16,
+
+    /// dialled instead of resolving `host`
+    pub address: Option<IpAddr>,
+}
+
+impl Endpoint {
+    /// The endpoint of an HTTP(S) remote at `url`, or `None` if it's reached through a proxy from the environment
+    pub fn of_url(url: &Url, address: Option<IpAddr>) -> Option<Endpoint> {
+        let host = match url.host()? {
+            url::Host::Ipv6(addr) => addr.to_string(),
+            host => host.to_string(),
+        };
+
+        if is_proxied(url.scheme(), &host) {
+            return None;
+        }
+
+        Some(Endpoint { host, port: url.port_or_known_default()?, address })
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.host.contains(':') {
+            true => write!(f, "[{}]:{}", self.host, self.port),
+            false => write!(f, "{}:{}", self.host, self.port),
+        }
+    }
+}
+
+/// Does the environment send requests for `scheme` URLs on `host` through a proxy, as reqwest reads it?
+fn is_proxied(scheme: &str, host: &str) -> bool {
+    let var = |name: &str| std::env::var(name).or_else(|_| std::env::var(name.to_lowercase())).ok().filter(|v| !v.is_empty());
+
+    let proxied = var(&format!("{}_PROXY", scheme.to_uppercase())).or_else(|| var("ALL_PROXY")).is_some();
+
+    proxied && !var("NO_PROXY").is_some_and(|no_proxy| crate::socks::no_proxy_matches(&no_proxy, host))
+}
+
+/// Check `endpoint` accepts TCP connections within `timeout`
+pub async fn probe(endpoint: &Endpoint, timeout: Duration) -> Result<()> {
+    let connect = async {
+        match endpoint.address {
+            Some(address) => TcpStream::connect((address, endpoint.port)).await,
+            None => TcpStream::connect((endpoint.host.as_str(), endpoint.port)).await,
+        }
+    };
+
+    match tokio::time::timeout(timeout, connect).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(anyhow::Error::new(e).context(format!("unable to connect to {endpoint}"))),
+        Err(elapsed) => Err(anyhow!(elapsed).context(format!("no response from {endpoint} within {}s", timeout.as_secs_f64()))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_probe() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let endpoint = Endpoint { host: "localhost".to_string(), port, address: Some("127.0.0.1".parse().unwrap()) };
+        assert_eq!(endpoint.to_string(), format!("localhost:{port}"));
+        probe(&endpoint, Duration::from_secs(5)).await.unwrap();
+
+        drop(listener);
+        let e = probe(&endpoint, Duration::from_secs(5)).await.unwrap_err();
+        assert!(e.to_string().starts_with("unable to connect to localhost:"), "{e:#}");
+
+        let endpoint = Endpoint::of_url(&Url::parse("https://[2001:db8::1]/redfish/v1").unwrap(), None).unwrap();
+        assert_eq!((endpoint.to_string().as_str(), endpoint.address), ("[2001:db8::1]:443", None));
+    }
+}
@@ -0,0 +1,267 @@
+//! Caddy, via its admin API
+//!
+//! The certificate is loaded with the `tls` app's `load_pem` loader, as an entry with an `@id`,
+//! so later updates replace it in place through `/id/...`. Caddy applies config changes
+//! without a restart. Sites using the certificate by tag can match on the same ID.
+
+use std::{net::IpAddr, rc::Rc};
+
+use reqwest::{Client, Method, RequestBuilder, Url};
+use serde::{de, Deserialize, Serialize};
+use serde_json::{json, Value};
+use anyhow::{anyhow, bail, Context, Result};
+use tracing::info;
+
+use crate::{config::{CertificatePair, CertificateRef, CredentialPathBuf, GlobalCertificates, SecretSource}, timing};
+
+#[derive(Deserialize, Debug)]
+struct RawConfig {
+    certificate: CertificateRef,
+
+    /// The admin endpoint (Caddy's `admin.listen`)
+    #[serde(default = "default_url")]
+    url: Url,
+
+    /// For basic auth, when the admin API is behind an authenticating proxy. The username is taken from the URL
+    password_file: Option<CredentialPathBuf>,
+
+    /// environment variable containing the password, instead of `password_file`
+    password_env: Option<String>,
+
+    /// e.g., a `client_identity` for Caddy's remote admin endpoint
+    #[serde(default)]
+    http: crate::http::Config,
+
+    /// Connect to this IP address instead of resolving the URL's hostname
+    address: Option<IpAddr>,
+
+    /// The `@id` (and tag) of the loaded certificate in Caddy's config
+    #[serde(default = "default_id")]
+    id: String,
+}
+
+fn default_url() -> Url {
+    Url::parse("http://localhost:2019/").expect("valid default URL")
+}
+
+fn default_id() -> String {
+    "certinstaller".to_string()
+}
+
+/// This remote's section of the example config
+pub(crate) fn sample() -> String {
+    format!(r#"[caddy.web]
+certificate = "default"
+# url = {}             # the admin endpoint
+# id = {}                       # the `@id` and tag of the loaded certificate
+# password_file = "caddy-password"           # when the admin API is behind a proxy with basic auth
+# http.client_identity = {{ certificate_path = "client.pem", private_key_path = "client.key" }}
+# http.user_agent = "Mozilla/5.0"           # instead of the top-level `user_agent`
+# http.max_response_bytes = {max_response_bytes}         # refuse larger responses
+"#, crate::sample::value(&default_url().as_str()), crate::sample::value(&default_id()), max_response_bytes = crate::http::DEFAULT_MAX_RESPONSE_BYTES)
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Config<CertT> {
+    pub certificate: CertT,
+
+    #[serde(serialize_with = "crate::config::serialize_url_redacted")]
+    pub url: Url,
+
+    /// used when the URL doesn't contain a password
+    #[serde(serialize_with = "crate::config::serialize_redacted", skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+
+    #[serde(skip_serializing_if = "crate::http::Config::is_empty")]
+    pub http: crate::http::Config,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<IpAddr>,
+
+    pub id: String,
+}
+
+impl Config<CertificateRef> {
+    pub fn try_resolve_certificate(self, global_certs: &GlobalCertificates) -> Result<Config<Rc<CertificatePair>>> {
+        Ok(Config {
+            certificate: self.certificate.try_resolve(global_certs).map_err(|e| anyhow!("{e} for key `certificate`"))?,
+            url: self.url,
+            password: self.password,
+            http: self.http,
+            address: self.address,
+            id: self.id,
+        })
+    }
+}
+
+impl<CertT> Config<CertT> {
+    /// The admin API
+    pub fn endpoint(&self) -> Option<crate::probe::Endpoint> {
+        crate::probe::Endpoint::of_url(&self.url, self.address)
+    }
+}
+
+impl <'de> Deserialize<'de> for Config<CertificateRef> {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>
+    {
+        let raw = RawConfig::deserialize(deserializer)?;
+
+        match raw.url.scheme() {
+            "http" | "https" => (),
+            other => return Err(de::Error::custom(format!("unknown protocol '{other}'"))),
+        }
+
+        if raw.id.is_empty() || raw.id.contains('/') {
+            return Err(de::Error::custom(format!("invalid `id` \"{}\"", raw.id)));
+        }
+
+        let password = SecretSource::from_keys(raw.password_file, raw.password_env, "password_file", "password_env")
+            .map_err(de::Error::custom)?
+            .map(|source| {
+                source.read_to_string()
+                    .map(|p| p.trim_end_matches(['\r', '\n']).to_string())
+                    .map_err(|e| de::Error::custom(format!("failed to read password {source} ({e:#})")))
+            })
+            .transpose()?;
+
+        Ok(Config { certificate: raw.certificate, url: raw.url, password, http: raw.http, address: raw.address, id: raw.id })
+    }
+}
+
+/// Where `load_pem` certificates live in Caddy's config
+const LOAD_PEM_PATH: [&str; 4] = ["apps", "tls", "certificates", "load_pem"];
+
+fn config_path(keys: &[&str]) -> String {
+    format!("config/{}", keys.join("/"))
+}
+
+/// `value` nested in objects under `keys`, outermost first
+fn nest(value: Value, keys: &[&str]) -> Value {
+    keys.iter().rev().fold(value, |value, key| json!({ *key: value }))
+}
+
+struct Api {
+    base_url: Url,
+    client: Client,
+    username: String,
+    password: Option<String>,
+    max_response_bytes: u64,
+}
+
+impl Api {
+    fn new(config: &Config<Rc<CertificatePair>>) -> Result<Self> {
+        let mut base_url = config.url.join("/").expect("valid base_url");
+
+        // credentials are sent as basic auth, never in the URL
+        let _ = base_url.set_username("");
+        let _ = base_url.set_password(None);
+
+        let client = crate::http::resolve_to(config.http.client_builder(), &config.url, config.address)
+            .build().context("failed to build a Client")?;
+
+        Ok(Api {
+            base_url,
+            client,
+            username: config.url.username().to_string(),
+            password: config.url.password().map(str::to_string).or(config.password.clone()),
+            max_response_bytes: config.http.max_response_bytes(),
+        })
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let request = self.client.request(method, self.base_url.join(path).expect("valid API url"));
+
+        match self.username.is_empty() && self.password.is_none() {
+            true => request,
+            false => request.basic_auth(&self.username, self.password.as_deref()),
+        }
+    }
+
+    /// The config value at `path`, which is `null` if it doesn't exist
+    async fn get(&self, path: &str) -> Result<Value> {
+        let response = self.request(Method::GET, path)
+            .send().await.context("failed to send request")?
+            .error_for_status().with_context(|| format!("failed to read /{path}"))?;
+
+        crate::http::json(response, self.max_response_bytes).await.context("failed to decode JSON response")
+    }
+
+    async fn send(&self, method: Method, path: &str, body: &Value) -> Result<()> {
+        let response = self.request(method, path)
+            .json(body)
+            .send().await.context("failed to send request")?;
+
+        // Caddy explains rejected config in the response body
+        if let Err(e) = response.error_for_status_ref() {
+            let body = crate::http::text(response, self.max_response_bytes).await.unwrap_or_default();
+            return Err(e).with_context(|| format!("Caddy rejected the change to /{path}: {}", body.trim()));
+        }
+
+        Ok(())
+    }
+}
+
+/// Read the config, without changing anything
+pub async fn test_connection(config: &Config<Rc<CertificatePair>>) -> Result<()> {
+    Api::new(config)?.get("config/").await?;
+
+    Ok(())
+}
+
+pub async fn update_certificate(config: &Config<Rc<CertificatePair>>) -> Result<()> {
+    timing::phase("upload", load_certificate(config)).await
+}
+
+async fn load_certificate(config: &Config<Rc<CertificatePair>>) -> Result<()> {
+    let api = Api::new(config)?;
+    let id = &config.id;
+
+    let entry = json!({
+        "@id": id,
+        "certificate": config.certificate.fullchain_certificate_pem_string()?,
+        "key": config.certificate.private_key_pem_string()?,
+        "tags": [id],
+    });
+
+    info!("reading loaded certificates from {}", api.base_url);
+    match api.get(&config_path(&LOAD_PEM_PATH)).await? {
+        Value::Array(entries) if entries.iter().any(|e| e["@id"] == *id) => {
+            info!("replacing certificate \"{id}\"");
+            api.send(Method::PATCH, &format!("id/{id}"), &entry).await
+        },
+        Value::Array(_) => {
+            info!("adding certificate \"{id}\"");
+            api.send(Method::POST, &config_path(&LOAD_PEM_PATH), &entry).await
+        },
+        Value::Null => {
+            // create whatever part of the path doesn't exist yet, beneath the deepest part that does
+            let mut depth = LOAD_PEM_PATH.len() - 1;
+            while depth > 0 && api.get(&config_path(&LOAD_PEM_PATH[..depth])).await?.is_null() {
+                depth -= 1;
+            }
+
+            let (path, value) = match depth == 0 && api.get("config/").await?.is_null() {
+                true => ("config/".to_string(), nest(json!([entry]), &LOAD_PEM_PATH)),
+                false => (config_path(&LOAD_PEM_PATH[..=depth]), nest(json!([entry]), &LOAD_PEM_PATH[depth + 1..])),
+            };
+
+            info!("adding certificate \"{id}\"");
+            api.send(Method::POST, &path, &value).await
+        },
+        other => bail!("unexpected value at /{}: {other}", config_path(&LOAD_PEM_PATH)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_nest() {
+        assert_eq!(nest(json!([1]), &LOAD_PEM_PATH[2..]), json!({ "certificates": { "load_pem": [1] } }));
+        assert_eq!(nest(json!([1]), &[]), json!([1]));
+        assert_eq!(config_path(&LOAD_PEM_PATH[..2]), "config/apps/tls");
+    }
+}
@@ -0,0 +1,277 @@
+//! Local PEM files
+//!
+//! For a service on the same host that reads its certificate chain and key from files, such as nginx reading
+//! a directory that's synced elsewhere. The files are replaced atomically, and the service is told to reload
+//! by sending a signal to the process in its PID file. Replaced files keep their owner and group, and their mode
+//! unless one is configured.
+
+use std::{path::Path, rc::Rc};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{de, Deserialize, Serialize};
+use tracing::info;
+
+use crate::{config::{CertificatePair, CertificateRef, CredentialPathBuf, GlobalCertificates}, deploy, file::write_atomic_preserving};
+
+/// The signal sent to reload the service
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Signal {
+    #[default]
+    #[serde(rename = "SIGHUP")]
+    Hup,
+    #[serde(rename = "SIGUSR1")]
+    Usr1,
+    #[serde(rename = "SIGUSR2")]
+    Usr2,
+    #[serde(rename = "SIGTERM")]
+    Term,
+    #[serde(rename = "SIGQUIT")]
+    Quit,
+}
+
+impl Signal {
+    fn number(self) -> libc::c_int {
+        match self {
+            Signal::Hup => libc::SIGHUP,
+            Signal::Usr1 => libc::SIGUSR1,
+            Signal::Usr2 => libc::SIGUSR2,
+            Signal::Term => libc::SIGTERM,
+            Signal::Quit => libc::SIGQUIT,
+        }
+    }
+}
+
+impl std::fmt::Display for Signal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Signal::Hup => "SIGHUP",
+            Signal::Usr1 => "SIGUSR1",
+            Signal::Usr2 => "SIGUSR2",
+            Signal::Term => "SIGTERM",
+            Signal::Quit => "SIGQUIT",
+        })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct RawConfig {
+    pub certificate: CertificateRef,
+
+    /// Where to write the certificate chain
+    pub cert_path: CredentialPathBuf,
+
+    /// Where to write the private key
+    pub key_path: CredentialPathBuf,
+
+    /// Defaults to the mode of the file being replaced, or 0644 for a new file
+    pub cert_mode: Option<u32>,
+
+    /// Can't allow access by other users. Defaults to the mode of the file being replaced, or 0600 for a new file
+    pub key_mode: Option<u32>,
+
+    /// The file containing the PID of the process to signal after writing the files. Nothing is signalled without one
+    pub pid_file: Option<CredentialPathBuf>,
+
+    #[serde(default)]
+    pub reload_signal: Signal,
+}
+
+impl RawConfig {
+    fn default_cert_mode() -> u32 {
+        0o644
+    }
+
+    fn default_key_mode() -> u32 {
+        0o600
+    }
+}
+
+/// This remote's section of the example config
+pub(crate) fn sample() -> String {
+    format!(r#"[file.web]
+certificate = "default"
+cert_path = "/etc/nginx/certs/fullchain.pem"
+key_path = "/etc/nginx/certs/privkey.pem"
+# cert_mode = 0o{:o}                            # defaults to the replaced file's, as does the key's mode
+# key_mode = 0o{:o}
+# pid_file = "/run/nginx.pid"                # the process to signal once the files are written
+# reload_signal = {}                   # or "SIGUSR1", "SIGUSR2", "SIGTERM" or "SIGQUIT"
+"#, RawConfig::default_cert_mode(), RawConfig::default_key_mode(), crate::sample::value(&Signal::default()))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Config<CertT> {
+    pub certificate: CertT,
+
+    cert_path: CredentialPathBuf,
+    key_path: CredentialPathBuf,
+    #[serde(serialize_with = "crate::deploy::serialize_mode", skip_serializing_if = "Option::is_none")]
+    cert_mode: Option<u32>,
+    #[serde(serialize_with = "crate::deploy::serialize_mode", skip_serializing_if = "Option::is_none")]
+    key_mode: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pid_file: Option<CredentialPathBuf>,
+    reload_signal: Signal,
+}
+
+impl Config<CertificateRef> {
+    pub fn try_resolve_certificate(self, global_certs: &GlobalCertificates) -> Result<Config<Rc<CertificatePair>>> {
+        Ok(Config {
+            certificate: self.certificate.try_resolve(global_certs).map_err(|e| anyhow!("{e} for key `certificate`"))?,
+            cert_path: self.cert_path,
+            key_path: self.key_path,
+            cert_mode: self.cert_mode,
+            key_mode: self.key_mode,
+            pid_file: self.pid_file,
+            reload_signal: self.reload_signal,
+        })
+    }
+}
+
+impl <'de> Deserialize<'de> for Config<CertificateRef> {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>
+    {
+        let raw = RawConfig::deserialize(deserializer)?;
+
+        if let Some(mode) = raw.cert_mode {
+            deploy::check_mode("cert_mode", mode, false).map_err(de::Error::custom)?;
+        }
+        if let Some(mode) = raw.key_mode {
+            deploy::check_mode("key_mode", mode, true).map_err(de::Error::custom)?;
+        }
+
+        Ok(Config {
+            certificate: raw.certificate,
+            cert_path: raw.cert_path,
+            key_path: raw.key_path,
+            cert_mode: raw.cert_mode,
+            key_mode: raw.key_mode,
+            pid_file: raw.pid_file,
+            reload_signal: raw.reload_signal,
+        })
+    }
+}
+
+/// The PID in `pid_file`
+fn read_pid(pid_file: &Path) -> Result<libc::pid_t> {
+    let contents = std::fs::read_to_string(pid_file).with_context(|| format!("failed to read PID file \"{}\"", pid_file.display()))?;
+
+    match contents.trim().parse() {
+        Ok(pid) if pid > 0 => Ok(pid),
+        _ => bail!("PID file \"{}\" doesn't contain a PID", pid_file.display()),
+    }
+}
+
+/// Send `signal` to `pid`. Signal 0 only checks the process exists and may be signalled
+fn kill(pid: libc::pid_t, signal: libc::c_int) -> std::io::Result<()> {
+    // SAFETY: kill(2) takes no pointers, and a positive PID names a single process
+    match unsafe { libc::kill(pid, signal) } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+/// Check the files' directories exist and the process in the PID file can be signalled, without writing anything
+pub async fn test_connection(config: &Config<Rc<CertificatePair>>) -> Result<()> {
+    for path in [&config.cert_path, &config.key_path] {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+
+        if !std::fs::metadata(dir).with_context(|| format!("unable to access \"{}\"", dir.display()))?.is_dir() {
+            bail!("\"{}\" isn't a directory", dir.display());
+        }
+    }
+
+    if let Some(pid_file) = &config.pid_file {
+        let pid = read_pid(pid_file)?;
+        kill(pid, 0).with_context(|| format!("unable to signal process {pid}"))?;
+    }
+
+    Ok(())
+}
+
+pub async fn update_certificate(config: &Config<Rc<CertificatePair>>) -> Result<()> {
+    let chain = config.certificate.fullchain_certificate_pem_string()?;
+    let key = config.certificate.private_key_pem_string()?;
+
+    let unchanged = |path: &Path, contents: &str| std::fs::read(path).is_ok_and(|existing| existing == contents.as_bytes());
+
+    if unchanged(&config.cert_path, &chain) && unchanged(&config.key_path, &key) {
+        info!("\"{}\" and \"{}\" already contain the certificate", config.cert_path.display(), config.key_path.display());
+        return Ok(());
+    }
+
+    // the key first, so a reader never sees the new certificate with the old key for longer than it takes to
+    // write the certificate
+    info!("writing \"{}\"", config.key_path.display());
+    write_atomic_preserving(&config.key_path, key.as_bytes(), config.key_mode, RawConfig::default_key_mode())?;
+
+    info!("writing \"{}\"", config.cert_path.display());
+    write_atomic_preserving(&config.cert_path, chain.as_bytes(), config.cert_mode, RawConfig::default_cert_mode())?;
+
+    if let Some(pid_file) = &config.pid_file {
+        let pid = read_pid(pid_file)?;
+
+        info!("sending {} to process {pid}", config.reload_signal);
+        kill(pid, config.reload_signal.number()).with_context(|| format!("failed to send {} to process {pid}", config.reload_signal))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, os::unix::{fs::PermissionsExt, process::ExitStatusExt}};
+
+    use figment::{providers::{Format, Toml}, Figment};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_write_files() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut process = std::process::Command::new("sleep").arg("30").spawn().unwrap();
+        std::fs::write(dir.path().join("app.pid"), format!("{}\n", process.id())).unwrap();
+
+        let config: Config<CertificateRef> = Figment::new().merge(Toml::string(&format!(r#"
+            certificate = "default"
+            cert_path = {:?}
+            key_path = {:?}
+            pid_file = {:?}
+            reload_signal = "SIGTERM"
+        "#, dir.path().join("fullchain.pem").display().to_string(), dir.path().join("privkey.pem").display().to_string(),
+            dir.path().join("app.pid").display().to_string()))).extract().unwrap();
+
+        let resolve = |config: Config<CertificateRef>| {
+            let certificates = HashMap::from([("default".to_string(), Rc::new(crate::test_util::certificate_pair(&["app.example.net"])))]);
+            config.try_resolve_certificate(&GlobalCertificates { certificates: &certificates, hostname: None }).unwrap()
+        };
+        let unresolved = config.clone();
+        let config = resolve(config);
+
+        test_connection(&config).await.unwrap();
+        update_certificate(&config).await.unwrap();
+
+        assert_eq!(std::fs::read_to_string(&config.cert_path).unwrap(), config.certificate.fullchain_certificate_pem_string().unwrap());
+        assert_eq!(std::fs::read_to_string(&config.key_path).unwrap(), config.certificate.private_key_pem_string().unwrap());
+        assert_eq!(std::fs::metadata(&config.key_path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(process.wait().unwrap().signal(), Some(libc::SIGTERM));
+
+        // left alone, and the PID file not read, when already up to date
+        std::fs::write(dir.path().join("app.pid"), "").unwrap();
+        update_certificate(&config).await.unwrap();
+
+        // a new certificate keeps the mode the file was given since
+        std::fs::set_permissions(&config.cert_path, std::fs::Permissions::from_mode(0o640)).unwrap();
+        let config = resolve(unresolved);
+        let e = update_certificate(&config).await.unwrap_err();
+        assert!(e.to_string().contains("doesn't contain a PID"), "{e:#}");
+        assert_eq!(std::fs::read_to_string(&config.cert_path).unwrap(), config.certificate.fullchain_certificate_pem_string().unwrap());
+        assert_eq!(std::fs::metadata(&config.cert_path).unwrap().permissions().mode() & 0o777, 0o640);
+    }
+}
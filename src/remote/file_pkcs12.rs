@@ -0,0 +1,183 @@
+//! A local PKCS#12 archive
+//!
+//! For software that reads its certificate from a `.p12`/`.pfx` file (e.g., Java applications
+//! or Windows services polling a directory), rather than being a device that's updated over the network.
+//! The archive is replaced atomically, and left untouched if it already contains the certificate chain.
+
+use std::{path::Path, rc::Rc};
+
+use anyhow::{anyhow, bail, Context, Result};
+use openssl::pkcs12::Pkcs12;
+use serde::{de, Deserialize, Serialize};
+use tracing::info;
+
+use crate::{config::{CertificatePair, CertificateRef, CredentialPathBuf, GlobalCertificates, SecretSource}, file::write_atomic};
+
+#[derive(Deserialize, Debug)]
+struct RawConfig {
+    pub certificate: CertificateRef,
+
+    /// Where to write the archive
+    pub path: CredentialPathBuf,
+
+    /// The archive's friendly name (the alias, in a Java keystore).
+    /// Defaults to the certificate's common name
+    pub friendly_name: Option<String>,
+
+    /// The archive password. Without one the archive is encrypted with an empty password
+    pub password_file: Option<CredentialPathBuf>,
+
+    /// environment variable containing the password, instead of `password_file`
+    pub password_env: Option<String>,
+}
+
+/// This remote's section of the example config
+pub(crate) fn sample() -> String {
+    r#"[pkcs12-file.app]
+certificate = "default"
+path = "/etc/app/keystore.p12"
+# friendly_name = "app"                      # defaults to the certificate's common name
+# password_file = "keystore-password"        # without one, the archive has an empty password
+# password_env = "KEYSTORE_PASSWORD"         # instead of password_file
+"#.to_string()
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Config<CertT> {
+    pub certificate: CertT,
+
+    path: CredentialPathBuf,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    friendly_name: Option<String>,
+
+    #[serde(serialize_with = "crate::config::serialize_redacted", skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+}
+
+impl Config<CertificateRef> {
+    pub fn try_resolve_certificate(self, global_certs: &GlobalCertificates) -> Result<Config<Rc<CertificatePair>>> {
+        Ok(Config {
+            certificate: self.certificate.try_resolve(global_certs).map_err(|e| anyhow!("{e} for key `certificate`"))?,
+            path: self.path,
+            friendly_name: self.friendly_name,
+            password: self.password,
+        })
+    }
+}
+
+impl <'de> Deserialize<'de> for Config<CertificateRef> {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>
+    {
+        let raw = RawConfig::deserialize(deserializer)?;
+
+        let password = SecretSource::from_keys(raw.password_file, raw.password_env, "password_file", "password_env")
+            .map_err(de::Error::custom)?
+            .map(|source| {
+                source.read_to_string()
+                    .map(|p| p.trim_end_matches(['\r', '\n']).to_string())
+                    .map_err(|e| de::Error::custom(format!("failed to read password {source} ({e:#})")))
+            })
+            .transpose()?;
+
+        Ok(Config { certificate: raw.certificate, path: raw.path, friendly_name: raw.friendly_name, password })
+    }
+}
+
+impl Config<Rc<CertificatePair>> {
+    fn friendly_name(&self) -> Result<String> {
+        if let Some(name) = &self.friendly_name {
+            return Ok(name.clone());
+        }
+
+        let summary = self.certificate.summary()?;
+
+        Ok(summary.subject.or_else(|| summary.names.into_iter().next()).unwrap_or_else(|| "certificate".to_string()))
+    }
+}
+
+/// Whether the archive at `path` opens with `password` and holds exactly `certificate`'s chain
+fn contains(path: &Path, password: &str, certificate: &CertificatePair) -> bool {
+    let Ok(der) = std::fs::read(path) else {
+        return false;
+    };
+
+    let Ok(archive) = Pkcs12::from_der(&der).and_then(|p| p.parse2(password)) else {
+        return false;
+    };
+
+    let chain = archive.cert.into_iter()
+        .chain(archive.ca.into_iter().flatten())
+        .map(|c| c.to_der())
+        .collect::<Result<Vec<_>, _>>();
+
+    chain.is_ok_and(|chain| chain.iter().map(Vec::as_slice).eq(certificate.certificate_chain.iter().map(|c| c.as_ref())))
+}
+
+/// Check the archive's directory exists, without writing anything
+pub async fn test_connection(config: &Config<Rc<CertificatePair>>) -> Result<()> {
+    let dir = match config.path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    if !std::fs::metadata(dir).with_context(|| format!("unable to access \"{}\"", dir.display()))?.is_dir() {
+        bail!("\"{}\" isn't a directory", dir.display());
+    }
+
+    Ok(())
+}
+
+pub async fn update_certificate(config: &Config<Rc<CertificatePair>>) -> Result<()> {
+    let password = config.password.as_deref().unwrap_or_default();
+
+    if contains(&config.path, password, &config.certificate) {
+        info!("\"{}\" already contains the certificate", config.path.display());
+        return Ok(());
+    }
+
+    let der = config.certificate.pkcs12_der(&config.friendly_name()?, password)?;
+
+    info!("writing \"{}\"", config.path.display());
+    write_atomic(&config.path, &der, 0o600)
+}
+
+#[cfg(test)]
+mod test {
+    use figment::{providers::{Format, Toml}, Figment};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_write_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keystore.p12");
+
+        let config = Config {
+            certificate: Rc::new(crate::test_util::certificate_pair(&["app.example.net"])),
+            path: Figment::new().merge(Toml::string(&format!("path = {:?}", path.display().to_string()))).extract_inner("path").unwrap(),
+            friendly_name: None,
+            password: Some("changeit".to_string()),
+        };
+
+        test_connection(&config).await.unwrap();
+        update_certificate(&config).await.unwrap();
+
+        let archive = Pkcs12::from_der(&std::fs::read(&path).unwrap()).unwrap().parse2("changeit").unwrap();
+        assert_eq!(archive.cert.unwrap().to_der().unwrap(), config.certificate.certificate_chain.first().as_ref());
+        assert_eq!(config.friendly_name().unwrap(), "app.example.net");
+        assert!(contains(&path, "changeit", &config.certificate));
+        assert!(!contains(&path, "wrong", &config.certificate));
+
+        // left alone when already up to date
+        let written = std::fs::read(&path).unwrap();
+        update_certificate(&config).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), written);
+
+        let config = Config { certificate: Rc::new(crate::test_util::certificate_pair(&["app.example.net"])), ..config };
+        update_certificate(&config).await.unwrap();
+        assert!(contains(&path, "changeit", &config.certificate));
+    }
+}
@@ -0,0 +1,244 @@
+//! HAProxy
+//!
+//! HAProxy loads the private key and certificate chain from a single PEM file.
+//! The combined file is uploaded over SFTP next to the existing file and renamed into place,
+//! the HAProxy configuration is checked with `haproxy -c`, and only if that passes is HAProxy reloaded.
+//! If the check or the reload fails the previous file is restored.
+
+use std::{net::IpAddr, rc::Rc};
+
+use anyhow::{anyhow, Result};
+use serde::{de, Deserialize, Serialize};
+use tracing::info;
+use url::Url;
+
+use crate::{config::{CertificatePair, CertificateRef, GlobalCertificates, PemLineEnding}, deploy::{self, Transfer}, error::RciError, sftp::FileAttributes, ssh::{exec, run_hooks, shell_quote, ssh_connect, ConnectOptions, Hook}, timing};
+
+#[derive(Deserialize, Debug)]
+struct RawConfig {
+    pub certificate: CertificateRef,
+
+    pub url: Url,
+
+    #[serde(rename = "ssh")]
+    pub ssh_config: crate::ssh::Config,
+
+    /// Connect to this IP address instead of resolving the URL's hostname
+    pub address: Option<IpAddr>,
+
+    /// `lf` or `crlf`, for firmware that only accepts one kind in PEM. Defaults to the platform's line ending
+    pub pem_line_ending: Option<PemLineEnding>,
+
+    /// Where to write the combined key + certificate chain PEM
+    pub pem_path: String,
+
+    /// Can't allow access by other users, as the file contains the private key.
+    /// Defaults to the mode of the file being replaced, or 0600 for a new file
+    pub pem_mode: Option<u32>,
+
+    /// User (name or ID) to own the file. Changing it generally requires logging in as root.
+    /// Without `owner` or `group`, the file keeps the owner and group of the file it replaces
+    pub owner: Option<String>,
+
+    /// Group (name or ID) for the file, e.g. `haproxy` with a `pem_mode` of `0640`
+    pub group: Option<String>,
+
+    #[serde(default = "RawConfig::default_haproxy")]
+    pub haproxy: String,
+
+    #[serde(default = "RawConfig::default_config_path")]
+    pub config_path: String,
+
+    #[serde(default = "RawConfig::default_reload_command")]
+    pub reload_command: String,
+}
+
+impl RawConfig {
+    fn default_haproxy() -> String {
+        "haproxy".to_string()
+    }
+
+    fn default_config_path() -> String {
+        "/etc/haproxy/haproxy.cfg".to_string()
+    }
+
+    fn default_reload_command() -> String {
+        "systemctl reload haproxy".to_string()
+    }
+
+    fn default_pem_mode() -> u32 {
+        0o600
+    }
+}
+
+/// This remote's section of the example config
+pub(crate) fn sample() -> String {
+    format!(r#"[haproxy.lb]
+certificate = "default"
+{}# where to write the combined private key and certificate chain (a `crt` in haproxy.cfg)
+pem_path = "/etc/haproxy/certs/example.net.pem"
+# pem_mode = 0o{:o}                             # defaults to the replaced file's, as does the owner
+# owner = "root"
+# group = "haproxy"
+# pem_line_ending = "lf"                     # or "crlf". Defaults to the platform's line ending
+# haproxy = {}
+# config_path = {}
+# reload_command = {}
+"#,
+        crate::sample::ssh("ssh://root@lb.example.net"),
+        RawConfig::default_pem_mode(),
+        crate::sample::value(&RawConfig::default_haproxy()),
+        crate::sample::value(&RawConfig::default_config_path()),
+        crate::sample::value(&RawConfig::default_reload_command()))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Config<CertT> {
+    pub certificate: CertT,
+
+    #[serde(rename = "ssh")]
+    ssh_options: ConnectOptions,
+
+    pem_path: String,
+    #[serde(serialize_with = "crate::deploy::serialize_mode", skip_serializing_if = "Option::is_none")]
+    pem_mode: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    owner: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group: Option<String>,
+    haproxy: String,
+    config_path: String,
+    reload_command: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pem_line_ending: Option<PemLineEnding>,
+}
+
+impl Config<CertificateRef> {
+    pub fn try_resolve_certificate(self, global_certs: &GlobalCertificates) -> Result<Config<Rc<CertificatePair>>> {
+        Ok(Config {
+            certificate: self.certificate.try_resolve(global_certs).map_err(|e| anyhow!("{e} for key `certificate`"))?,
+            ssh_options: self.ssh_options,
+            pem_path: self.pem_path,
+            pem_mode: self.pem_mode,
+            owner: self.owner,
+            group: self.group,
+            haproxy: self.haproxy,
+            config_path: self.config_path,
+            reload_command: self.reload_command,
+            pem_line_ending: self.pem_line_ending,
+        })
+    }
+}
+
+impl<CertT> Config<CertT> {
+    /// HAProxy on the host it was deployed to
+    pub fn default_verify_url(&self) -> Url {
+        crate::verify::https_url(self.ssh_options.host(), None)
+    }
+
+    /// Where the remote is managed through
+    pub fn endpoint(&self) -> Option<crate::probe::Endpoint> {
+        self.ssh_options.endpoint()
+    }
+}
+
+impl <'de> Deserialize<'de> for Config<CertificateRef> {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>
+    {
+        let raw = RawConfig::deserialize(deserializer)?;
+
+        let ssh_options = match raw.url.scheme() {
+            "ssh" => ConnectOptions::new(raw.url, &raw.ssh_config).map_err(de::Error::custom)?.with_address(raw.address),
+            other => return Err(de::Error::custom(format!("unknown protocol '{other}'"))),
+        };
+
+        if let Some(mode) = raw.pem_mode {
+            deploy::check_mode("pem_mode", mode, true).map_err(de::Error::custom)?;
+        }
+
+        Ok(Config {
+            certificate: raw.certificate,
+            ssh_options,
+            pem_path: raw.pem_path,
+            pem_mode: raw.pem_mode,
+            owner: raw.owner,
+            group: raw.group,
+            haproxy: raw.haproxy,
+            config_path: raw.config_path,
+            reload_command: raw.reload_command,
+            pem_line_ending: raw.pem_line_ending,
+        })
+    }
+}
+
+/// HAProxy expects the private key first, followed by the leaf certificate and any intermediates
+fn combined_pem(certificate: &CertificatePair, line_ending: Option<PemLineEnding>) -> Result<String> {
+    Ok(certificate.private_key_pem_string_with(line_ending)? + &certificate.fullchain_certificate_pem_string_with(line_ending)?)
+}
+
+pub async fn test_connection(config: &Config<Rc<CertificatePair>>) -> Result<()> {
+    crate::ssh::test_connection(&config.ssh_options).await
+}
+
+pub async fn update_certificate(config: &Config<Rc<CertificatePair>>) -> Result<()> {
+    let pem = combined_pem(&config.certificate, config.pem_line_ending)?;
+
+    let handle = ssh_connect(&config.ssh_options).await?;
+    run_hooks(&handle, &config.ssh_options, Hook::Pre).await?;
+
+    let owner = deploy::chown_spec(config.owner.as_deref(), config.group.as_deref());
+
+    let staged = deploy::stage(&handle, &[
+        deploy::File {
+            path: &config.pem_path,
+            contents: pem.as_bytes(),
+            attrs: FileAttributes::mode(config.pem_mode.unwrap_or_else(RawConfig::default_pem_mode)),
+            preserve: deploy::Preserve { mode: config.pem_mode.is_none(), owner: owner.is_none() },
+            owner,
+        },
+    ], config.ssh_options.transfer(Transfer::Sftp)).await?;
+
+    staged.commit().await?;
+
+    info!("checking HAProxy configuration");
+    let check_command = format!("{} -c -f {}", shell_quote(&config.haproxy), shell_quote(&config.config_path));
+    let check = timing::phase("check", exec(&handle, &check_command, &[])).await?;
+    if !check.success() {
+        staged.rollback().await;
+
+        return Err(RciError::RemoteRejected(anyhow!("HAProxy configuration check failed, not reloading: {}", check.output_lossy())).into());
+    }
+
+    info!("reloading HAProxy");
+    let reload = timing::phase("reload", exec(&handle, &config.reload_command, &[])).await
+        .and_then(|output| output.check("reload command"));
+    if let Err(e) = reload {
+        staged.rollback().await;
+
+        return Err(e);
+    }
+
+    staged.finish().await?;
+
+    run_hooks(&handle, &config.ssh_options, Hook::Post).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_combined_pem_order() {
+        let pair = crate::test_util::certificate_pair(&["lb.example.net"]);
+
+        let pem = combined_pem(&pair, None).unwrap();
+
+        let labels = pem.lines()
+            .filter_map(|l| l.strip_prefix("-----BEGIN "))
+            .collect::<Vec<_>>();
+
+        assert_eq!(labels, ["PRIVATE KEY-----", "CERTIFICATE-----", "CERTIFICATE-----"]);
+    }
+}
@@ -0,0 +1,268 @@
+//! Home Assistant
+//!
+//! Home Assistant serves the `ssl_certificate` and `ssl_key` files named in `configuration.yaml`,
+//! and only reads them at startup. The files are written over SSH (for HAOS, via the SSH add-on,
+//! whose `/ssl` directory is the default location), then Home Assistant is restarted either through
+//! its API with a long-lived access token, or with a command over SSH (e.g., `ha core restart` on HAOS).
+//! The update only succeeds once Home Assistant is back and serving the new certificate.
+//!
+//! The API's certificate is verified, so while Home Assistant is serving an untrusted (e.g., self-signed)
+//! certificate, restart it with `restart_command` instead.
+
+use std::{net::IpAddr, rc::Rc, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
+use reqwest::Client;
+use serde::{de, Deserialize, Serialize};
+use tracing::info;
+use url::Url;
+
+use crate::{config::{CertificatePair, CertificateRef, CredentialPathBuf, GlobalCertificates, SecretSource}, deploy::{self, Transfer}, sftp::FileAttributes, ssh::{exec, run_hooks, ssh_connect, ConnectOptions, Hook}, timing};
+
+/// How long Home Assistant's restart takes to begin, so it isn't mistaken for being back before it went away
+const RESTART_GRACE: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize, Debug)]
+struct RawConfig {
+    pub certificate: CertificateRef,
+
+    /// Where to write the files, over SSH
+    pub url: Url,
+
+    #[serde(rename = "ssh")]
+    pub ssh_config: crate::ssh::Config,
+
+    /// Connect to this IP address instead of resolving the URL's hostname
+    pub address: Option<IpAddr>,
+
+    /// `ssl_certificate` in `configuration.yaml`
+    #[serde(default = "RawConfig::default_certificate_chain_path")]
+    pub certificate_chain_path: String,
+
+    /// `ssl_key` in `configuration.yaml`
+    #[serde(default = "RawConfig::default_private_key_path")]
+    pub private_key_path: String,
+
+    /// Home Assistant itself, for restarting via the API and checking the new certificate is served.
+    /// Defaults to port 8123 on the SSH host
+    pub api_url: Option<Url>,
+
+    /// A long-lived access token, to restart via the API
+    pub token_file: Option<CredentialPathBuf>,
+
+    /// environment variable containing the token, instead of `token_file`
+    pub token_env: Option<String>,
+
+    /// A command to restart Home Assistant over SSH, instead of using the API
+    pub restart_command: Option<String>,
+
+    /// How long to wait for Home Assistant to serve the new certificate after restarting, in seconds
+    #[serde(default = "RawConfig::default_restart_timeout")]
+    pub restart_timeout: u64,
+}
+
+impl RawConfig {
+    fn default_certificate_chain_path() -> String {
+        "/ssl/fullchain.pem".to_string()
+    }
+
+    fn default_private_key_path() -> String {
+        "/ssl/privkey.pem".to_string()
+    }
+
+    fn default_restart_timeout() -> u64 {
+        300
+    }
+}
+
+/// How Home Assistant is restarted
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Restart {
+    Api {
+        #[serde(serialize_with = "crate::config::serialize_redacted")]
+        token: Option<String>,
+    },
+    Command(String),
+}
+
+/// This remote's section of the example config
+pub(crate) fn sample() -> String {
+    format!(r#"[homeassistant.ha]
+certificate = "default"
+{}# a long-lived access token, to restart Home Assistant through its API
+token_file = "ha-token"
+# token_env = "HA_TOKEN"                     # instead of token_file
+# restart_command = "ha core restart"        # instead of a token, to restart over SSH
+# certificate_chain_path = {}  # `ssl_certificate` in configuration.yaml
+# private_key_path = {}       # `ssl_key`
+# api_url = "https://ha.example.net:8123/"   # defaults to port 8123 on the SSH host
+# restart_timeout = {}
+"#,
+        crate::sample::ssh("ssh://root@ha.example.net:22222"),
+        crate::sample::value(&RawConfig::default_certificate_chain_path()),
+        crate::sample::value(&RawConfig::default_private_key_path()),
+        RawConfig::default_restart_timeout())
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Config<CertT> {
+    pub certificate: CertT,
+
+    #[serde(rename = "ssh")]
+    ssh_options: ConnectOptions,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<IpAddr>,
+
+    certificate_chain_path: String,
+    private_key_path: String,
+    api_url: Url,
+    restart: Restart,
+    restart_timeout: u64,
+
+    /// The config's top-level `user_agent`, set when it's loaded, for requests to the service
+    #[serde(skip)]
+    pub(crate) user_agent: Option<String>,
+}
+
+impl Config<CertificateRef> {
+    pub fn try_resolve_certificate(self, global_certs: &GlobalCertificates) -> Result<Config<Rc<CertificatePair>>> {
+        Ok(Config {
+            certificate: self.certificate.try_resolve(global_certs).map_err(|e| anyhow!("{e} for key `certificate`"))?,
+            ssh_options: self.ssh_options,
+            address: self.address,
+            certificate_chain_path: self.certificate_chain_path,
+            private_key_path: self.private_key_path,
+            api_url: self.api_url,
+            restart: self.restart,
+            restart_timeout: self.restart_timeout,
+            user_agent: self.user_agent,
+        })
+    }
+}
+
+impl<CertT> Config<CertT> {
+    /// Home Assistant's web interface
+    pub fn default_verify_url(&self) -> Url {
+        self.api_url.clone()
+    }
+
+    /// Where the remote is managed through
+    pub fn endpoint(&self) -> Option<crate::probe::Endpoint> {
+        self.ssh_options.endpoint()
+    }
+}
+
+impl <'de> Deserialize<'de> for Config<CertificateRef> {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>
+    {
+        let raw = RawConfig::deserialize(deserializer)?;
+
+        let ssh_options = match raw.url.scheme() {
+            "ssh" => ConnectOptions::new(raw.url, &raw.ssh_config).map_err(de::Error::custom)?.with_address(raw.address),
+            other => return Err(de::Error::custom(format!("unknown protocol '{other}'"))),
+        };
+
+        let api_url = match raw.api_url {
+            Some(url) if url.scheme() == "https" => url,
+            Some(url) => return Err(de::Error::custom(format!("`api_url` must be https, as it's used to check the certificate (not '{}')", url.scheme()))),
+            None => crate::verify::https_url(ssh_options.host(), Some(8123)),
+        };
+
+        let token = SecretSource::from_keys(raw.token_file, raw.token_env, "token_file", "token_env")
+            .map_err(de::Error::custom)?
+            .map(|source| {
+                source.read_to_string()
+                    .map(|t| t.trim().to_string())
+                    .map_err(|e| de::Error::custom(format!("failed to read token {source} ({e:#})")))
+            })
+            .transpose()?;
+
+        let restart = match (token, raw.restart_command) {
+            (Some(token), None) => Restart::Api { token: Some(token) },
+            (None, Some(command)) => Restart::Command(command),
+            (Some(_), Some(_)) => return Err(de::Error::custom("only one of a token (to restart via the API) and `restart_command` may be set")),
+            (None, None) => return Err(de::Error::custom("one of `token_file`/`token_env` (to restart via the API) or `restart_command` is required")),
+        };
+
+        Ok(Config {
+            certificate: raw.certificate,
+            ssh_options,
+            address: raw.address,
+            certificate_chain_path: raw.certificate_chain_path,
+            private_key_path: raw.private_key_path,
+            api_url,
+            restart,
+            restart_timeout: raw.restart_timeout,
+            user_agent: None,
+        })
+    }
+}
+
+/// Check the SSH connection and, if restarting via the API, that the token is accepted
+pub async fn test_connection(config: &Config<Rc<CertificatePair>>) -> Result<()> {
+    crate::ssh::test_connection(&config.ssh_options).await?;
+
+    if let Restart::Api { token } = &config.restart {
+        api_client(config)?
+            .get(config.api_url.join("api/").expect("valid API url"))
+            .bearer_auth(token.as_deref().unwrap_or_default())
+            .send().await.context("failed to send request")?
+            .error_for_status().context("the Home Assistant API rejected the request")?;
+    }
+
+    Ok(())
+}
+
+fn api_client(config: &Config<Rc<CertificatePair>>) -> Result<Client> {
+    crate::http::resolve_to(crate::http::client_builder(config.user_agent.as_deref()), &config.api_url, config.address)
+        .build().context("failed to build a Client")
+}
+
+pub async fn update_certificate(config: &Config<Rc<CertificatePair>>) -> Result<()> {
+    let chain = config.certificate.fullchain_certificate_pem_string()?;
+    let key = config.certificate.private_key_pem_string()?;
+
+    let handle = ssh_connect(&config.ssh_options).await?;
+    run_hooks(&handle, &config.ssh_options, Hook::Pre).await?;
+
+    let staged = deploy::stage(&handle, &[
+        deploy::File { path: &config.certificate_chain_path, contents: chain.as_bytes(), attrs: FileAttributes::mode(0o644), owner: None, preserve: deploy::Preserve::default() },
+        deploy::File { path: &config.private_key_path, contents: key.as_bytes(), attrs: FileAttributes::mode(0o600), owner: None, preserve: deploy::Preserve::default() },
+    ], config.ssh_options.transfer(Transfer::Sftp)).await?;
+
+    staged.commit().await?;
+
+    info!("restarting Home Assistant");
+    let restarted = match &config.restart {
+        Restart::Api { token } => timing::phase("restart", async {
+            api_client(config)?
+                .post(config.api_url.join("api/services/homeassistant/restart").expect("valid API url"))
+                .bearer_auth(token.as_deref().unwrap_or_default())
+                .send().await.context("failed to send request")?
+                .error_for_status().context("the Home Assistant API rejected the restart")?;
+
+            Ok(())
+        }).await,
+        Restart::Command(command) => timing::phase("restart", exec(&handle, command, &[])).await
+            .and_then(|output| output.check("restart command"))
+            .map(|_| ()),
+    };
+
+    if let Err(e) = restarted {
+        staged.rollback().await;
+
+        return Err(e);
+    }
+
+    staged.finish().await?;
+
+    info!("waiting for Home Assistant to serve the new certificate");
+    let timeout = Duration::from_secs(config.restart_timeout);
+    timing::phase("verify", crate::verify::wait_for_certificate(&config.api_url, config.address, config.user_agent.as_deref(), &config.certificate, RESTART_GRACE, timeout)).await?;
+
+    run_hooks(&handle, &config.ssh_options, Hook::Post).await
+}
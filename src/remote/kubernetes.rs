@@ -0,0 +1,567 @@
+//! A Kubernetes `kubernetes.io/tls` Secret, e.g. one an Ingress serves its certificate from
+//!
+//! The Secret's `tls.crt` (the full chain) and `tls.key` are set through the API server, creating the
+//! Secret if it doesn't exist yet. It's annotated with the certificate's fingerprint and expiry and when
+//! it was deployed, so `kubectl describe secret` shows what's installed. Whatever consumes the Secret
+//! (e.g. an ingress controller) picks up the change itself, so nothing is restarted.
+//!
+//! The API server and credentials are taken from `kubeconfig`, or else from the pod's service account
+//! when running in a cluster. The kubeconfig can be the YAML `kubectl` writes (e.g. `~/.kube/config`) or
+//! JSON; credential plugins (`exec`) aren't supported.
+
+use std::{collections::BTreeMap, fmt, path::Path, rc::Rc};
+
+use anyhow::{anyhow, bail, Context, Result};
+use openssl::base64::{decode_block, encode_block};
+use reqwest::{Certificate, Client, Identity, Method, RequestBuilder, StatusCode, Url};
+use serde::{de, Deserialize, Serialize, Serializer};
+use serde_json::{json, Value};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tracing::info;
+
+use crate::{config::{CertificatePair, CertificateRef, CredentialPathBuf, GlobalCertificates}, timing};
+
+/// Where a pod's service account credentials are mounted
+const SERVICE_ACCOUNT_PATH: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+/// The prefix of the annotations recording what was deployed
+const ANNOTATION_PREFIX: &str = "certinstaller";
+
+#[derive(Deserialize, Debug)]
+struct RawConfig {
+    certificate: CertificateRef,
+
+    secret_name: String,
+
+    /// Defaults to the namespace of the kubeconfig context or the service account, else `default`
+    namespace: Option<String>,
+
+    /// A kubeconfig file, e.g. `~/.kube/config`. Without one, the pod's service account is used
+    kubeconfig: Option<CredentialPathBuf>,
+
+    /// The kubeconfig context to use, instead of its `current-context`
+    context: Option<String>,
+
+    /// Set on the Secret, along with those recording the deployment
+    #[serde(default)]
+    annotations: BTreeMap<String, String>,
+
+    #[serde(default)]
+    http: crate::http::Config,
+}
+
+/// This remote's section of the example config
+pub(crate) fn sample() -> String {
+    format!(r#"[kubernetes.ingress]
+certificate = "default"
+secret_name = "example-tls"
+# namespace = "web"                          # default: the kubeconfig context's, or the service account's
+kubeconfig = "kubeconfig"                    # omit to use the pod's service account
+context = "admin@cluster"                    # instead of the kubeconfig's current-context
+# annotations = {{ "example.net/owner" = "ops" }}
+# http.max_response_bytes = {}         # refuse larger responses
+"#, crate::http::DEFAULT_MAX_RESPONSE_BYTES)
+}
+
+/// How requests to the API server are authenticated
+#[derive(Clone)]
+enum Credentials {
+    None,
+    Token(String),
+    ClientCertificate(Identity),
+}
+
+/// An API server, and how to reach and authenticate to it
+#[derive(Clone)]
+pub struct Cluster {
+    /// where the cluster config came from, for display
+    source: String,
+
+    server: Url,
+
+    /// trusted in addition to the system roots
+    ca_certificates: Vec<Certificate>,
+
+    /// from `insecure-skip-tls-verify`
+    insecure: bool,
+
+    credentials: Credentials,
+
+    /// of the kubeconfig context or service account
+    namespace: Option<String>,
+}
+
+impl fmt::Debug for Cluster {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cluster").field("source", &self.source).field("server", &self.server).finish_non_exhaustive()
+    }
+}
+
+impl Serialize for Cluster {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct View<'a> {
+            source: &'a str,
+            server: &'a str,
+            credentials: &'a str,
+            #[serde(skip_serializing_if = "std::ops::Not::not")]
+            insecure: bool,
+        }
+
+        View {
+            source: &self.source,
+            server: self.server.as_str(),
+            credentials: match self.credentials {
+                Credentials::None => "none",
+                Credentials::Token(_) => "token",
+                Credentials::ClientCertificate(_) => "client certificate",
+            },
+            insecure: self.insecure,
+        }.serialize(serializer)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Kubeconfig {
+    #[serde(default)]
+    clusters: Vec<NamedCluster>,
+    #[serde(default)]
+    contexts: Vec<NamedContext>,
+    #[serde(default)]
+    users: Vec<NamedUser>,
+    current_context: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NamedCluster {
+    name: String,
+    cluster: KubeconfigCluster,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct KubeconfigCluster {
+    server: Url,
+    certificate_authority: Option<String>,
+    certificate_authority_data: Option<String>,
+    #[serde(default)]
+    insecure_skip_tls_verify: bool,
+}
+
+#[derive(Deserialize)]
+struct NamedContext {
+    name: String,
+    context: KubeconfigContext,
+}
+
+#[derive(Deserialize)]
+struct KubeconfigContext {
+    cluster: String,
+    user: Option<String>,
+    namespace: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NamedUser {
+    name: String,
+    user: KubeconfigUser,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct KubeconfigUser {
+    token: Option<String>,
+    #[serde(rename = "tokenFile")]
+    token_file: Option<String>,
+    client_certificate: Option<String>,
+    client_certificate_data: Option<String>,
+    client_key: Option<String>,
+    client_key_data: Option<String>,
+    exec: Option<Value>,
+    auth_provider: Option<Value>,
+}
+
+/// Inline base64 `data`, or else the file at `path` (relative to `dir`)
+fn data_or_file(data: Option<&str>, path: Option<&str>, dir: &Path) -> Result<Option<Vec<u8>>> {
+    match (data, path) {
+        (Some(data), _) => Ok(Some(decode_block(data.trim()).context("invalid base64")?)),
+        (None, Some(path)) => {
+            let path = dir.join(path);
+            Ok(Some(std::fs::read(&path).with_context(|| format!("failed to read \"{}\"", path.display()))?))
+        },
+        (None, None) => Ok(None),
+    }
+}
+
+fn ca_certificates(pem: &[u8]) -> Result<Vec<Certificate>> {
+    let certificates = openssl::x509::X509::stack_from_pem(pem).context("failed to read the certificate authority")?;
+
+    certificates.iter()
+        .map(|certificate| Ok(Certificate::from_der(&certificate.to_der()?)?))
+        .collect()
+}
+
+impl Cluster {
+    /// The cluster of `context` (or the current context) in the kubeconfig at `path`
+    fn from_kubeconfig(path: &Path, context: Option<&str>) -> Result<Cluster> {
+        let contents = std::fs::read(path).with_context(|| format!("failed to read \"{}\"", path.display()))?;
+        let kubeconfig = crate::yaml::from_slice::<Kubeconfig>(&contents).context("failed to parse the kubeconfig")?;
+
+        Self::from_parsed_kubeconfig(kubeconfig, path, context)
+    }
+
+    fn from_parsed_kubeconfig(kubeconfig: Kubeconfig, path: &Path, context: Option<&str>) -> Result<Cluster> {
+        let dir = path.parent().unwrap_or(Path::new("."));
+
+        let Some(context_name) = context.or(kubeconfig.current_context.as_deref()) else {
+            bail!("the kubeconfig has no `current-context`, so `context` must be set");
+        };
+
+        let context = kubeconfig.contexts.iter().find(|c| c.name == context_name)
+            .ok_or_else(|| anyhow!("the kubeconfig has no context \"{context_name}\""))?;
+
+        let cluster = kubeconfig.clusters.iter().find(|c| c.name == context.context.cluster)
+            .ok_or_else(|| anyhow!("the kubeconfig has no cluster \"{}\" (from context \"{context_name}\")", context.context.cluster))?;
+
+        let user = match &context.context.user {
+            Some(user) => Some(&kubeconfig.users.iter().find(|u| u.name == *user)
+                .ok_or_else(|| anyhow!("the kubeconfig has no user \"{user}\" (from context \"{context_name}\")"))?.user),
+            None => None,
+        };
+
+        let ca = data_or_file(cluster.cluster.certificate_authority_data.as_deref(), cluster.cluster.certificate_authority.as_deref(), dir)
+            .context("failed to read the cluster's certificate authority")?;
+
+        let credentials = match user {
+            None => Credentials::None,
+            Some(user) if user.exec.is_some() || user.auth_provider.is_some() =>
+                bail!("the kubeconfig user of context \"{context_name}\" uses a credential plugin, which isn't supported (use a token or client certificate)"),
+            Some(KubeconfigUser { token: Some(token), .. }) => Credentials::Token(token.clone()),
+            Some(KubeconfigUser { token_file: Some(token_file), .. }) => {
+                let path = dir.join(token_file);
+                Credentials::Token(std::fs::read_to_string(&path).with_context(|| format!("failed to read \"{}\"", path.display()))?.trim().to_string())
+            },
+            Some(user) => {
+                let certificate = data_or_file(user.client_certificate_data.as_deref(), user.client_certificate.as_deref(), dir)
+                    .context("failed to read the user's client certificate")?;
+                let key = data_or_file(user.client_key_data.as_deref(), user.client_key.as_deref(), dir)
+                    .context("failed to read the user's client key")?;
+
+                match (certificate, key) {
+                    (Some(certificate), Some(key)) => {
+                        // native-tls only reads PKCS#8 keys, and kubeconfigs usually have PKCS#1 or SEC1 ones
+                        let key = openssl::pkey::PKey::private_key_from_pem(&key).context("failed to read the user's client key")?;
+                        Credentials::ClientCertificate(Identity::from_pkcs8_pem(&certificate, &key.private_key_to_pem_pkcs8()?)?)
+                    },
+                    (None, None) => Credentials::None,
+                    _ => bail!("the kubeconfig user of context \"{context_name}\" has a client certificate or key, but not both"),
+                }
+            },
+        };
+
+        Ok(Cluster {
+            source: format!("{} (context \"{context_name}\")", path.display()),
+            server: cluster.cluster.server.clone(),
+            ca_certificates: ca.as_deref().map(ca_certificates).transpose()?.unwrap_or_default(),
+            insecure: cluster.cluster.insecure_skip_tls_verify,
+            credentials,
+            namespace: context.context.namespace.clone(),
+        })
+    }
+
+    /// The cluster the pod is running in, with its service account's credentials
+    fn in_cluster() -> Result<Cluster> {
+        let Ok(host) = std::env::var("KUBERNETES_SERVICE_HOST") else {
+            bail!("`kubeconfig` isn't set, and this isn't running in a cluster (KUBERNETES_SERVICE_HOST is unset)");
+        };
+        let port = std::env::var("KUBERNETES_SERVICE_PORT").ok().and_then(|port| port.parse().ok());
+
+        let read = |name: &str| {
+            let path = Path::new(SERVICE_ACCOUNT_PATH).join(name);
+            std::fs::read(&path).with_context(|| format!("failed to read the service account's {name} (\"{}\")", path.display()))
+        };
+
+        let token = String::from_utf8_lossy(&read("token")?).trim().to_string();
+        let namespace = read("namespace").ok().map(|namespace| String::from_utf8_lossy(&namespace).trim().to_string());
+
+        Ok(Cluster {
+            source: "service account".to_string(),
+            server: crate::verify::https_url(&host, port),
+            ca_certificates: ca_certificates(&read("ca.crt")?)?,
+            insecure: false,
+            credentials: Credentials::Token(token),
+            namespace,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Config<CertT> {
+    pub certificate: CertT,
+
+    pub secret_name: String,
+    pub namespace: String,
+
+    pub cluster: Cluster,
+
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+
+    #[serde(skip_serializing_if = "crate::http::Config::is_empty")]
+    pub http: crate::http::Config,
+}
+
+impl Config<CertificateRef> {
+    pub fn try_resolve_certificate(self, global_certs: &GlobalCertificates) -> Result<Config<Rc<CertificatePair>>> {
+        Ok(Config {
+            certificate: self.certificate.try_resolve(global_certs).map_err(|e| anyhow!("{e} for key `certificate`"))?,
+            secret_name: self.secret_name,
+            namespace: self.namespace,
+            cluster: self.cluster,
+            annotations: self.annotations,
+            http: self.http,
+        })
+    }
+}
+
+impl<CertT> Config<CertT> {
+    /// The API server
+    pub fn endpoint(&self) -> Option<crate::probe::Endpoint> {
+        crate::probe::Endpoint::of_url(&self.cluster.server, None)
+    }
+}
+
+impl <'de> Deserialize<'de> for Config<CertificateRef> {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>
+    {
+        let raw = RawConfig::deserialize(deserializer)?;
+
+        let cluster = match &raw.kubeconfig {
+            Some(path) => Cluster::from_kubeconfig(path, raw.context.as_deref())
+                .map_err(|e| de::Error::custom(format!("failed to load kubeconfig \"{}\" ({e:#})", path.display())))?,
+            None if raw.context.is_some() => return Err(de::Error::custom("`context` requires `kubeconfig`")),
+            None => Cluster::in_cluster().map_err(|e| de::Error::custom(format!("{e:#}")))?,
+        };
+
+        let namespace = raw.namespace.or(cluster.namespace.clone()).unwrap_or_else(|| "default".to_string());
+
+        Ok(Config {
+            certificate: raw.certificate,
+            secret_name: raw.secret_name,
+            namespace,
+            cluster,
+            annotations: raw.annotations,
+            http: raw.http,
+        })
+    }
+}
+
+/// The annotations recording that `certificate` was deployed at `now`, along with the configured ones
+fn annotations(certificate: &CertificatePair, configured: &BTreeMap<String, String>, now: OffsetDateTime) -> Result<BTreeMap<String, String>> {
+    let summary = certificate.summary()?;
+
+    let mut annotations = configured.clone();
+    annotations.insert(format!("{ANNOTATION_PREFIX}/sha256-fingerprint"), summary.sha256_fingerprint);
+    annotations.insert(format!("{ANNOTATION_PREFIX}/not-after"), summary.not_after);
+    annotations.insert(format!("{ANNOTATION_PREFIX}/deployed-at"), now.format(&Rfc3339)?);
+
+    Ok(annotations)
+}
+
+/// The Secret's `data`
+fn secret_data(certificate: &CertificatePair) -> Result<Value> {
+    Ok(json!({
+        "tls.crt": encode_block(certificate.fullchain_certificate_pem_string()?.as_bytes()).replace('\n', ""),
+        "tls.key": encode_block(certificate.private_key_pem_string()?.as_bytes()).replace('\n', ""),
+    }))
+}
+
+struct Api {
+    client: Client,
+    secrets_url: Url,
+    credentials: Credentials,
+    max_response_bytes: u64,
+}
+
+impl Api {
+    fn new(config: &Config<Rc<CertificatePair>>) -> Result<Self> {
+        let mut builder = config.http.client_builder()
+            .danger_accept_invalid_certs(config.cluster.insecure);
+        for certificate in &config.cluster.ca_certificates {
+            builder = builder.add_root_certificate(certificate.clone());
+        }
+        if let Credentials::ClientCertificate(identity) = &config.cluster.credentials {
+            builder = builder.identity(identity.clone());
+        }
+
+        let segment = |s: &str| percent_encoding::utf8_percent_encode(s, percent_encoding::NON_ALPHANUMERIC).to_string();
+        let secrets_url = config.cluster.server.join(&format!("api/v1/namespaces/{}/secrets/", segment(&config.namespace)))
+            .context("invalid API server URL")?;
+
+        Ok(Api {
+            client: builder.build().context("failed to build a Client")?,
+            secrets_url,
+            credentials: config.cluster.credentials.clone(),
+            max_response_bytes: config.http.max_response_bytes(),
+        })
+    }
+
+    fn request(&self, method: Method, name: Option<&str>) -> RequestBuilder {
+        let url = match name {
+            Some(name) => self.secrets_url.join(&percent_encoding::utf8_percent_encode(name, percent_encoding::NON_ALPHANUMERIC).to_string()).expect("valid secret url"),
+            None => self.secrets_url.as_str().trim_end_matches('/').parse().expect("valid secrets url"),
+        };
+
+        let request = self.client.request(method, url).header("Accept", "application/json");
+
+        match &self.credentials {
+            Credentials::Token(token) => request.bearer_auth(token),
+            _ => request,
+        }
+    }
+
+    /// The Secret called `name`, or `None` if it doesn't exist
+    async fn get(&self, name: &str) -> Result<Option<Value>> {
+        let response = self.request(Method::GET, Some(name)).send().await.context("failed to send request")?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let response = self.check(response, "read").await?;
+        Ok(Some(crate::http::json(response, self.max_response_bytes).await.context("failed to decode JSON response")?))
+    }
+
+    /// Fail if the API server refused the request, with the reason it gave
+    async fn check(&self, response: reqwest::Response, what: &str) -> Result<reqwest::Response> {
+        match response.error_for_status_ref() {
+            Ok(_) => Ok(response),
+            Err(e) => {
+                // the API server explains refusals in a `Status` object
+                let body = crate::http::text(response, self.max_response_bytes).await.unwrap_or_default();
+                let message = serde_json::from_str::<Value>(&body).ok()
+                    .and_then(|status| status["message"].as_str().map(str::to_string))
+                    .unwrap_or(body);
+
+                Err(e).with_context(|| format!("the API server refused to {what} the Secret: {}", message.trim()))
+            },
+        }
+    }
+}
+
+/// Read the Secret (which needn't exist yet), without changing anything
+pub async fn test_connection(config: &Config<Rc<CertificatePair>>) -> Result<()> {
+    let api = Api::new(config)?;
+
+    match api.get(&config.secret_name).await? {
+        Some(_) => info!("Secret {}/{} exists", config.namespace, config.secret_name),
+        None => info!("Secret {}/{} doesn't exist, and will be created", config.namespace, config.secret_name),
+    }
+
+    Ok(())
+}
+
+pub async fn update_certificate(config: &Config<Rc<CertificatePair>>) -> Result<()> {
+    timing::phase("upload", update_secret(config)).await
+}
+
+async fn update_secret(config: &Config<Rc<CertificatePair>>) -> Result<()> {
+    let api = Api::new(config)?;
+    let (namespace, name) = (&config.namespace, &config.secret_name);
+
+    let annotations = annotations(&config.certificate, &config.annotations, OffsetDateTime::now_utc())?;
+    let data = secret_data(&config.certificate)?;
+
+    info!("reading Secret {namespace}/{name} from {}", config.cluster.server);
+    match api.get(name).await? {
+        Some(existing) => {
+            // a Secret's type can't be changed, and other types aren't used for TLS
+            let secret_type = existing["type"].as_str().unwrap_or_default();
+            if secret_type != "kubernetes.io/tls" {
+                bail!("Secret {namespace}/{name} has type \"{secret_type}\", not \"kubernetes.io/tls\"");
+            }
+
+            info!("updating Secret {namespace}/{name}");
+            let response = api.request(Method::PATCH, Some(name))
+                .header("Content-Type", "application/merge-patch+json")
+                .body(json!({ "metadata": { "annotations": annotations }, "data": data }).to_string())
+                .send().await.context("failed to send request")?;
+            api.check(response, "update").await?;
+        },
+        None => {
+            info!("creating Secret {namespace}/{name}");
+            let response = api.request(Method::POST, None)
+                .json(&json!({
+                    "apiVersion": "v1",
+                    "kind": "Secret",
+                    "type": "kubernetes.io/tls",
+                    "metadata": { "name": name, "namespace": namespace, "annotations": annotations },
+                    "data": data,
+                }))
+                .send().await.context("failed to send request")?;
+            api.check(response, "create").await?;
+        },
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_kubeconfig() {
+        let kubeconfig = |json: Value| serde_json::from_value::<Kubeconfig>(json).unwrap();
+
+        let config = json!({
+            "clusters": [{ "name": "prod", "cluster": { "server": "https://k8s.example.net:6443", "insecure-skip-tls-verify": true } }],
+            "contexts": [
+                { "name": "admin@prod", "context": { "cluster": "prod", "user": "admin", "namespace": "web" } },
+                { "name": "anonymous", "context": { "cluster": "prod" } },
+                { "name": "sso", "context": { "cluster": "prod", "user": "sso" } },
+            ],
+            "users": [
+                { "name": "admin", "user": { "token": "secret" } },
+                { "name": "sso", "user": { "exec": { "command": "kubelogin" } } },
+            ],
+            "current-context": "admin@prod",
+        });
+
+        let path = Path::new("/etc/rci/kubeconfig.json");
+
+        let cluster = Cluster::from_parsed_kubeconfig(kubeconfig(config.clone()), path, None).unwrap();
+        assert_eq!(cluster.server.as_str(), "https://k8s.example.net:6443/");
+        assert_eq!(cluster.namespace.as_deref(), Some("web"));
+        assert!(cluster.insecure);
+        assert!(matches!(cluster.credentials, Credentials::Token(ref token) if token == "secret"));
+        assert_eq!(cluster.source, r#"/etc/rci/kubeconfig.json (context "admin@prod")"#);
+
+        let cluster = Cluster::from_parsed_kubeconfig(kubeconfig(config.clone()), path, Some("anonymous")).unwrap();
+        assert!(matches!(cluster.credentials, Credentials::None));
+        assert_eq!(cluster.namespace, None);
+
+        let e = Cluster::from_parsed_kubeconfig(kubeconfig(config.clone()), path, Some("sso")).unwrap_err();
+        assert!(e.to_string().contains("credential plugin"), "{e}");
+
+        let e = Cluster::from_parsed_kubeconfig(kubeconfig(config), path, Some("staging")).unwrap_err();
+        assert_eq!(e.to_string(), r#"the kubeconfig has no context "staging""#);
+    }
+
+    #[test]
+    fn test_annotations() {
+        let pair = crate::test_util::certificate_pair(&["web.example.net"]);
+        let configured = BTreeMap::from([("example.net/owner".to_string(), "ops".to_string())]);
+
+        let annotations = annotations(&pair, &configured, OffsetDateTime::UNIX_EPOCH).unwrap();
+        assert_eq!(annotations.keys().collect::<Vec<_>>(), ["certinstaller/deployed-at", "certinstaller/not-after", "certinstaller/sha256-fingerprint", "example.net/owner"]);
+        assert_eq!(annotations["certinstaller/deployed-at"], "1970-01-01T00:00:00Z");
+        assert_eq!(annotations["certinstaller/sha256-fingerprint"], pair.summary().unwrap().sha256_fingerprint);
+
+        let data = secret_data(&pair).unwrap();
+        assert_eq!(decode_block(data["tls.key"].as_str().unwrap()).unwrap(), pair.private_key_pem_string().unwrap().as_bytes());
+    }
+}
@@ -1,10 +1,11 @@
-use std::{collections::HashMap, path::{Path, PathBuf}, rc::Rc, sync::Arc};
+use std::{collections::HashMap, net::IpAddr, rc::Rc, time::{Duration, Instant}};
 
-use reqwest::{cookie::Jar, header::{HeaderMap, HeaderValue}, multipart::{Form, Part}, Client, Url};
-use serde::Deserialize;
-use anyhow::{Context, Result};
+use reqwest::{header::{HeaderValue, CONNECTION}, multipart::{Form, Part}, Client, Method, RequestBuilder, Response, StatusCode, Url};
+use serde::{de, Deserialize, Serialize};
+use anyhow::{anyhow, bail, Context, Result};
+use tracing::{debug, info, warn};
 
-use crate::config::{CertificatePair, CertificateRef, CredentialPathBuf};
+use crate::{config::{CertificatePair, CertificateRef, CredentialPathBuf, GlobalCertificates, PemLineEnding, SecretSource}, timing};
 
 //use crate::config::CertificateConfig;
 
@@ -14,14 +15,203 @@ pub struct RawConfig {
 
     pub url: Url,
 
-    pub password_file: Option<CredentialPathBuf>
+    pub password_file: Option<CredentialPathBuf>,
+
+    /// environment variable containing the password, instead of `password_file`
+    pub password_env: Option<String>,
+
+    #[serde(default)]
+    pub http: crate::http::Config,
+
+    /// Connect to this IP address instead of resolving the URL's hostname
+    pub address: Option<IpAddr>,
+
+    /// `lf` or `crlf`, for firmware that only accepts one kind in PEM. Defaults to the platform's line ending
+    pub pem_line_ending: Option<PemLineEnding>,
+
+    /// What to restart after uploading, for firmware that doesn't serve the new certificate until then
+    #[serde(default)]
+    pub restart: Restart,
+
+    /// How long to wait for the BMC to return after restarting, in seconds
+    #[serde(default = "RawConfig::default_restart_timeout")]
+    pub restart_timeout: u64,
+
+    /// The name of the upload form's certificate field. Firmware versions differ: `new_certificate` is most
+    /// common, and others use `certificate` or `cert_file`
+    #[serde(default = "RawConfig::default_certificate_field")]
+    pub certificate_field: String,
+
+    /// The name of the upload form's private key field: `new_private_key`, `private_key` or `key_file`,
+    /// to go with `certificate_field`
+    #[serde(default = "RawConfig::default_private_key_field")]
+    pub private_key_field: String,
+
+    /// Where the certificate upload carries the CSRF token, for firmware that wants it in the form
+    #[serde(default)]
+    pub csrf_in: CsrfIn,
+}
+
+impl RawConfig {
+    fn default_restart_timeout() -> u64 {
+        300
+    }
+
+    fn default_certificate_field() -> String {
+        "new_certificate".to_string()
+    }
+
+    fn default_private_key_field() -> String {
+        "new_private_key".to_string()
+    }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Restart {
+    #[default]
+    None,
+
+    /// Only the web server, which is enough for most firmware
+    Webserver,
+
+    /// A full BMC reset. The host isn't affected
+    Bmc,
+}
+
+impl Restart {
+    /// The API endpoint (as used by the web interface's maintenance pages) that performs the restart
+    fn path(self) -> Option<&'static str> {
+        match self {
+            Restart::None => None,
+            Restart::Webserver => Some("maintenance/restart_http"),
+            Restart::Bmc => Some("maintenance/reset"),
+        }
+    }
+}
+
+impl std::fmt::Display for Restart {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Restart::None => "nothing",
+            Restart::Webserver => "web server",
+            Restart::Bmc => "BMC",
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CsrfIn {
+    /// The `X-CSRFTOKEN` header
+    #[default]
+    Header,
+
+    /// A `CSRFToken` field of the multipart body
+    Body,
+
+    Both,
+}
+
+impl CsrfIn {
+    fn header(self) -> bool {
+        matches!(self, CsrfIn::Header | CsrfIn::Both)
+    }
+
+    fn body(self) -> bool {
+        matches!(self, CsrfIn::Body | CsrfIn::Both)
+    }
+}
+
+/// How long a restart takes to begin, so the BMC isn't mistaken for being back before it went away
+const RESTART_GRACE: Duration = Duration::from_secs(5);
+
+const RESTART_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// This remote's section of the example config
+pub(crate) fn sample() -> String {
+    format!(r#"[megarac-bmc.bmc]
+certificate = "default"
+url = "https://admin@bmc.example.net"
+password_file = "bmc-password"
+# password_env = "BMC_PASSWORD"              # instead of password_file
+# http.client_identity = {{ certificate_path = "client.pem", private_key_path = "client.key" }}
+# http.user_agent = "Mozilla/5.0"           # instead of the top-level `user_agent`
+# http.max_response_bytes = {max_response_bytes}         # refuse larger responses
+# http.min_tls_version = "1.0"               # for old firmware; also http.max_tls_version, and http.sni = false
+# pem_line_ending = "lf"                     # or "crlf". Defaults to the platform's line ending
+# restart = {}                           # or "webserver" or "bmc", for firmware that doesn't serve the new certificate until then
+# restart_timeout = {}
+# certificate_field = {}      # or "certificate" or "cert_file", the upload form's field names on other firmware
+# private_key_field = {}      # or "private_key" or "key_file"
+# csrf_in = {}                         # or "body" or "both", where the upload carries the CSRF token
+"#, crate::sample::value(&Restart::default()), RawConfig::default_restart_timeout(),
+    crate::sample::value(&RawConfig::default_certificate_field()), crate::sample::value(&RawConfig::default_private_key_field()),
+    crate::sample::value(&CsrfIn::default()), max_response_bytes = crate::http::DEFAULT_MAX_RESPONSE_BYTES)
+}
+
+#[derive(Clone, Debug, Serialize)]
 pub struct Config<CertT> {
     pub certificate: CertT,
 
-    pub url: Url
+    #[serde(serialize_with = "crate::config::serialize_url_redacted")]
+    pub url: Url,
+
+    /// used when the URL doesn't contain a password
+    #[serde(serialize_with = "crate::config::serialize_redacted", skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+
+    #[serde(skip_serializing_if = "crate::http::Config::is_empty")]
+    pub http: crate::http::Config,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<IpAddr>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pem_line_ending: Option<PemLineEnding>,
+
+    pub restart: Restart,
+
+    pub restart_timeout: u64,
+
+    pub certificate_field: String,
+    pub private_key_field: String,
+
+    pub csrf_in: CsrfIn,
+}
+
+impl Config<CertificateRef> {
+    pub fn try_resolve_certificate(self, global_certs: &GlobalCertificates) -> Result<Config<Rc<CertificatePair>>> {
+        Ok(Config {
+            certificate: self.certificate.try_resolve(global_certs).map_err(|e| anyhow!("{e} for key `certificate`"))?,
+            url: self.url,
+            password: self.password,
+            http: self.http,
+            address: self.address,
+            pem_line_ending: self.pem_line_ending,
+            restart: self.restart,
+            restart_timeout: self.restart_timeout,
+            certificate_field: self.certificate_field,
+            private_key_field: self.private_key_field,
+            csrf_in: self.csrf_in,
+        })
+    }
+}
+
+impl<CertT> Config<CertT> {
+    /// the BMC web interface
+    pub fn default_verify_url(&self) -> Url {
+        let mut url = self.url.join("/").expect("valid verify URL");
+        let _ = url.set_username("");
+        let _ = url.set_password(None);
+
+        url
+    }
+
+    /// Where the remote is managed through
+    pub fn endpoint(&self) -> Option<crate::probe::Endpoint> {
+        crate::probe::Endpoint::of_url(&self.url, self.address)
+    }
 }
 
 impl <'de> Deserialize<'de> for Config<CertificateRef> {
@@ -31,15 +221,40 @@ impl <'de> Deserialize<'de> for Config<CertificateRef> {
     {
         let raw = RawConfig::deserialize(deserializer)?;
 
-        todo!()
+        match raw.url.scheme() {
+            "http" | "https" => (),
+            other => return Err(de::Error::custom(format!("unknown protocol '{other}'"))),
+        }
+
+        let password = SecretSource::from_keys(raw.password_file, raw.password_env, "password_file", "password_env")
+            .map_err(de::Error::custom)?
+            .map(|source| {
+                source.read_to_string()
+                    .map(|p| p.trim_end_matches(['\r', '\n']).to_string())
+                    .map_err(|e| de::Error::custom(format!("failed to read password {source} ({e:#})")))
+            })
+            .transpose()?;
+
+        Ok(Config {
+            certificate: raw.certificate,
+            url: raw.url,
+            password,
+            http: raw.http,
+            address: raw.address,
+            pem_line_ending: raw.pem_line_ending,
+            restart: raw.restart,
+            restart_timeout: raw.restart_timeout,
+            certificate_field: raw.certificate_field,
+            private_key_field: raw.private_key_field,
+            csrf_in: raw.csrf_in,
+        })
     }
 }
 
 
-
-
 #[derive(Deserialize)]
 struct NewSessionResponse {
+    #[allow(dead_code)]
     user_id: u32,
 
     #[serde(rename = "CSRFToken")]
@@ -47,6 +262,7 @@ struct NewSessionResponse {
 }
 
 #[derive(Deserialize, Debug)]
+#[allow(dead_code)]
 struct CertificateInfoResponse {
     id: u32,
     certificate_available: u32,
@@ -77,41 +293,152 @@ struct CertificateInfoResponse {
 /// Note that the HTTPS connections are made to ignore invalid certificates
 /// (`danger_accept_invalid_certs(true)`) to work around:
 /// 1. previously generated self-signed certificates being installed but not trusted by this tool
-///     (e.g., not in system trust store)
+///    (e.g., not in system trust store)
 /// 2. a bug in the BMC firmware where it strips a fullchain.pem and only stores the first certificate in the chain,
-///     which causes even valid certificates to be seen as invalid by native-tls and other tools. For example,
-///     on my test X570D4U board with a Lets Encrypt cert, openssl s_client -connect returns
-///         verify error:num=20:unable to get local issuer certificate
-///         verify error:num=21:unable to verify the first certificate
+///    which causes even valid certificates to be seen as invalid by native-tls and other tools. For example,
+///    on my test X570D4U board with a Lets Encrypt cert, openssl s_client -connect returns
+///    ```text
+///    verify error:num=20:unable to get local issuer certificate
+///    verify error:num=21:unable to verify the first certificate
+///    ```
 pub async fn update_certificate(config: &Config<Rc<CertificatePair>>) -> Result<()> {
-    todo!();
+    let api = Api::new(config)?;
 
-    /*let base_url = config.url.join("/api/").expect("valid base_url");
-    let cookie_jar = Arc::new(Jar::default());
+    // STAGE 1: login to create a session cookie and get CSRF token
+    let mut session = timing::phase("auth", api.login(config)).await?;
 
-    let api_url = |path: &str| -> Url {
-        base_url.join(path).expect("valid API url")
-    };
+    // STAGE 2: upload the new certificate and key
+    let mut response = timing::phase("upload", session.upload(config)).await?;
 
-    let build_client = |csrf_token: Option<String>| -> Result<Client> {
-        let mut builder = Client::builder()
-            .cookie_provider(cookie_jar.clone())
-            .danger_accept_invalid_certs(true); // see comment above
+    // sessions are short-lived, and slower BMCs may expire one before the upload arrives.
+    // Log in again, but only once: a second rejection is something else
+    if let status @ (StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) = response.status() {
+        warn!("certificate upload was rejected ({status}), the session may have expired; logging in again");
 
-        if let Some(csrf_token) = csrf_token {
-            let mut headers = HeaderMap::new();
-            headers.append("X-CSRFTOKEN", csrf_token.parse().unwrap());
+        session = timing::phase("auth", api.login(config)).await?;
+        response = timing::phase("upload", session.upload(config)).await?
+            .error_for_status().context("certificate upload was still rejected after logging in again")?;
+    }
 
-            builder = builder.default_headers(headers);
-        }
+    let response = crate::http::text(response.error_for_status()?, config.http.max_response_bytes()).await
+        .context("failed to read response")?;
 
-        builder.build().context("failed to build a Client")
-    };
+    debug!("upload response: {response}");
 
-    // STAGE 1: login to create a session cookie and get CSRF token
-    let client = build_client(None)?;
+    // STAGE 3: restart, if the firmware needs it to serve the new certificate
+    if let Some(path) = config.restart.path() {
+        info!("restarting the {}", config.restart);
+        session.request(Method::POST, path)
+            .send().await.context("failed to send request")?
+            .error_for_status().with_context(|| format!("failed to restart the {}", config.restart))?;
+
+        timing::phase("restart", api.wait_for_restart(Duration::from_secs(config.restart_timeout))).await?;
+    }
+
+    // let response: CertificateInfoResponse = client.get(api_url("settings/ssl/certificate-info"))
+    //     .send().expect("send request")
+    //     .json().expect("valid JSON response");
+
+
+
+
+    // let response = client.delete(api_url("settings/ssl/certificate")).send()
+    //     .expect("send request")
+    //     .text().expect("response");
+
+    Ok(())
+}
+
+/// What the BMC reports of its current certificate, whose fields vary between firmware versions
+pub async fn certificate_info<CertT>(config: &Config<CertT>) -> Result<serde_json::Map<String, serde_json::Value>> {
+    let api = Api::new(config)?;
+
+    let session = api.login(config).await?;
+
+    let response = session.request(Method::GET, "settings/ssl/certificate-info")
+        .send().await.context("failed to send request")?
+        .error_for_status().context("failed to read the certificate info")?;
+    let info = crate::http::json(response, config.http.max_response_bytes()).await
+        .context("failed to decode JSON response")?;
+
+    session.logout().await?;
+
+    Ok(info)
+}
+
+/// Login to the BMC and then immediately logout, without changing anything
+pub async fn test_connection(config: &Config<Rc<CertificatePair>>) -> Result<()> {
+    let api = Api::new(config)?;
+
+    let session = api.login(config).await?;
+
+    session.logout().await
+}
+
+/// The BMC API. A single client (and so cookie store and connection pool) is used for
+/// every stage, as the BMC may invalidate a session that is continued on a new connection.
+struct Api {
+    base_url: Url,
+    client: Client,
+}
+
+/// A logged in session, whose requests carry its CSRF token
+struct Session<'a> {
+    api: &'a Api,
+    csrf_token: HeaderValue,
+}
+
+impl Api {
+    fn new<CertT>(config: &Config<CertT>) -> Result<Self> {
+        let mut base_url = config.url.join("/api/").expect("valid base_url");
+
+        // credentials are sent via the login form, never in the URL
+        let _ = base_url.set_username("");
+        let _ = base_url.set_password(None);
+
+        let client = crate::http::resolve_to(config.http.client_builder(), &base_url, config.address)
+            .cookie_store(true)
+            .danger_accept_invalid_certs(true) // see comment on update_certificate
+            .build().context("failed to build a Client")?;
+
+        Ok(Api { base_url, client })
+    }
+
+    fn url(&self, path: &str) -> Url {
+        self.base_url.join(path).expect("valid API url")
+    }
 
-    let login_response = {
+    /// Wait for the web interface to respond again, within `timeout`
+    async fn wait_for_restart(&self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let url = self.base_url.join("/").expect("valid web interface URL");
+
+        tokio::time::sleep(RESTART_GRACE).await;
+
+        loop {
+            // any response at all means the web server is back. Polls don't keep their connection, so each
+            // resolves the hostname again, in case the BMC came back on a different address
+            let poll = self.client.get(url.clone())
+                .header(CONNECTION, "close")
+                .timeout(RESTART_POLL_INTERVAL);
+
+            match poll.send().await {
+                Ok(_) => {
+                    info!("BMC is back after restarting");
+                    return Ok(());
+                },
+                Err(e) => debug!("BMC isn't back yet: {e}"),
+            }
+
+            if Instant::now() + RESTART_POLL_INTERVAL > deadline {
+                bail!("the BMC didn't come back within {}s of restarting", timeout.as_secs());
+            }
+
+            tokio::time::sleep(RESTART_POLL_INTERVAL).await;
+        }
+    }
+
+    async fn login<CertT>(&self, config: &Config<CertT>) -> Result<Session<'_>> {
         let mut creds = HashMap::new();
         creds.insert("username", config.url.username());
         creds.insert("password", config.url.password()
@@ -119,40 +446,54 @@ pub async fn update_certificate(config: &Config<Rc<CertificatePair>>) -> Result<
                                     .unwrap_or_default()
                                 );
 
-        let response: NewSessionResponse = client.post(api_url("session"))
+        info!("logging in to {}", self.base_url);
+        let response = self.client.post(self.url("session"))
             .form(&creds)
             .send().await.context("failed to send request")?
-            .error_for_status()?
-            .json().await.context("failed to decode JSON response")?;
-
-        response
-    };
-
-    let client = build_client(Some(login_response.csrf_token))?;
-
-    let certificate_form = Form::new();
-        //.part("new_certificate", Part::file(config.certificate.as_ref().expect("certificate"))?)
-        //.part("new_private_key", Part::file(config.private_key.as_ref().expect("private key"))?);
-
-    let response = client.post(api_url("settings/ssl/certificate"))
-        .multipart(certificate_form)
-        .send().await.context("failed to send request")?
-        .error_for_status()?
-        .text().await.context("failed to decode JSON response");
+            .error_for_status().context("login failed")?;
+        let response: NewSessionResponse = crate::http::json(response, config.http.max_response_bytes()).await
+            .context("failed to decode JSON response")?;
+
+        Ok(Session {
+            api: self,
+            csrf_token: response.csrf_token.parse().context("invalid CSRF token")?,
+        })
+    }
+}
 
-    // let response: CertificateInfoResponse = client.get(api_url("settings/ssl/certificate-info"))
-    //     .send().expect("send request")
-    //     .json().expect("valid JSON response");
+impl Session<'_> {
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.api.client.request(method, self.api.url(path))
+            .header("X-CSRFTOKEN", self.csrf_token.clone())
+    }
 
+    /// Upload the certificate and key, returning the response whatever its status
+    async fn upload(&self, config: &Config<Rc<CertificatePair>>) -> Result<Response> {
+        let mut certificate_form = Form::new();
+        if config.csrf_in.body() {
+            certificate_form = certificate_form.text("CSRFToken", self.csrf_token.to_str().context("invalid CSRF token")?.to_string());
+        }
 
+        let certificate_form = certificate_form
+            .part(config.certificate_field.clone(), Part::text(config.certificate.fullchain_certificate_pem_string_with(config.pem_line_ending)?).file_name("fullchain.pem"))
+            .part(config.private_key_field.clone(), Part::text(config.certificate.private_key_pem_string_with(config.pem_line_ending)?).file_name("privkey.pem"));
 
+        let request = match config.csrf_in.header() {
+            true => self.request(Method::POST, "settings/ssl/certificate"),
+            false => self.api.client.post(self.api.url("settings/ssl/certificate")),
+        };
 
-    // let response = client.delete(api_url("settings/ssl/certificate")).send()
-    //     .expect("send request")
-    //     .text().expect("response");
-
+        info!("uploading certificate");
+        request
+            .multipart(certificate_form)
+            .send().await.context("failed to send request")
+    }
 
-    println!("{response:?}");
+    async fn logout(self) -> Result<()> {
+        self.request(Method::DELETE, "session")
+            .send().await.context("failed to send request")?
+            .error_for_status().context("logout failed")?;
 
-    Ok(())*/
-}
\ No newline at end of file
+        Ok(())
+    }
+}
@@ -1,7 +1,19 @@
 
 pub mod brother;
+pub mod caddy;
 pub mod cloudkey;
+pub mod file_pem;
+pub mod file_pkcs12;
+pub mod haproxy;
+pub mod homeassistant;
 pub mod intel_amt;
+pub mod kubernetes;
 pub mod megarac;
+pub mod nginx;
 pub mod onvif;
-pub mod pfsense;
\ No newline at end of file
+pub mod openwrt;
+pub mod pfsense;
+pub mod redfish;
+pub mod udm;
+pub mod unifi_controller;
+pub mod winrm;
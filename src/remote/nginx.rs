@@ -0,0 +1,271 @@
+//! nginx
+//!
+//! The certificate chain and private key are uploaded over SFTP and renamed into place together,
+//! then the configuration is checked with `nginx -t` before nginx is reloaded.
+//! If the check or the reload fails the previous files are restored, so a broken
+//! configuration (whether caused by the new files or not) never takes the site down.
+
+use std::{net::IpAddr, rc::Rc};
+
+use anyhow::{anyhow, Result};
+use serde::{de, Deserialize, Serialize};
+use tracing::info;
+use url::Url;
+
+use crate::{config::{CertificatePair, CertificateRef, GlobalCertificates, PemLineEnding}, deploy::{self, Transfer}, error::RciError, sftp::FileAttributes, ssh::{exec, run_hooks, shell_quote, ssh_connect, ConnectOptions, Hook}, timing};
+
+#[derive(Deserialize, Debug)]
+struct RawConfig {
+    pub certificate: CertificateRef,
+
+    pub url: Url,
+
+    #[serde(rename = "ssh")]
+    pub ssh_config: crate::ssh::Config,
+
+    /// Connect to this IP address instead of resolving the URL's hostname
+    pub address: Option<IpAddr>,
+
+    /// `lf` or `crlf`, for firmware that only accepts one kind in PEM. Defaults to the platform's line ending
+    pub pem_line_ending: Option<PemLineEnding>,
+
+    /// Where to write the certificate chain (`ssl_certificate`)
+    pub certificate_chain_path: String,
+
+    /// Where to write the private key (`ssl_certificate_key`)
+    pub private_key_path: String,
+
+    /// Defaults to the mode of the file being replaced, or 0644 for a new file
+    pub certificate_chain_mode: Option<u32>,
+
+    /// Can't allow access by other users. Defaults to the mode of the file being replaced, or 0600 for a new file
+    pub private_key_mode: Option<u32>,
+
+    /// User (name or ID) to own both files. Changing it generally requires logging in as root.
+    /// Without `owner` or `group`, each file keeps the owner and group of the file it replaces
+    pub owner: Option<String>,
+
+    /// Group (name or ID) for both files, e.g. so a worker running as another user can read the key
+    pub group: Option<String>,
+
+    #[serde(default = "RawConfig::default_nginx")]
+    pub nginx: String,
+
+    /// The configuration file to check, if not nginx's compiled-in default
+    pub config_path: Option<String>,
+
+    #[serde(default = "RawConfig::default_reload_command")]
+    pub reload_command: String,
+}
+
+impl RawConfig {
+    fn default_nginx() -> String {
+        "nginx".to_string()
+    }
+
+    fn default_reload_command() -> String {
+        "systemctl reload nginx".to_string()
+    }
+
+    fn default_certificate_chain_mode() -> u32 {
+        0o644
+    }
+
+    fn default_private_key_mode() -> u32 {
+        0o600
+    }
+}
+
+/// This remote's section of the example config
+pub(crate) fn sample() -> String {
+    format!(r#"[nginx.web]
+certificate = "default"
+{}# `ssl_certificate` and `ssl_certificate_key` in the nginx config
+certificate_chain_path = "/etc/nginx/ssl/example.net.crt"
+private_key_path = "/etc/nginx/ssl/example.net.key"
+# certificate_chain_mode = 0o{:o}               # defaults to the replaced file's, as do the private key's mode and the owner
+# private_key_mode = 0o{:o}
+# owner = "root"
+# group = "www-data"
+# pem_line_ending = "lf"                     # or "crlf". Defaults to the platform's line ending
+# nginx = {}
+# config_path = "/etc/nginx/nginx.conf"      # defaults to nginx's compiled-in path
+# reload_command = {}
+"#,
+        crate::sample::ssh("ssh://root@web.example.net"),
+        RawConfig::default_certificate_chain_mode(),
+        RawConfig::default_private_key_mode(),
+        crate::sample::value(&RawConfig::default_nginx()),
+        crate::sample::value(&RawConfig::default_reload_command()))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Config<CertT> {
+    pub certificate: CertT,
+
+    #[serde(rename = "ssh")]
+    ssh_options: ConnectOptions,
+
+    certificate_chain_path: String,
+    private_key_path: String,
+    #[serde(serialize_with = "crate::deploy::serialize_mode", skip_serializing_if = "Option::is_none")]
+    certificate_chain_mode: Option<u32>,
+    #[serde(serialize_with = "crate::deploy::serialize_mode", skip_serializing_if = "Option::is_none")]
+    private_key_mode: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    owner: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group: Option<String>,
+    nginx: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    config_path: Option<String>,
+    reload_command: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pem_line_ending: Option<PemLineEnding>,
+}
+
+impl Config<CertificateRef> {
+    pub fn try_resolve_certificate(self, global_certs: &GlobalCertificates) -> Result<Config<Rc<CertificatePair>>> {
+        Ok(Config {
+            certificate: self.certificate.try_resolve(global_certs).map_err(|e| anyhow!("{e} for key `certificate`"))?,
+            ssh_options: self.ssh_options,
+            certificate_chain_path: self.certificate_chain_path,
+            private_key_path: self.private_key_path,
+            certificate_chain_mode: self.certificate_chain_mode,
+            private_key_mode: self.private_key_mode,
+            owner: self.owner,
+            group: self.group,
+            nginx: self.nginx,
+            config_path: self.config_path,
+            reload_command: self.reload_command,
+            pem_line_ending: self.pem_line_ending,
+        })
+    }
+}
+
+impl<CertT> Config<CertT> {
+    /// nginx on the host it was deployed to
+    pub fn default_verify_url(&self) -> Url {
+        crate::verify::https_url(self.ssh_options.host(), None)
+    }
+
+    /// Where the remote is managed through
+    pub fn endpoint(&self) -> Option<crate::probe::Endpoint> {
+        self.ssh_options.endpoint()
+    }
+}
+
+/// `nginx -t`, against `config_path` if set
+fn check_command(nginx: &str, config_path: Option<&str>) -> String {
+    let mut command = format!("{} -t", shell_quote(nginx));
+
+    if let Some(path) = config_path {
+        command += &format!(" -c {}", shell_quote(path));
+    }
+
+    command
+}
+
+impl <'de> Deserialize<'de> for Config<CertificateRef> {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>
+    {
+        let raw = RawConfig::deserialize(deserializer)?;
+
+        let ssh_options = match raw.url.scheme() {
+            "ssh" => ConnectOptions::new(raw.url, &raw.ssh_config).map_err(de::Error::custom)?.with_address(raw.address),
+            other => return Err(de::Error::custom(format!("unknown protocol '{other}'"))),
+        };
+
+        if let Some(mode) = raw.certificate_chain_mode {
+            deploy::check_mode("certificate_chain_mode", mode, false).map_err(de::Error::custom)?;
+        }
+        if let Some(mode) = raw.private_key_mode {
+            deploy::check_mode("private_key_mode", mode, true).map_err(de::Error::custom)?;
+        }
+
+        Ok(Config {
+            certificate: raw.certificate,
+            ssh_options,
+            certificate_chain_path: raw.certificate_chain_path,
+            private_key_path: raw.private_key_path,
+            certificate_chain_mode: raw.certificate_chain_mode,
+            private_key_mode: raw.private_key_mode,
+            owner: raw.owner,
+            group: raw.group,
+            nginx: raw.nginx,
+            config_path: raw.config_path,
+            reload_command: raw.reload_command,
+            pem_line_ending: raw.pem_line_ending,
+        })
+    }
+}
+
+pub async fn test_connection(config: &Config<Rc<CertificatePair>>) -> Result<()> {
+    crate::ssh::test_connection(&config.ssh_options).await
+}
+
+pub async fn update_certificate(config: &Config<Rc<CertificatePair>>) -> Result<()> {
+    let chain = config.certificate.fullchain_certificate_pem_string_with(config.pem_line_ending)?;
+    let key = config.certificate.private_key_pem_string_with(config.pem_line_ending)?;
+
+    let handle = ssh_connect(&config.ssh_options).await?;
+    run_hooks(&handle, &config.ssh_options, Hook::Pre).await?;
+
+    let owner = deploy::chown_spec(config.owner.as_deref(), config.group.as_deref());
+
+    let keep_owner = owner.is_none();
+    let preserve = |mode: Option<u32>| deploy::Preserve { mode: mode.is_none(), owner: keep_owner };
+
+    let staged = deploy::stage(&handle, &[
+        deploy::File {
+            path: &config.certificate_chain_path,
+            contents: chain.as_bytes(),
+            attrs: FileAttributes::mode(config.certificate_chain_mode.unwrap_or_else(RawConfig::default_certificate_chain_mode)),
+            owner: owner.clone(),
+            preserve: preserve(config.certificate_chain_mode),
+        },
+        deploy::File {
+            path: &config.private_key_path,
+            contents: key.as_bytes(),
+            attrs: FileAttributes::mode(config.private_key_mode.unwrap_or_else(RawConfig::default_private_key_mode)),
+            owner,
+            preserve: preserve(config.private_key_mode),
+        },
+    ], config.ssh_options.transfer(Transfer::Sftp)).await?;
+
+    staged.commit().await?;
+
+    info!("checking nginx configuration");
+    let check = timing::phase("check", exec(&handle, &check_command(&config.nginx, config.config_path.as_deref()), &[])).await?;
+    if !check.success() {
+        staged.rollback().await;
+
+        return Err(RciError::RemoteRejected(anyhow!("nginx configuration test failed, not reloading: {}", check.output_lossy())).into());
+    }
+
+    info!("reloading nginx");
+    let reload = timing::phase("reload", exec(&handle, &config.reload_command, &[])).await
+        .and_then(|output| output.check("reload command"));
+    if let Err(e) = reload {
+        staged.rollback().await;
+
+        return Err(e);
+    }
+
+    staged.finish().await?;
+
+    run_hooks(&handle, &config.ssh_options, Hook::Post).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check_command() {
+        assert_eq!(check_command("nginx", None), "'nginx' -t");
+        assert_eq!(check_command("/usr/sbin/nginx", Some("/etc/nginx/sites.conf")), "'/usr/sbin/nginx' -t -c '/etc/nginx/sites.conf'");
+    }
+}
@@ -0,0 +1,32 @@
+<?php
+// lists the certificates in the config, one JSON object per line, without changing anything
+
+require_once("certs.inc");
+require_once("config.inc");
+
+$frontends = $config['installedpackages']['haproxy']['ha_backends']['item'];
+if (!is_array($frontends)) {
+    $frontends = array();
+}
+
+foreach ((is_array($config['cert']) ? $config['cert'] : array()) as $cert) {
+    $crt = openssl_x509_parse(base64_decode($cert['crt']));
+
+    $services = array();
+    if ($config['system']['webgui']['ssl-certref'] === $cert['refid']) {
+        $services[] = "webgui";
+    }
+    foreach ($frontends as $frontend) {
+        if ($frontend['ssloffloadcert'] === $cert['refid']) {
+            $services[] = "haproxy:" . $frontend['name'];
+        }
+    }
+
+    echo json_encode(array(
+        'refid' => $cert['refid'],
+        'descr' => $cert['descr'],
+        'not_after' => $crt === false ? null : $crt['validTo_time_t'],
+        'services' => $services,
+    )) . "\n";
+}
+?>
@@ -1,5 +1,12 @@
 <?php
-$refid = "@@REFID@@";
+// the refid and/or the description (base64 encoded), either may be empty.
+// with a refid, the description is only used for a certificate created with that refid
+$refid = base64_decode("@@REFID@@");
+$descr = base64_decode("@@DESCR@@");
+
+// services to switch over to the certificate, one per line: "webgui" or "haproxy:<frontend>"
+$service_names = base64_decode("@@SERVICES@@");
+$service_names = $service_names === "" ? array() : explode("\n", $service_names);
 
 $cert_str = <<<'CERT'
 @@CERTIFICATE@@
@@ -14,24 +21,101 @@ require_once("pfsense-utils.inc");
 require_once("config.inc");
 require_once("globals.inc");
 
-// find certificate
-foreach ($config['cert'] as &$cert) {
-    if ($cert['refid'] === $refid) {
-        break;
+if (!is_array($config['cert'])) {
+    $config['cert'] = array();
+}
+
+// set when the certificate isn't in the config yet, e.g., on a freshly installed firewall
+$created = false;
+
+if ($refid !== "") {
+    // find certificate
+    foreach ($config['cert'] as &$cert) {
+        if ($cert['refid'] === $refid) {
+            break;
+        }
+
+        unset($cert);
     }
 
-    unset($cert);
+    if (!isset($cert)) {
+        echo "no certificate with refid $refid.\n";
+        $config['cert'][] = array('refid' => $refid, 'descr' => $descr !== "" ? $descr : $refid);
+        $cert = &$config['cert'][count($config['cert']) - 1];
+        $created = true;
+    }
+} else {
+    $matches = array();
+    foreach ($config['cert'] as $i => $c) {
+        if ($c['descr'] === $descr) {
+            $matches[] = $i;
+        }
+    }
+
+    if (count($matches) > 1) {
+        echo count($matches) . " certificates have the description \"$descr\", set refid instead.\n";
+        die(1);
+    }
+
+    if (count($matches) === 1) {
+        $cert = &$config['cert'][$matches[0]];
+    } else {
+        echo "no certificate with description \"$descr\".\n";
+        $config['cert'][] = array('refid' => uniqid(), 'descr' => $descr);
+        $cert = &$config['cert'][count($config['cert']) - 1];
+        $created = true;
+    }
+
+    $refid = $cert['refid'];
 }
 
-if (!isset($cert)) {
-    echo "couldn't find certificate with refid $refid.\n";
-    die(1);
+// find any HAProxy frontends before changing anything, so an unknown name leaves the config untouched
+$frontends = array();
+foreach ($service_names as $service) {
+    if ($service === "webgui") {
+        continue;
+    }
+
+    $name = substr($service, strlen("haproxy:"));
+    if (!is_array($config['installedpackages']['haproxy']['ha_backends']['item'])) {
+        echo "couldn't find HAProxy frontend \"$name\", is the HAProxy package installed?\n";
+        die(1);
+    }
+
+    $found = false;
+    foreach ($config['installedpackages']['haproxy']['ha_backends']['item'] as $i => $frontend) {
+        if ($frontend['name'] === $name) {
+            $frontends[$name] = $i;
+            $found = true;
+            break;
+        }
+    }
+
+    if (!$found) {
+        echo "couldn't find HAProxy frontend \"$name\".\n";
+        die(1);
+    }
 }
 
-echo "updating certificate \"{$cert['descr']}\" ($refid).\n";
+// "creating certificate" tells certinstaller the certificate is new
+echo ($created ? "creating" : "updating") . " certificate \"{$cert['descr']}\" ($refid).\n";
 cert_import($cert, $cert_str, $key_str);
-write_config("rci: remote update of certificate \"{$cert['descr']}\" ($refid)");
 
+foreach ($service_names as $service) {
+    if ($service === "webgui") {
+        echo "using certificate for the webConfigurator.\n";
+        $config['system']['webgui']['ssl-certref'] = $refid;
+    }
+}
+
+foreach ($frontends as $name => $i) {
+    echo "using certificate for HAProxy frontend \"$name\".\n";
+    $config['installedpackages']['haproxy']['ha_backends']['item'][$i]['ssloffloadcert'] = $refid;
+}
+
+write_config("rci: remote " . ($created ? "creation" : "update") . " of certificate \"{$cert['descr']}\" ($refid)");
+
+// includes any services just switched over to the certificate
 echo "restarting all services used by certificate.\n";
 $services = cert_get_all_services($cert['refid']);
 cert_restart_services($services);
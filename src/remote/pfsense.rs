@@ -1,14 +1,15 @@
-use std::{collections::HashMap, path::PathBuf, rc::Rc};
+use std::{fmt, net::IpAddr, rc::Rc, str::FromStr};
 
 use anyhow::{anyhow, bail, Result};
-use serde::{de::{self, MapAccess, Visitor}, Deserialize};
+use serde::{de, Deserialize, Serialize};
 use url::Url;
+use vec1::Vec1;
 
-use crate::{config::{CertificatePair, CertificateRef}, ssh::ConnectOptions};
+use crate::{config::{CertificatePair, CertificateRef, GlobalCertificates, PemLineEnding}, ssh::ConnectOptions};
 
 #[derive(Deserialize, Debug)]
 struct RawConfig {
-    pub certificate: CertificateRef,
+    pub certificate: Option<CertificateRef>,
 
     pub url: Url,
 
@@ -18,41 +19,234 @@ struct RawConfig {
     #[serde(rename = "http")]
     pub http_config: Option<crate::http::Config>,
 
+    /// Connect to this IP address instead of resolving the URL's hostname
+    pub address: Option<IpAddr>,
+
     // #[serde(rename = "verify")]
     // pub verify_config: crate::verify::RawConfig,
 
     /// The pfSense certificate reference ID
-    pub refid: String,
+    pub refid: Option<String>,
+
+    /// The pfSense certificate description, used to find (or create) the certificate when `refid` isn't known.
+    /// With `refid`, the description of the certificate created if there's none with that refid
+    pub descr: Option<String>,
+
+    /// Services to switch over to the certificate
+    pub services: Option<Vec<Service>>,
+
+    /// Several certificates to install, instead of the single `certificate`
+    pub certificates: Option<Vec<RawBinding>>,
+
+    /// `lf` or `crlf`, for the PEM passed to the update script. Defaults to the platform's line ending
+    pub pem_line_ending: Option<PemLineEnding>,
 }
 
+/// A certificate and the pfSense certificate it replaces
+#[derive(Deserialize, Debug)]
+struct RawBinding {
+    pub certificate: CertificateRef,
+
+    pub refid: Option<String>,
+
+    pub descr: Option<String>,
+
+    #[serde(default)]
+    pub services: Vec<Service>,
+}
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "protocol", rename_all = "lowercase")]
+#[allow(clippy::large_enum_variant)]
 pub enum ProtocolConfig {
     Ssh {
+        #[serde(rename = "ssh")]
         ssh_options: crate::ssh::ConnectOptions,
     },
     Http {}
 }
 
-#[derive(Debug, Clone)]
-pub struct Config<CertT> {
+/// Which certificate in the pfSense config is updated
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum CertificateSelector {
+    /// the certificate with this refid, which is created if there isn't one (e.g., on a freshly installed
+    /// firewall) with the description `descr`, or else the refid
+    Refid {
+        refid: String,
+
+        #[serde(skip_serializing_if = "Option::is_none")]
+        descr: Option<String>,
+    },
+
+    /// the certificate with this description, which is created if there isn't one
+    Descr {
+        descr: String,
+    },
+}
+
+impl CertificateSelector {
+    fn from_keys<E: de::Error>(refid: Option<String>, descr: Option<String>) -> std::result::Result<Self, E> {
+        match (refid, descr) {
+            (Some(refid), descr) => Ok(CertificateSelector::Refid { refid, descr }),
+            (None, Some(descr)) => Ok(CertificateSelector::Descr { descr }),
+            (None, None) => Err(E::custom("one of `refid` or `descr` is required")),
+        }
+    }
+}
+
+/// A pfSense service that can be set to use the installed certificate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Service {
+    /// the webConfigurator (`webgui`)
+    WebGui,
+
+    /// the HAProxy package frontend with this name (`haproxy:<frontend>`)
+    HaproxyFrontend(String),
+}
+
+impl FromStr for Service {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            None if s == "webgui" => Ok(Service::WebGui),
+            Some(("haproxy", frontend)) if !frontend.is_empty() => Ok(Service::HaproxyFrontend(frontend.to_string())),
+            _ => bail!("unknown service '{s}' (expected `webgui` or `haproxy:<frontend>`)"),
+        }
+    }
+}
+
+impl fmt::Display for Service {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Service::WebGui => write!(f, "webgui"),
+            Service::HaproxyFrontend(frontend) => write!(f, "haproxy:{frontend}"),
+        }
+    }
+}
+
+impl Serialize for Service {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Service {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
+
+/// A certificate already in the pfSense config
+#[derive(Debug, Deserialize)]
+pub struct ExistingCertificate {
+    pub refid: String,
+
+    pub descr: String,
+
+    /// When it expires, in seconds since the Unix epoch, if pfSense could parse it
+    pub not_after: Option<i64>,
+
+    /// The services using it
+    pub services: Vec<Service>,
+}
+
+/// The keys needed to connect to a firewall, without any of its certificates
+#[derive(Deserialize, Debug)]
+pub struct ConnectConfig {
+    url: Url,
+
+    ssh: crate::ssh::Config,
+
+    address: Option<IpAddr>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Binding<CertT> {
     pub certificate: CertT,
 
-    refid: String,
+    #[serde(flatten)]
+    selector: CertificateSelector,
+
+    /// switched over to the certificate once it is installed.
+    /// Without any, only the certificate store is updated.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    services: Vec<Service>,
+}
+
+/// This remote's section of the example config
+pub(crate) fn sample() -> String {
+    format!(r#"[pfsense.router]
+certificate = "default"
+{}# the pfSense certificate to replace, by description or `refid` (either is created if there isn't one;
+# with both, `descr` describes the certificate created for `refid`)
+descr = "certinstaller"
+# services switched over to the certificate: "webgui", or "haproxy:<frontend>"
+services = ["webgui"]
+# pem_line_ending = "lf"                     # or "crlf". Defaults to the platform's line ending
+# several certificates can be installed over one connection, with `[[pfsense.router.certificates]]`
+# tables of `certificate`, `refid`/`descr` and `services`, instead of the keys above
+"#, crate::sample::ssh("ssh://admin@router.example.net"))
+}
 
+#[derive(Debug, Clone, Serialize)]
+pub struct Config<CertT> {
+    /// installed in order, over a single connection
+    pub certificates: Vec1<Binding<CertT>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pem_line_ending: Option<PemLineEnding>,
+
+    #[serde(flatten)]
     protocol: ProtocolConfig
 }
 
 impl Config<CertificateRef> {
-    pub fn try_resolve_certificate(self, global_certs: &HashMap<String, Rc<CertificatePair>>) -> Result<Config<Rc<CertificatePair>>> {
+    pub fn try_resolve_certificate(self, global_certs: &GlobalCertificates) -> Result<Config<Rc<CertificatePair>>> {
+        let single = self.certificates.len() == 1;
+        let mut i = 0;
+
+        let certificates = self.certificates.try_mapped(|binding| {
+            let key = match single {
+                true => "`certificate`".to_string(),
+                false => format!("`certificate` of `certificates[{i}]`"),
+            };
+            i += 1;
+
+            Ok::<_, anyhow::Error>(Binding {
+                certificate: binding.certificate.try_resolve(global_certs).map_err(|e| anyhow!("{e} for key {key}"))?,
+                selector: binding.selector,
+                services: binding.services,
+            })
+        })?;
+
         Ok(Config {
-            certificate: self.certificate.try_resolve(global_certs).map_err(|e| anyhow!("{e} for key `certificate`"))?,
-            refid: self.refid,
+            certificates,
+            pem_line_ending: self.pem_line_ending,
             protocol: self.protocol
         })
     }
 }
 
+impl<CertT> Config<CertT> {
+    /// the webConfigurator, on the same host used to apply the update
+    pub fn default_verify_url(&self) -> Option<Url> {
+        match &self.protocol {
+            ProtocolConfig::Ssh { ssh_options } => Some(crate::verify::https_url(ssh_options.host(), None)),
+            ProtocolConfig::Http {  } => None,
+        }
+    }
+
+    /// Where the remote is managed through
+    pub fn endpoint(&self) -> Option<crate::probe::Endpoint> {
+        match &self.protocol {
+            ProtocolConfig::Ssh { ssh_options } => ssh_options.endpoint(),
+            ProtocolConfig::Http {  } => None,
+        }
+    }
+}
+
 impl <'de> Deserialize<'de> for Config<CertificateRef> {
     fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
     where
@@ -60,6 +254,27 @@ impl <'de> Deserialize<'de> for Config<CertificateRef> {
     {
         let raw = RawConfig::deserialize(deserializer)?;
 
+        let certificates = match (raw.certificate, raw.certificates) {
+            (Some(_), Some(_)) => return Err(de::Error::custom("only one of `certificate` and `certificates` may be set")),
+            (Some(certificate), None) => Vec1::new(Binding {
+                certificate,
+                selector: CertificateSelector::from_keys(raw.refid, raw.descr)?,
+                services: raw.services.unwrap_or_default(),
+            }),
+            (None, Some(bindings)) => {
+                if raw.refid.is_some() || raw.descr.is_some() || raw.services.is_some() {
+                    return Err(de::Error::custom("`refid`, `descr` and `services` must be set for each of `certificates`"));
+                }
+
+                let bindings = bindings.into_iter()
+                    .map(|b| Ok(Binding { certificate: b.certificate, selector: CertificateSelector::from_keys(b.refid, b.descr)?, services: b.services }))
+                    .collect::<std::result::Result<Vec<_>, D::Error>>()?;
+
+                Vec1::try_from_vec(bindings).map_err(|_| de::Error::custom("`certificates` must not be empty"))?
+            },
+            (None, None) => return Err(de::Error::custom("one of `certificate` or `certificates` is required")),
+        };
+
         let pc = match raw.url.scheme() {
             proto @ ("http" | "https") => {
                 if raw.ssh_config.is_some() {
@@ -76,7 +291,8 @@ impl <'de> Deserialize<'de> for Config<CertificateRef> {
                 let ssh_config = raw.ssh_config
                     .ok_or(de::Error::custom(format!("key `ssh` is required for {proto} connections")))?;
 
-                let ssh_options = ConnectOptions::new(raw.url, &ssh_config).unwrap();
+                let ssh_options = ConnectOptions::new(raw.url, &ssh_config).map_err(de::Error::custom)?
+                    .with_address(raw.address);
 
                 ProtocolConfig::Ssh { ssh_options }
             },
@@ -85,72 +301,164 @@ impl <'de> Deserialize<'de> for Config<CertificateRef> {
             }
         };
 
-        Ok(Config { certificate: raw.certificate, refid: raw.refid, protocol: pc })
+        Ok(Config { certificates, pem_line_ending: raw.pem_line_ending, protocol: pc })
     }
 }
 
 mod ssh {
-    use std::{fmt::Display, sync::Arc};
+    use std::rc::Rc;
 
-    use anyhow::{bail, Context, Result};
-    use russh::{ChannelMsg, CryptoVec};
-    use tokio::io::AsyncWriteExt as _;
-    use tracing::{debug, event, Level};
-    use url::Url;
+    use anyhow::{Context, Result};
 
-    use crate::{config::CertificatePair, ssh::{ssh_connect, ConnectOptions}};
+    use openssl::base64::encode_block;
+    use tracing::info;
 
-    use super::Config;
+    use crate::{config::{CertificatePair, PemLineEnding}, ssh::{exec, run_hooks, ssh_connect, CommandOutput, ConnectOptions, Hook}, timing};
+
+    use super::{Binding, CertificateSelector, ExistingCertificate, Service};
 
     const UPDATE_SCRIPT: &str = include_str!("pfsense-update.php");
 
-    pub async fn update_certificate(certificate: &CertificatePair, ref_id: &String, ssh_options: &ConnectOptions) -> Result<()> {
-        let script = UPDATE_SCRIPT.replace("@@REFID@@", ref_id)
-            .replace("@@CERTIFICATE@@", &certificate.fullchain_certificate_pem_string()?)
-            .replace("@@PRIVATE_KEY@@", &certificate.private_key_pem_string()?)
-            .into_bytes();
+    const LIST_SCRIPT: &str = include_str!("pfsense-list.php");
 
+    pub async fn list_certificates(ssh_options: &ConnectOptions) -> Result<Vec<ExistingCertificate>> {
         let handle = ssh_connect(ssh_options).await?;
 
-        debug!("opening session");
-        let mut channel = handle.channel_open_session().await?;
+        let output = exec(&handle, "php", LIST_SCRIPT.as_bytes()).await?
+            .check("certificate listing script")?;
 
-        debug!("running PHP update script");
-        channel.exec(true, "php").await?;
-        channel.data(&script[..]).await?;
-        channel.eof().await?;
+        parse_list(&output.stdout)
+    }
 
-        let mut exit_status = None;
+    /// The certificates printed by the listing script, ignoring any other output (e.g., PHP warnings)
+    fn parse_list(stdout: &[u8]) -> Result<Vec<ExistingCertificate>> {
+        String::from_utf8_lossy(stdout).lines()
+            .filter(|line| line.starts_with('{'))
+            .map(|line| serde_json::from_str(line).with_context(|| format!("unexpected output from the certificate listing script: {line}")))
+            .collect()
+    }
 
-        loop {
-            let Some(msg) = channel.wait().await else {
-                break;
-            };
+    pub async fn update_certificates(bindings: &[Binding<Rc<CertificatePair>>], ssh_options: &ConnectOptions, line_ending: Option<PemLineEnding>) -> Result<()> {
+        let handle = ssh_connect(ssh_options).await?;
+        run_hooks(&handle, ssh_options, Hook::Pre).await?;
 
-            match msg {
-                ChannelMsg::Data { ref data } => {
-                    struct DisplayUtf8CryptoVec<'a>(&'a CryptoVec);
+        for binding in bindings {
+            let script = binding_script(binding, line_ending, false)?.into_bytes();
 
-                    impl Display for DisplayUtf8CryptoVec<'_> {
-                        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-                            write!(f,"{}", String::from_utf8_lossy(self.0))
-                        }
-                    }
+            let output = timing::phase("upload", exec(&handle, "php", &script)).await?
+                .check("certificate update script")
+                .with_context(|| format!("failed to update pfSense certificate {}", refid_or_descr(&binding.selector)))?;
 
-                    debug!("script stdout: {}", DisplayUtf8CryptoVec(data))
-                }
-                ChannelMsg::ExitStatus { exit_status: status } => exit_status = Some(status),
-                _ => {}
+            match created(&output) {
+                true => info!("created pfSense certificate {}", refid_or_descr(&binding.selector)),
+                false => info!("updated pfSense certificate {}", refid_or_descr(&binding.selector)),
             }
         }
 
-        let Some(exit_status) = exit_status else {
-            bail!("SSH channel closed without an exit status from the script");
+        run_hooks(&handle, ssh_options, Hook::Post).await
+    }
+
+    /// Whether the update script added a new certificate to the config, rather than updating an existing one
+    fn created(output: &CommandOutput) -> bool {
+        String::from_utf8_lossy(&output.stdout).lines().any(|line| line.starts_with("creating certificate "))
+    }
+
+    /// The script that installs `binding`, with the private key replaced by a placeholder if `redact_key`
+    pub fn binding_script(binding: &Binding<Rc<CertificatePair>>, line_ending: Option<PemLineEnding>, redact_key: bool) -> Result<String> {
+        let Binding { certificate, selector, services } = binding;
+
+        // base64 encoded so arbitrary config values can't break out of the PHP string literals
+        let (refid, descr) = match selector {
+            CertificateSelector::Refid { refid, descr } => (refid.as_str(), descr.as_deref().unwrap_or_default()),
+            CertificateSelector::Descr { descr } => ("", descr.as_str()),
         };
 
-        match exit_status {
-            0 => Ok(()),
-            other => bail!("certificate update script exited with status {other}")
+        let script = script(refid, descr, services, certificate, line_ending)?;
+
+        Ok(match redact_key {
+            true => script.replace(&certificate.private_key_pem_string_with(line_ending)?, "<redacted>"),
+            false => script,
+        })
+    }
+
+    pub fn refid_or_descr(selector: &CertificateSelector) -> String {
+        match selector {
+            CertificateSelector::Refid { refid, .. } => format!("with refid {refid}"),
+            CertificateSelector::Descr { descr } => format!("\"{descr}\""),
+        }
+    }
+
+    fn script(refid: &str, descr: &str, services: &[Service], certificate: &CertificatePair, line_ending: Option<PemLineEnding>) -> Result<String> {
+        // one per line, as understood by the script
+        let services = services.iter().map(Service::to_string).collect::<Vec<_>>().join("\n");
+
+        Ok(UPDATE_SCRIPT.replace("@@REFID@@", &encode_block(refid.as_bytes()))
+            .replace("@@DESCR@@", &encode_block(descr.as_bytes()))
+            .replace("@@SERVICES@@", &encode_block(services.as_bytes()))
+            .replace("@@CERTIFICATE@@", &certificate.fullchain_certificate_pem_string_with(line_ending)?)
+            .replace("@@PRIVATE_KEY@@", &certificate.private_key_pem_string_with(line_ending)?))
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        #[test]
+        fn test_script_escapes_selector() {
+            let pair = crate::test_util::certificate_pair(&["nexus.example.net"]);
+
+            let script = script("", "web \"$GUI\" cert", &[], &pair, None).unwrap();
+
+            assert!(script.contains(r#"$refid = base64_decode("");"#));
+            assert!(script.contains(r#"$descr = base64_decode("d2ViICIkR1VJIiBjZXJ0");"#));
+            assert!(script.contains(r#"$service_names = base64_decode("");"#));
+            assert!(!script.contains("@@"));
+        }
+
+        #[test]
+        fn test_script_services() {
+            let pair = crate::test_util::certificate_pair(&["nexus.example.net"]);
+
+            let services = [Service::WebGui, Service::HaproxyFrontend("\"$front\"".to_string())];
+            let script = script("5f1a", "", &services, &pair, None).unwrap();
+            assert!(script.contains(&format!(r#"$service_names = base64_decode("{}");"#, encode_block(b"webgui\nhaproxy:\"$front\""))));
+        }
+
+        #[test]
+        fn test_parse_list() {
+            let certificates = parse_list(concat!(
+                "PHP Warning:  Undefined array key \"haproxy\"\n",
+                r#"{"refid":"5f1a","descr":"webConfigurator default","not_after":1798761600,"services":["webgui","haproxy:web"]}"#, "\n",
+                r#"{"refid":"6e2b","descr":"broken","not_after":null,"services":[]}"#, "\n",
+            ).as_bytes()).unwrap();
+
+            assert_eq!(certificates.len(), 2);
+            assert_eq!((certificates[0].refid.as_str(), certificates[0].descr.as_str(), certificates[0].not_after), ("5f1a", "webConfigurator default", Some(1798761600)));
+            assert_eq!(certificates[0].services, [Service::WebGui, Service::HaproxyFrontend("web".to_string())]);
+            assert_eq!(certificates[1].not_after, None);
+
+            assert!(parse_list(b"{\"refid\": 1}\n").is_err());
+        }
+
+        #[test]
+        fn test_created() {
+            let output = |stdout: &str| CommandOutput { exit_status: 0, stdout: stdout.as_bytes().to_vec(), stderr: vec![] };
+
+            assert!(created(&output("no certificate with refid 5f1a.\ncreating certificate \"web\" (5f1a).\ncomplete.\n")));
+            assert!(!created(&output("updating certificate \"web\" (5f1a).\ncomplete.\n")));
+        }
+
+        #[test]
+        fn test_binding_script_redacts_key() {
+            let binding = Binding { certificate: Rc::new(crate::test_util::certificate_pair(&["nexus.example.net"])), selector: CertificateSelector::Refid { refid: "5f1a".to_string(), descr: None }, services: vec![] };
+            let key = binding.certificate.private_key_pem_string().unwrap();
+
+            let script = binding_script(&binding, None, true).unwrap();
+            assert!(!script.contains(key.trim()) && !script.contains("PRIVATE KEY"));
+            assert!(script.contains("$key_str = <<<'KEY'\n<redacted>\nKEY;"), "{script}");
+            assert!(script.contains(&binding.certificate.fullchain_certificate_pem_string().unwrap()));
+
+            assert!(binding_script(&binding, None, false).unwrap().contains(&key));
         }
     }
 }
@@ -224,10 +532,34 @@ mod ssh {
 // }
 
 
+pub async fn test_connection(config: &Config<Rc<CertificatePair>>) -> Result<()> {
+    match &config.protocol {
+        ProtocolConfig::Ssh { ssh_options } => crate::ssh::test_connection(ssh_options).await,
+        ProtocolConfig::Http {  } => bail!("HTTP connections are not yet supported"),
+    }
+}
+
+/// The certificates in the firewall's config, without changing anything
+pub async fn list_certificates(config: ConnectConfig) -> Result<Vec<ExistingCertificate>> {
+    match config.url.scheme() {
+        "ssh" => ssh::list_certificates(&ConnectOptions::new(config.url, &config.ssh)?.with_address(config.address)).await,
+        "http" | "https" => bail!("HTTP connections are not yet supported"),
+        other => bail!("unknown protocol '{other}'"),
+    }
+}
+
+/// The script run to install each of the remote's certificates, and which certificate it replaces,
+/// exactly as it would be sent. Unless `show_secrets`, the private key is replaced by a placeholder
+pub fn update_scripts(config: &Config<Rc<CertificatePair>>, show_secrets: bool) -> Result<Vec<(String, String)>> {
+    config.certificates.iter()
+        .map(|binding| Ok((ssh::refid_or_descr(&binding.selector), ssh::binding_script(binding, config.pem_line_ending, !show_secrets)?)))
+        .collect()
+}
+
 pub async fn update_certificate(config: &Config<Rc<CertificatePair>>) -> Result<()> {
     match &config.protocol {
-        ProtocolConfig::Ssh { ssh_options } => ssh::update_certificate(&config.certificate, &config.refid, &ssh_options).await,
-        ProtocolConfig::Http {  } => todo!(),
+        ProtocolConfig::Ssh { ssh_options } => ssh::update_certificates(&config.certificates, ssh_options, config.pem_line_ending).await,
+        ProtocolConfig::Http {  } => bail!("HTTP connections are not yet supported"),
     }
 }
 
@@ -237,10 +569,91 @@ pub async fn update_certificate(config: &Config<Rc<CertificatePair>>) -> Result<
 mod test {
     use figment::{providers::{Format as _, Toml}, Figment};
 
-    use crate::{config, ssh::ssh_connect};
-
     use super::*;
 
+    #[test]
+    #[allow(clippy::result_large_err)]
+    fn test_certificate_bindings() {
+        let mut key = Vec::new();
+        russh_keys::encode_pkcs8_pem(&russh_keys::key::KeyPair::generate_ed25519().unwrap(), &mut key).unwrap();
+
+        figment::Jail::expect_with(|jail| {
+            jail.set_env("SSH_KEY", String::from_utf8(key.clone()).unwrap());
+
+            let extract = |toml: &str| Figment::new()
+                .merge(Toml::string(&format!(r#"
+                    url = "ssh://admin@router.example.net"
+                    ssh.private_key_env = "SSH_KEY"
+                    ssh.host_key = "ignore"
+                    {toml}
+                "#)))
+                .extract::<Config<CertificateRef>>();
+
+            // the single form
+            let config = extract(r#"
+                certificate = "default"
+                refid = "5f1a"
+            "#).unwrap();
+            assert_eq!(config.certificates.len(), 1);
+            assert!(matches!(&config.certificates[0].selector, CertificateSelector::Refid { refid, descr: None } if refid == "5f1a"));
+
+            let config = extract(r#"
+                [[certificates]]
+                certificate = "default"
+                refid = "5f1a"
+
+                [[certificates]]
+                certificate = "vpn"
+                descr = "OpenVPN server"
+            "#).unwrap();
+            assert_eq!(config.certificates.len(), 2);
+            assert!(matches!(&config.certificates[1].certificate, CertificateRef::Named(n) if n == "vpn"));
+            assert!(matches!(&config.certificates[1].selector, CertificateSelector::Descr { descr } if descr == "OpenVPN server"));
+
+            // the description of the certificate created if there's none with the refid
+            let config = extract(r#"
+                certificate = "default"
+                refid = "5f1a"
+                descr = "web"
+            "#).unwrap();
+            assert!(matches!(&config.certificates[0].selector, CertificateSelector::Refid { refid, descr: Some(descr) } if refid == "5f1a" && descr == "web"));
+
+            let e = extract(r#"
+                certificate = "default"
+                refid = "5f1a"
+                certificates = [{ certificate = "vpn", refid = "6e2b" }]
+            "#).unwrap_err();
+            assert!(e.to_string().contains("only one of `certificate` and `certificates`"), "{e}");
+
+            let e = extract(r#"certificates = [{ certificate = "vpn" }]"#).unwrap_err();
+            assert!(e.to_string().contains("one of `refid` or `descr` is required"), "{e}");
+
+            assert!(extract("certificates = []").is_err());
+
+            let config = extract(r#"
+                certificate = "default"
+                refid = "5f1a"
+                services = ["webgui", "haproxy:myfrontend"]
+            "#).unwrap();
+            assert_eq!(config.certificates[0].services, [Service::WebGui, Service::HaproxyFrontend("myfrontend".to_string())]);
+
+            let e = extract(r#"
+                certificates = [{ certificate = "vpn", refid = "6e2b" }]
+                services = ["webgui"]
+            "#).unwrap_err();
+            assert!(e.to_string().contains("must be set for each of `certificates`"), "{e}");
+
+            let e = extract(r#"
+                certificate = "default"
+                refid = "5f1a"
+                services = ["haproxy"]
+            "#).unwrap_err();
+            assert!(e.to_string().contains("unknown service 'haproxy'"), "{e}");
+
+            Ok(())
+        });
+    }
+
     // #[tokio::test]
     // async fn test_config() {
     //     #[derive(Deserialize)]
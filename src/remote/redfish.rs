@@ -0,0 +1,336 @@
+//! Dell iDRAC and HPE iLO management controllers, via their Redfish APIs
+
+use std::{net::IpAddr, rc::Rc};
+
+use reqwest::{header::{HeaderMap, HeaderValue, LOCATION}, Client, Url};
+use serde::{de, Deserialize, Serialize};
+use serde_json::{json, Value};
+use anyhow::{anyhow, Context, Result};
+use tracing::{debug, info, warn};
+
+use crate::{config::{CertificatePair, CertificateRef, CredentialPathBuf, GlobalCertificates, PemLineEnding, SecretSource}, timing};
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Vendor {
+    /// Dell iDRAC 9 (firmware 5.10 or later, for custom certificate import)
+    Idrac,
+
+    /// HPE iLO 5/6, via the standard `CertificateService.ReplaceCertificate` action
+    Ilo,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+struct RawConfig {
+    certificate: CertificateRef,
+
+    url: Url,
+
+    vendor: Vendor,
+
+    password_file: Option<CredentialPathBuf>,
+
+    /// environment variable containing the password, instead of `password_file`
+    password_env: Option<String>,
+
+    /// restart the controller so it starts serving the new certificate
+    #[serde(default = "default_reset")]
+    reset: bool,
+
+    #[serde(default)]
+    http: crate::http::Config,
+
+    /// Connect to this IP address instead of resolving the URL's hostname
+    address: Option<IpAddr>,
+
+    /// `lf` or `crlf`, for firmware that only accepts one kind in PEM (iLO). Defaults to the platform's line ending
+    pem_line_ending: Option<PemLineEnding>,
+}
+
+fn default_reset() -> bool { true }
+
+/// This remote's section of the example config
+pub(crate) fn sample() -> String {
+    format!(r#"[redfish.idrac]
+certificate = "default"
+url = "https://root@idrac.example.net"
+vendor = "idrac"                             # or "ilo"
+password_file = "idrac-password"
+# password_env = "IDRAC_PASSWORD"            # instead of password_file
+# reset = {}                               # restart the controller so it serves the new certificate
+# http.client_identity = {{ certificate_path = "client.pem", private_key_path = "client.key" }}
+# http.user_agent = "Mozilla/5.0"           # instead of the top-level `user_agent`
+# http.max_response_bytes = {max_response_bytes}         # refuse larger responses
+# pem_line_ending = "lf"                     # or "crlf". Defaults to the platform's line ending
+"#, default_reset(), max_response_bytes = crate::http::DEFAULT_MAX_RESPONSE_BYTES)
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Config<CertT> {
+    pub certificate: CertT,
+
+    #[serde(serialize_with = "crate::config::serialize_url_redacted")]
+    pub url: Url,
+
+    pub vendor: Vendor,
+
+    /// used when the URL doesn't contain a password
+    #[serde(serialize_with = "crate::config::serialize_redacted", skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+
+    pub reset: bool,
+
+    #[serde(skip_serializing_if = "crate::http::Config::is_empty")]
+    pub http: crate::http::Config,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<IpAddr>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pem_line_ending: Option<PemLineEnding>,
+}
+
+impl Config<CertificateRef> {
+    pub fn try_resolve_certificate(self, global_certs: &GlobalCertificates) -> Result<Config<Rc<CertificatePair>>> {
+        Ok(Config {
+            certificate: self.certificate.try_resolve(global_certs).map_err(|e| anyhow!("{e} for key `certificate`"))?,
+            url: self.url,
+            vendor: self.vendor,
+            password: self.password,
+            reset: self.reset,
+            http: self.http,
+            address: self.address,
+            pem_line_ending: self.pem_line_ending,
+        })
+    }
+}
+
+impl<CertT> Config<CertT> {
+    /// the controller web interface
+    pub fn default_verify_url(&self) -> Url {
+        let mut url = self.url.join("/").expect("valid verify URL");
+        let _ = url.set_username("");
+        let _ = url.set_password(None);
+
+        url
+    }
+
+    /// Where the remote is managed through
+    pub fn endpoint(&self) -> Option<crate::probe::Endpoint> {
+        crate::probe::Endpoint::of_url(&self.url, self.address)
+    }
+}
+
+impl <'de> Deserialize<'de> for Config<CertificateRef> {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>
+    {
+        let raw = RawConfig::deserialize(deserializer)?;
+
+        match raw.url.scheme() {
+            "http" | "https" => (),
+            other => return Err(de::Error::custom(format!("unknown protocol '{other}'"))),
+        }
+
+        let password = SecretSource::from_keys(raw.password_file, raw.password_env, "password_file", "password_env")
+            .map_err(de::Error::custom)?
+            .map(|source| {
+                source.read_to_string()
+                    .map(|p| p.trim_end_matches(['\r', '\n']).to_string())
+                    .map_err(|e| de::Error::custom(format!("failed to read password {source} ({e:#})")))
+            })
+            .transpose()?;
+
+        Ok(Config { certificate: raw.certificate, url: raw.url, vendor: raw.vendor, password, reset: raw.reset, http: raw.http, address: raw.address, pem_line_ending: raw.pem_line_ending })
+    }
+}
+
+impl Vendor {
+    /// The Redfish ID of the controller itself
+    fn manager_id(&self) -> &'static str {
+        match self {
+            Vendor::Idrac => "iDRAC.Embedded.1",
+            Vendor::Ilo => "1",
+        }
+    }
+
+    /// The action that installs the certificate, and its request body
+    fn import_request(&self, certificate: &CertificatePair, line_ending: Option<PemLineEnding>) -> Result<(String, Value)> {
+        Ok(match self {
+            Vendor::Idrac => {
+                // a plain "Server" certificate must match a CSR generated by the iDRAC, so the
+                // key is included by importing a PKCS#12 archive as a custom certificate
+                let passphrase = random_passphrase()?;
+                let pkcs12 = certificate.pkcs12_der("idrac", &passphrase)?;
+
+                (
+                    format!("Dell/Managers/{}/DelliDRACCardService/Actions/DelliDRACCardService.ImportSSLCertificate", self.manager_id()),
+                    json!({
+                        "CertificateType": "CustomCertificate",
+                        "SSLCertificateFile": openssl::base64::encode_block(&pkcs12),
+                        "Passphrase": passphrase,
+                    })
+                )
+            },
+            Vendor::Ilo => (
+                "CertificateService/Actions/CertificateService.ReplaceCertificate".to_string(),
+                json!({
+                    "CertificateType": "PEMchain",
+                    "CertificateString": format!("{}{}", certificate.fullchain_certificate_pem_string_with(line_ending)?, certificate.private_key_pem_string_with(line_ending)?),
+                    "CertificateUri": {
+                        "@odata.id": format!("/redfish/v1/Managers/{}/NetworkProtocol/HTTPS/Certificates/1", self.manager_id()),
+                    },
+                })
+            ),
+        })
+    }
+
+    fn reset_request(&self) -> (String, Value) {
+        (
+            format!("Managers/{}/Actions/Manager.Reset", self.manager_id()),
+            json!({ "ResetType": "GracefulRestart" })
+        )
+    }
+}
+
+fn random_passphrase() -> Result<String> {
+    let mut bytes = [0u8; 18];
+    openssl::rand::rand_bytes(&mut bytes).context("failed to generate a PKCS#12 passphrase")?;
+
+    Ok(openssl::base64::encode_block(&bytes))
+}
+
+/// Update iDRAC/iLO web server TLS certificates
+///
+/// As with MegaRAC BMCs, invalid certificates are accepted when talking to the controller,
+/// since it is likely presenting a self-signed (or soon to be replaced) certificate.
+pub async fn update_certificate(config: &Config<Rc<CertificatePair>>) -> Result<()> {
+    let session = timing::phase("auth", Session::login(config)).await?;
+
+    let (action, body) = config.vendor.import_request(&config.certificate, config.pem_line_ending)?;
+
+    info!("uploading certificate");
+    let response = timing::phase("upload", session.post(&action, &body)).await.context("certificate import failed")?;
+    debug!("import response: {response}");
+
+    if config.reset {
+        // the controller drops the session as it restarts, so there's no logout
+        let (action, body) = config.vendor.reset_request();
+
+        info!("resetting controller");
+        timing::phase("restart", session.post(&action, &body)).await.context("controller reset failed")?;
+
+    } else {
+        session.logout().await?;
+    }
+
+    Ok(())
+}
+
+/// Create a session and then immediately delete it, without changing anything
+pub async fn test_connection(config: &Config<Rc<CertificatePair>>) -> Result<()> {
+    Session::login(config).await?
+        .logout().await
+}
+
+/// A Redfish session, authenticated with `X-Auth-Token`
+struct Session {
+    base_url: Url,
+    client: Client,
+
+    /// the session resource, deleted on logout
+    location: Option<Url>,
+
+    max_response_bytes: u64,
+}
+
+impl Session {
+    async fn login(config: &Config<Rc<CertificatePair>>) -> Result<Self> {
+        let mut base_url = config.url.join("/redfish/v1/").expect("valid base_url");
+
+        // credentials are sent in the session request, never in the URL
+        let _ = base_url.set_username("");
+        let _ = base_url.set_password(None);
+
+        let client = crate::http::resolve_to(config.http.client_builder(), &config.url, config.address)
+            .danger_accept_invalid_certs(true) // see comment on update_certificate
+            .build().context("failed to build a Client")?;
+
+        let creds = json!({
+            "UserName": config.url.username(),
+            "Password": config.url.password().or(config.password.as_deref()).unwrap_or_default(),
+        });
+
+        info!("logging in to {base_url}");
+        let response = client.post(base_url.join("SessionService/Sessions").expect("valid API url"))
+            .json(&creds)
+            .send().await.context("failed to send request")?
+            .error_for_status().context("login failed")?;
+
+        let token = response.headers().get("X-Auth-Token")
+            .ok_or_else(|| anyhow!("login response did not include an X-Auth-Token"))?;
+
+        let mut token = HeaderValue::from_bytes(token.as_bytes()).context("invalid session token")?;
+        token.set_sensitive(true);
+
+        let location = response.headers().get(LOCATION)
+            .and_then(|l| l.to_str().ok())
+            .and_then(|l| base_url.join(l).ok());
+
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Auth-Token", token);
+
+        let client = crate::http::resolve_to(config.http.client_builder(), &config.url, config.address)
+            .default_headers(headers)
+            .danger_accept_invalid_certs(true)
+            .build().context("failed to build a Client")?;
+
+        Ok(Session { base_url, client, location, max_response_bytes: config.http.max_response_bytes() })
+    }
+
+    async fn post(&self, path: &str, body: &Value) -> Result<String> {
+        let response = self.client.post(self.base_url.join(path).expect("valid API url"))
+            .json(body)
+            .send().await.context("failed to send request")?
+            .error_for_status()?;
+
+        crate::http::text(response, self.max_response_bytes).await.context("failed to read response")
+    }
+
+    async fn logout(&self) -> Result<()> {
+        let Some(location) = &self.location else {
+            warn!("login response did not include a session location, not logging out");
+            return Ok(());
+        };
+
+        self.client.delete(location.clone())
+            .send().await.context("failed to send request")?
+            .error_for_status().context("logout failed")?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_import_request() {
+        let pair = crate::test_util::certificate_pair(&["idrac.example.net"]);
+
+        let (action, body) = Vendor::Idrac.import_request(&pair, None).unwrap();
+        assert_eq!(action, "Dell/Managers/iDRAC.Embedded.1/DelliDRACCardService/Actions/DelliDRACCardService.ImportSSLCertificate");
+
+        let pkcs12 = openssl::base64::decode_block(body["SSLCertificateFile"].as_str().unwrap()).unwrap();
+        let parsed = openssl::pkcs12::Pkcs12::from_der(&pkcs12).unwrap()
+            .parse2(body["Passphrase"].as_str().unwrap()).unwrap();
+        assert_eq!(parsed.cert.unwrap().to_der().unwrap(), pair.certificate_chain.first().as_ref());
+
+        let (action, body) = Vendor::Ilo.import_request(&pair, Some(PemLineEnding::Crlf)).unwrap();
+        assert_eq!(action, "CertificateService/Actions/CertificateService.ReplaceCertificate");
+        assert_eq!(body["CertificateUri"]["@odata.id"], "/redfish/v1/Managers/1/NetworkProtocol/HTTPS/Certificates/1");
+        assert!(body["CertificateString"].as_str().unwrap().contains("PRIVATE KEY-----\r\n"));
+    }
+}
@@ -0,0 +1,404 @@
+//! UniFi Dream Machine (UDM, UDM Pro, UDM SE) and other UniFi OS consoles
+//!
+//! Unlike the UniFi Network application on a Cloud Key, whose certificate lives in a Java keystore,
+//! UniFi OS serves its web interface from `unifi-core`, which reads a PEM certificate and key from
+//! `/data/unifi-core/config/unifi-core.crt` and `unifi-core.key` at startup. The files are uploaded
+//! over SSH, checked with `openssl` on the console, then `unifi-core` is restarted. The update only
+//! succeeds once the console is serving the new certificate.
+//!
+//! `unifi-core` sends only what's in the `.crt` file, so it holds the full chain; with just the
+//! end-entity certificate, clients without the intermediates cached can't build a chain.
+//!
+//! With `services = ["core", "protect"]` the certificate is also installed for UniFi Protect, which
+//! serves its own certificate on port 7443, and `unifi-protect` is restarted too. A service that
+//! isn't installed on the console (its certificate directory doesn't exist) is skipped.
+
+use std::{fmt, net::IpAddr, path::Path, rc::Rc, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use serde::{de, Deserialize, Serialize};
+use tracing::{info, warn};
+use url::Url;
+
+use crate::{config::{CertificatePair, CertificateRef, GlobalCertificates}, deploy::{self, Transfer}, error::RciError, sftp::FileAttributes, ssh::{exec, run_hooks, shell_quote, ssh_connect, ConnectOptions, Hook}, timing};
+
+/// How long `unifi-core` takes to stop once restarted, so the old instance isn't mistaken for the new one
+const RESTART_GRACE: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize, Debug)]
+struct RawConfig {
+    pub certificate: CertificateRef,
+
+    pub url: Url,
+
+    #[serde(rename = "ssh")]
+    pub ssh_config: crate::ssh::Config,
+
+    /// Connect to this IP address instead of resolving the URL's hostname
+    pub address: Option<IpAddr>,
+
+    #[serde(default = "RawConfig::default_certificate_path")]
+    pub certificate_path: String,
+
+    #[serde(default = "RawConfig::default_private_key_path")]
+    pub private_key_path: String,
+
+    /// `openssl` on the console, used to check the uploaded files
+    #[serde(default = "RawConfig::default_openssl")]
+    pub openssl: String,
+
+    #[serde(default = "RawConfig::default_restart_command")]
+    pub restart_command: String,
+
+    /// How long to wait for the console to serve the new certificate after restarting, in seconds
+    #[serde(default = "RawConfig::default_restart_timeout")]
+    pub restart_timeout: u64,
+
+    /// The services to install the certificate for
+    #[serde(default = "RawConfig::default_services")]
+    pub services: Vec<Service>,
+
+    #[serde(default = "RawConfig::default_protect_certificate_path")]
+    pub protect_certificate_path: String,
+
+    #[serde(default = "RawConfig::default_protect_private_key_path")]
+    pub protect_private_key_path: String,
+
+    #[serde(default = "RawConfig::default_protect_restart_command")]
+    pub protect_restart_command: String,
+
+    /// The port UniFi Protect serves HTTPS on
+    #[serde(default = "RawConfig::default_protect_port")]
+    pub protect_port: u16,
+}
+
+impl RawConfig {
+    fn default_certificate_path() -> String {
+        "/data/unifi-core/config/unifi-core.crt".to_string()
+    }
+
+    fn default_private_key_path() -> String {
+        "/data/unifi-core/config/unifi-core.key".to_string()
+    }
+
+    fn default_openssl() -> String {
+        "openssl".to_string()
+    }
+
+    fn default_restart_command() -> String {
+        "systemctl restart unifi-core".to_string()
+    }
+
+    fn default_restart_timeout() -> u64 {
+        180
+    }
+
+    fn default_services() -> Vec<Service> {
+        vec![Service::Core]
+    }
+
+    fn default_protect_certificate_path() -> String {
+        "/data/unifi-protect/certs/unifi-protect.crt".to_string()
+    }
+
+    fn default_protect_private_key_path() -> String {
+        "/data/unifi-protect/certs/unifi-protect.key".to_string()
+    }
+
+    fn default_protect_restart_command() -> String {
+        "systemctl restart unifi-protect".to_string()
+    }
+
+    fn default_protect_port() -> u16 {
+        7443
+    }
+}
+
+/// A service on the console that the certificate is installed for
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Service {
+    /// `unifi-core`, which serves the UniFi OS web interface
+    Core,
+
+    /// `unifi-protect`, the UniFi Protect NVR
+    Protect,
+}
+
+impl fmt::Display for Service {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Service::Core => "unifi-core",
+            Service::Protect => "unifi-protect",
+        })
+    }
+}
+
+/// Where a service reads the certificate and key from, and how it's restarted
+#[derive(Debug, Clone, Serialize)]
+struct Target {
+    service: Service,
+    certificate_path: String,
+    private_key_path: String,
+    restart_command: String,
+
+    /// the port the service serves HTTPS on, if not 443
+    #[serde(skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+}
+
+/// Check `services` lists at least one service, and none more than once
+fn check_services(services: &[Service]) -> Result<()> {
+    if services.is_empty() {
+        bail!("`services` must list at least one service");
+    }
+
+    for (i, service) in services.iter().enumerate() {
+        if services[..i].contains(service) {
+            bail!("`services` lists {service} more than once");
+        }
+    }
+
+    Ok(())
+}
+
+/// This remote's section of the example config
+pub(crate) fn sample() -> String {
+    format!(r#"[udm.console]
+certificate = "default"
+{}# certificate_path = {}
+# private_key_path = {}
+# openssl = {}
+# restart_command = {}
+# restart_timeout = {}
+# services = {}                       # or ["core", "protect"] to also install it for UniFi Protect
+# protect_certificate_path = {}
+# protect_private_key_path = {}
+# protect_restart_command = {}
+# protect_port = {}
+"#,
+        crate::sample::ssh("ssh://root@unifi.example.net"),
+        crate::sample::value(&RawConfig::default_certificate_path()),
+        crate::sample::value(&RawConfig::default_private_key_path()),
+        crate::sample::value(&RawConfig::default_openssl()),
+        crate::sample::value(&RawConfig::default_restart_command()),
+        RawConfig::default_restart_timeout(),
+        crate::sample::value(&RawConfig::default_services()),
+        crate::sample::value(&RawConfig::default_protect_certificate_path()),
+        crate::sample::value(&RawConfig::default_protect_private_key_path()),
+        crate::sample::value(&RawConfig::default_protect_restart_command()),
+        RawConfig::default_protect_port())
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Config<CertT> {
+    pub certificate: CertT,
+
+    #[serde(rename = "ssh")]
+    ssh_options: ConnectOptions,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<IpAddr>,
+
+    openssl: String,
+    restart_timeout: u64,
+    services: Vec<Target>,
+
+    /// The config's top-level `user_agent`, set when it's loaded, for requests to the service
+    #[serde(skip)]
+    pub(crate) user_agent: Option<String>,
+}
+
+impl Config<CertificateRef> {
+    pub fn try_resolve_certificate(self, global_certs: &GlobalCertificates) -> Result<Config<Rc<CertificatePair>>> {
+        Ok(Config {
+            certificate: self.certificate.try_resolve(global_certs).map_err(|e| anyhow!("{e} for key `certificate`"))?,
+            ssh_options: self.ssh_options,
+            address: self.address,
+            openssl: self.openssl,
+            restart_timeout: self.restart_timeout,
+            services: self.services,
+            user_agent: self.user_agent,
+        })
+    }
+}
+
+impl<CertT> Config<CertT> {
+    /// The UniFi OS web interface, on the console it was deployed to
+    pub fn default_verify_url(&self) -> Url {
+        crate::verify::https_url(self.ssh_options.host(), None)
+    }
+
+    /// Where the remote is managed through
+    pub fn endpoint(&self) -> Option<crate::probe::Endpoint> {
+        self.ssh_options.endpoint()
+    }
+}
+
+impl <'de> Deserialize<'de> for Config<CertificateRef> {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>
+    {
+        let raw = RawConfig::deserialize(deserializer)?;
+
+        let ssh_options = match raw.url.scheme() {
+            "ssh" => ConnectOptions::new(raw.url, &raw.ssh_config).map_err(de::Error::custom)?.with_address(raw.address),
+            other => return Err(de::Error::custom(format!("unknown protocol '{other}'"))),
+        };
+
+        check_services(&raw.services).map_err(de::Error::custom)?;
+
+        let services = raw.services.iter().map(|&service| match service {
+            Service::Core => Target {
+                service,
+                certificate_path: raw.certificate_path.clone(),
+                private_key_path: raw.private_key_path.clone(),
+                restart_command: raw.restart_command.clone(),
+                port: None,
+            },
+            Service::Protect => Target {
+                service,
+                certificate_path: raw.protect_certificate_path.clone(),
+                private_key_path: raw.protect_private_key_path.clone(),
+                restart_command: raw.protect_restart_command.clone(),
+                port: Some(raw.protect_port),
+            },
+        }).collect();
+
+        Ok(Config {
+            certificate: raw.certificate,
+            ssh_options,
+            address: raw.address,
+            openssl: raw.openssl,
+            restart_timeout: raw.restart_timeout,
+            services,
+            user_agent: None,
+        })
+    }
+}
+
+/// Check the installed certificate and key parse, and that the key is the certificate's
+fn check_command(openssl: &str, certificate_path: &str, private_key_path: &str) -> String {
+    let (openssl, certificate_path, private_key_path) = (shell_quote(openssl), shell_quote(certificate_path), shell_quote(private_key_path));
+
+    let script = format!(r#"set -e
+certificate=$({openssl} x509 -noout -pubkey -in {certificate_path})
+key=$({openssl} pkey -pubout -in {private_key_path})
+if [ "$certificate" != "$key" ]; then echo "the private key doesn't match the certificate" >&2; exit 1; fi"#);
+
+    format!("sh -c {}", shell_quote(&script))
+}
+
+pub async fn test_connection(config: &Config<Rc<CertificatePair>>) -> Result<()> {
+    crate::ssh::test_connection(&config.ssh_options).await
+}
+
+/// Check the directory the service reads its certificate from exists, i.e. that it's installed
+fn installed_command(target: &Target) -> String {
+    let directory = Path::new(&target.certificate_path).parent().and_then(Path::to_str).unwrap_or("/");
+
+    format!("test -d {}", shell_quote(directory))
+}
+
+pub async fn update_certificate(config: &Config<Rc<CertificatePair>>) -> Result<()> {
+    let chain = config.certificate.fullchain_certificate_pem_string()?;
+    let key = config.certificate.private_key_pem_string()?;
+
+    let handle = ssh_connect(&config.ssh_options).await?;
+    run_hooks(&handle, &config.ssh_options, Hook::Pre).await?;
+
+    let mut targets = Vec::new();
+    for target in &config.services {
+        match exec(&handle, &installed_command(target), &[]).await?.success() {
+            true => targets.push(target),
+            false => warn!("{} isn't installed on {}, skipping it", target.service, config.ssh_options.host()),
+        }
+    }
+
+    if targets.is_empty() {
+        return Err(RciError::RemoteRejected(anyhow!("none of the configured services are installed on {}", config.ssh_options.host())).into());
+    }
+
+    let files = targets.iter()
+        .flat_map(|target| [
+            deploy::File { path: &target.certificate_path, contents: chain.as_bytes(), attrs: FileAttributes::mode(0o644), owner: None, preserve: deploy::Preserve::default() },
+            deploy::File { path: &target.private_key_path, contents: key.as_bytes(), attrs: FileAttributes::mode(0o600), owner: None, preserve: deploy::Preserve::default() },
+        ])
+        .collect::<Vec<_>>();
+
+    let staged = deploy::stage(&handle, &files, config.ssh_options.transfer(Transfer::Sftp)).await?;
+
+    staged.commit().await?;
+
+    for target in &targets {
+        info!("checking the certificate and key installed for {}", target.service);
+        let check = timing::phase("check", exec(&handle, &check_command(&config.openssl, &target.certificate_path, &target.private_key_path), &[])).await?;
+        if !check.success() {
+            staged.rollback().await;
+
+            return Err(RciError::RemoteRejected(anyhow!("the certificate and key installed for {} failed their check, not restarting any service: {}", target.service, check.output_lossy())).into());
+        }
+    }
+
+    for target in &targets {
+        info!("restarting {}", target.service);
+        let restart = timing::phase("restart", exec(&handle, &target.restart_command, &[])).await
+            .and_then(|output| output.check(&format!("{} restart command", target.service)));
+        if let Err(e) = restart {
+            staged.rollback().await;
+
+            return Err(e);
+        }
+    }
+
+    staged.finish().await?;
+
+    let timeout = Duration::from_secs(config.restart_timeout);
+    for target in &targets {
+        let url = crate::verify::https_url(config.ssh_options.host(), target.port);
+        timing::phase("verify", crate::verify::wait_for_certificate(&url, config.address, config.user_agent.as_deref(), &config.certificate, RESTART_GRACE, timeout)).await?;
+    }
+
+    run_hooks(&handle, &config.ssh_options, Hook::Post).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check_command() {
+        let pair = crate::test_util::certificate_pair(&["unifi.example.net"]);
+        let other = crate::test_util::certificate_pair(&["unifi.example.net"]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let certificate_path = dir.path().join("unifi-core.crt");
+        let private_key_path = dir.path().join("unifi-core.key");
+        std::fs::write(&certificate_path, pair.fullchain_certificate_pem_string().unwrap()).unwrap();
+
+        let check = |key: &CertificatePair| {
+            std::fs::write(&private_key_path, key.private_key_pem_string().unwrap()).unwrap();
+
+            let command = check_command("openssl", certificate_path.to_str().unwrap(), private_key_path.to_str().unwrap());
+            std::process::Command::new("sh").arg("-c").arg(&command).output().unwrap()
+        };
+
+        assert!(check(&pair).status.success());
+
+        let output = check(&other);
+        assert!(!output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stderr).trim(), "the private key doesn't match the certificate");
+    }
+
+    #[test]
+    fn test_check_services() {
+        check_services(&[Service::Core, Service::Protect]).unwrap();
+
+        assert_eq!(check_services(&[]).unwrap_err().to_string(), "`services` must list at least one service");
+        assert_eq!(check_services(&[Service::Protect, Service::Core, Service::Protect]).unwrap_err().to_string(), "`services` lists unifi-protect more than once");
+
+        let services = toml::from_str::<std::collections::HashMap<String, Vec<Service>>>(r#"services = ["core", "protect"]"#).unwrap();
+        assert_eq!(services["services"], [Service::Core, Service::Protect]);
+    }
+}
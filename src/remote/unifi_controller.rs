@@ -0,0 +1,181 @@
+//! Self-hosted UniFi Network Controller
+//!
+//! The controller serves its web UI with a certificate from a Java keystore.
+//! The certificate pair is converted to PKCS#12 locally, copied over SSH, imported into the
+//! keystore with `keytool` under the `unifi` alias, and the controller is restarted.
+
+use std::{net::IpAddr, rc::Rc};
+
+use anyhow::{anyhow, Result};
+use serde::{de, Deserialize, Serialize};
+use tracing::info;
+use url::Url;
+
+use crate::{config::{CertificatePair, CertificateRef, GlobalCertificates}, ssh::{exec, run_hooks, shell_quote, ssh_connect, ConnectOptions, Hook}, timing};
+
+/// The keystore alias the controller loads its certificate from
+const KEYSTORE_ALIAS: &str = "unifi";
+
+#[derive(Deserialize, Debug)]
+struct RawConfig {
+    pub certificate: CertificateRef,
+
+    pub url: Url,
+
+    #[serde(rename = "ssh")]
+    pub ssh_config: crate::ssh::Config,
+
+    /// Connect to this IP address instead of resolving the URL's hostname
+    pub address: Option<IpAddr>,
+
+    #[serde(default = "RawConfig::default_keystore_path")]
+    pub keystore_path: String,
+
+    #[serde(default = "RawConfig::default_keystore_password")]
+    pub keystore_password: String,
+
+    #[serde(default = "RawConfig::default_keytool")]
+    pub keytool: String,
+
+    #[serde(default = "RawConfig::default_restart_command")]
+    pub restart_command: String,
+}
+
+impl RawConfig {
+    fn default_keystore_path() -> String {
+        "/usr/lib/unifi/data/keystore".to_string()
+    }
+
+    fn default_keystore_password() -> String {
+        // the password the controller hard-codes for its keystore
+        "aircontrolenterprise".to_string()
+    }
+
+    fn default_keytool() -> String {
+        "keytool".to_string()
+    }
+
+    fn default_restart_command() -> String {
+        "systemctl restart unifi".to_string()
+    }
+}
+
+/// This remote's section of the example config
+pub(crate) fn sample() -> String {
+    format!(r#"[unifi-controller.unifi]
+certificate = "default"
+{}# keystore_path = {}
+# keystore_password = {}
+# keytool = {}
+# restart_command = {}
+"#,
+        crate::sample::ssh("ssh://root@unifi.example.net"),
+        crate::sample::value(&RawConfig::default_keystore_path()),
+        crate::sample::value(&RawConfig::default_keystore_password()),
+        crate::sample::value(&RawConfig::default_keytool()),
+        crate::sample::value(&RawConfig::default_restart_command()))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Config<CertT> {
+    pub certificate: CertT,
+
+    #[serde(rename = "ssh")]
+    ssh_options: ConnectOptions,
+
+    keystore_path: String,
+    #[serde(skip)]
+    keystore_password: String,
+    keytool: String,
+    restart_command: String,
+}
+
+impl Config<CertificateRef> {
+    pub fn try_resolve_certificate(self, global_certs: &GlobalCertificates) -> Result<Config<Rc<CertificatePair>>> {
+        Ok(Config {
+            certificate: self.certificate.try_resolve(global_certs).map_err(|e| anyhow!("{e} for key `certificate`"))?,
+            ssh_options: self.ssh_options,
+            keystore_path: self.keystore_path,
+            keystore_password: self.keystore_password,
+            keytool: self.keytool,
+            restart_command: self.restart_command,
+        })
+    }
+}
+
+impl<CertT> Config<CertT> {
+    /// the controller web UI
+    pub fn default_verify_url(&self) -> Url {
+        crate::verify::https_url(self.ssh_options.host(), Some(8443))
+    }
+
+    /// Where the remote is managed through
+    pub fn endpoint(&self) -> Option<crate::probe::Endpoint> {
+        self.ssh_options.endpoint()
+    }
+}
+
+impl <'de> Deserialize<'de> for Config<CertificateRef> {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>
+    {
+        let raw = RawConfig::deserialize(deserializer)?;
+
+        let ssh_options = match raw.url.scheme() {
+            "ssh" => ConnectOptions::new(raw.url, &raw.ssh_config).map_err(de::Error::custom)?.with_address(raw.address),
+            other => return Err(de::Error::custom(format!("unknown protocol '{other}'"))),
+        };
+
+        // it's read as a line on the remote
+        if raw.keystore_password.contains('\n') {
+            return Err(de::Error::custom("`keystore_password` can't contain a newline"));
+        }
+
+        Ok(Config {
+            certificate: raw.certificate,
+            ssh_options,
+            keystore_path: raw.keystore_path,
+            keystore_password: raw.keystore_password,
+            keytool: raw.keytool,
+            restart_command: raw.restart_command,
+        })
+    }
+}
+
+pub async fn test_connection(config: &Config<Rc<CertificatePair>>) -> Result<()> {
+    crate::ssh::test_connection(&config.ssh_options).await
+}
+
+pub async fn update_certificate(config: &Config<Rc<CertificatePair>>) -> Result<()> {
+    let pkcs12 = config.certificate.pkcs12_der(KEYSTORE_ALIAS, &config.keystore_password)?;
+
+    // the password is sent on stdin ahead of the archive and handed to keytool through its environment,
+    // so it's on no command line. The archive is written to a private temporary file that's removed regardless of outcome
+    let import_script = format!(
+        "umask 077 && tmp=$(mktemp) && trap 'rm -f \"$tmp\"' EXIT && \
+        IFS= read -r RCI_STOREPASS && export RCI_STOREPASS && cat > \"$tmp\" && \
+        {keytool} -importkeystore -noprompt \
+            -srckeystore \"$tmp\" -srcstoretype PKCS12 -srcstorepass:env RCI_STOREPASS -srcalias {alias} \
+            -destkeystore {keystore} -deststorepass:env RCI_STOREPASS -destalias {alias}",
+        keytool = shell_quote(&config.keytool),
+        keystore = shell_quote(&config.keystore_path),
+        alias = KEYSTORE_ALIAS,
+    );
+
+    let mut stdin = format!("{}\n", config.keystore_password).into_bytes();
+    stdin.extend(pkcs12);
+
+    let handle = ssh_connect(&config.ssh_options).await?;
+    run_hooks(&handle, &config.ssh_options, Hook::Pre).await?;
+
+    info!("importing certificate into keystore {}", config.keystore_path);
+    timing::phase("upload", exec(&handle, &format!("sh -c {}", shell_quote(&import_script)), &stdin)).await?
+        .check("keystore import")?;
+
+    info!("restarting controller");
+    timing::phase("restart", exec(&handle, &config.restart_command, &[])).await?
+        .check("restart command")?;
+
+    run_hooks(&handle, &config.ssh_options, Hook::Post).await
+}
@@ -0,0 +1,166 @@
+//! Example configuration, printed by the `init` subcommand.
+//!
+//! Each remote type's section is written by its own module, beside its config, taking the
+//! defaults it shows from the functions the config itself uses.
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+
+use crate::remote;
+
+/// Every remote type, by its table name in the config file
+pub const REMOTE_TYPES: &[&str] = &[
+    "pfsense",
+    "megarac-bmc",
+    "unifi-controller",
+    "haproxy",
+    "nginx",
+    "redfish",
+    "pkcs12-file",
+    "file",
+    "caddy",
+    "homeassistant",
+    "openwrt",
+    "iis",
+    "udm",
+    "kubernetes",
+];
+
+/// The example host key. No real host presents it, so a copied example fails safe
+const EXAMPLE_HOST_KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIBA9inaj+wWq5ulmw2k3/wkG7umibQzdrnIiDdyRuzFj";
+
+const CERTS: &str = r#"# Certificates, which remotes refer to by name. Relative paths are resolved
+# against the directory of the config file.
+[certs.default]
+certificate_chain_path = "fullchain.pem"
+private_key_path = "privkey.pem"
+# certificate_chain_env = "CERT_CHAIN"   # instead of certificate_chain_path
+# private_key_env = "CERT_KEY"           # instead of private_key_path
+"#;
+
+const REMOTE_OPTIONS: &str = r#"# Every remote also accepts:
+# certificate = "auto"                         # the global certificate covering the hostname, instead of one by name
+# verify.url = "https://device.example.net/"   # where the installed certificate is served, if not the remote's default (https:// or tcp+tls://host:port)
+# address = "192.0.2.1"                        # connect to this address instead of resolving the hostname
+# hostnames = ["device.example.net"]           # names the certificate must cover
+# key_usage = "server"                         # or "client" or "both", what the certificate's extended key usage must allow
+# post_update_command = "/usr/local/bin/notify"
+# timeout = 600                                # abandon the update after this many seconds
+# min_intermediates = 1
+# max_chain_length = 5
+# skip_precheck = true                         # install the certificate even if it fails its checks, in an emergency
+"#;
+
+/// An example config with a certificate and a section for `remote_type`, or for every remote type
+pub fn sample(remote_type: Option<&str>) -> Result<String> {
+    let types = match remote_type {
+        Some(remote_type) if !REMOTE_TYPES.contains(&remote_type) => bail!("unknown remote type `{remote_type}` (expected one of {})", REMOTE_TYPES.join(", ")),
+        Some(remote_type) => vec![remote_type],
+        None => REMOTE_TYPES.to_vec(),
+    };
+
+    let mut sample = CERTS.to_string();
+
+    for remote_type in types {
+        let section = match remote_type {
+            "pfsense" => remote::pfsense::sample(),
+            "megarac-bmc" => remote::megarac::sample(),
+            "unifi-controller" => remote::unifi_controller::sample(),
+            "haproxy" => remote::haproxy::sample(),
+            "nginx" => remote::nginx::sample(),
+            "redfish" => remote::redfish::sample(),
+            "pkcs12-file" => remote::file_pkcs12::sample(),
+            "file" => remote::file_pem::sample(),
+            "caddy" => remote::caddy::sample(),
+            "homeassistant" => remote::homeassistant::sample(),
+            "openwrt" => remote::openwrt::sample(),
+            "iis" => remote::winrm::sample(),
+            "udm" => remote::udm::sample(),
+            "kubernetes" => remote::kubernetes::sample(),
+            other => unreachable!("no sample for remote type `{other}`"),
+        };
+
+        sample += "\n";
+        sample += &section;
+    }
+
+    sample += "\n";
+    sample += REMOTE_OPTIONS;
+
+    Ok(sample)
+}
+
+/// `value` as TOML, for showing defaults
+pub(crate) fn value<T: Serialize>(value: &T) -> String {
+    toml::Value::try_from(value).expect("default representable in TOML").to_string()
+}
+
+/// The keys of a remote managed over SSH at `url`
+pub(crate) fn ssh(url: &str) -> String {
+    format!(r#"url = {}
+ssh.private_key_file = "id_ed25519"
+# the server's public key, or an array of keys any of which is accepted
+ssh.host_key = {}
+# ssh.keepalive_interval = 30
+# ssh.username = "admin"                     # when the URL has none
+# ssh.proxy = "socks5h://bastion:1080"
+# ssh.password_file = "ssh-password"        # for password authentication
+# ssh.auth = ["agent", "key", "password"]   # the methods to try, in order (default: key, then password)
+# ssh.transfer = "scp"                      # or "sftp" or "exec-cat", how files are uploaded if not the remote's default
+# ssh.log_banner = true                     # log the server's login banner at info level, e.g. for audit records
+# ssh.pre_commands = ["service app stop"]   # run before installing, failing the update if one fails
+# ssh.post_commands = ["healthcheck"]       # run after reloading; or {{ command = "...", ignore_failure = true }}
+# ssh.host_key_algorithms = ["ssh-ed25519"] # allowed, in order; also kex_algorithms, ciphers and macs
+"#, value(&url), value(&EXAMPLE_HOST_KEY))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::load_config_str;
+
+    use super::*;
+
+    #[test]
+    #[allow(clippy::result_large_err)]
+    fn test_samples_load() {
+        let pair = crate::test_util::certificate_pair(&["device.example.net"]);
+
+        let mut key = Vec::new();
+        russh_keys::encode_pkcs8_pem(&russh_keys::key::KeyPair::generate_ed25519().unwrap(), &mut key).unwrap();
+
+        figment::Jail::expect_with(|jail| {
+            jail.create_file("fullchain.pem", &pair.fullchain_certificate_pem_string().unwrap())?;
+            jail.create_file("privkey.pem", &pair.private_key_pem_string().unwrap())?;
+            jail.create_file("id_ed25519", std::str::from_utf8(&key).unwrap())?;
+            for secret in ["bmc-password", "idrac-password", "ha-token", "winrm-password"] {
+                jail.create_file(secret, "secret\n")?;
+            }
+            jail.create_file("kubeconfig", "clusters:
+- cluster:
+    server: https://k8s.example.net:6443
+  name: cluster
+contexts:
+- context:
+    cluster: cluster
+    user: admin
+  name: admin@cluster
+users:
+- name: admin
+  user:
+    token: secret
+")?;
+
+            let config = load_config_str(&sample(None).unwrap()).unwrap();
+            assert_eq!(config.remotes.len(), REMOTE_TYPES.len());
+
+            let sample = sample(Some("nginx")).unwrap();
+            let config = load_config_str(&sample).unwrap();
+            assert_eq!(config.remotes.keys().collect::<Vec<_>>(), ["nginx.web"]);
+            assert!(sample.contains("# reload_command = \"systemctl reload nginx\""), "{sample}");
+
+            Ok(())
+        });
+
+        assert!(sample(Some("telnet")).is_err());
+    }
+}
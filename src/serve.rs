@@ -0,0 +1,233 @@
+//! An HTTP endpoint for triggering deploys over the network (the `serve` subcommand), rather than from cron.
+//!
+//! `POST /deploy?remote=<name>&remote=...` (or without `remote`, for every remote) runs an update and
+//! responds with its result as JSON. Requests must carry `Authorization: Bearer <token>`.
+//! Requests are handled one at a time. There's no TLS, so the server should listen on localhost
+//! or behind a reverse proxy.
+//!
+//! ```toml
+//! [serve]
+//! listen = "127.0.0.1:8700"
+//! token_file = "$CREDENTIALS_DIRECTORY/serve-token"
+//! ```
+
+use std::{future::Future, net::SocketAddr, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use serde_json::{json, Value};
+use tokio::{io::AsyncWriteExt, net::{TcpListener, TcpStream}};
+use tracing::{debug, info, warn};
+use url::Url;
+
+use crate::config::{CredentialPathBuf, SecretSource};
+
+/// How long a client has to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+const MAX_REQUEST_SIZE: usize = 16 * 1024;
+
+#[derive(Deserialize)]
+struct RawConfig {
+    #[serde(default = "RawConfig::default_listen")]
+    listen: SocketAddr,
+
+    token_file: Option<CredentialPathBuf>,
+
+    /// environment variable containing the token, instead of `token_file`
+    token_env: Option<String>,
+}
+
+impl RawConfig {
+    fn default_listen() -> SocketAddr {
+        "127.0.0.1:8700".parse().expect("valid listen address")
+    }
+}
+
+/// The `[serve]` table
+#[derive(Serialize, Debug, Clone)]
+pub struct Config {
+    pub listen: SocketAddr,
+
+    #[serde(serialize_with = "crate::config::serialize_redacted")]
+    token: Option<String>,
+}
+
+impl<'de> Deserialize<'de> for Config {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>
+    {
+        let raw = RawConfig::deserialize(deserializer)?;
+
+        let source = SecretSource::from_keys(raw.token_file, raw.token_env, "token_file", "token_env")
+            .map_err(de::Error::custom)?
+            .ok_or_else(|| de::Error::missing_field("token_file"))?;
+
+        let token = source.read_to_string()
+            .map(|t| t.trim().to_string())
+            .map_err(|e| de::Error::custom(format!("failed to read token {source} ({e:#})")))?;
+
+        if token.is_empty() {
+            return Err(de::Error::custom(format!("token {source} is empty")));
+        }
+
+        Ok(Config { listen: raw.listen, token: Some(token) })
+    }
+}
+
+/// A parsed request. Only what's needed to route it is kept
+#[derive(Debug, PartialEq, Eq)]
+struct Request {
+    method: String,
+    path: String,
+    remotes: Vec<String>,
+    authorization: Option<String>,
+}
+
+fn parse_request(request: &[u8]) -> Result<Request> {
+    let request = std::str::from_utf8(request).context("request isn't valid UTF-8")?;
+    let mut lines = request.split("\r\n");
+
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(target)) = (request_line.next(), request_line.next()) else {
+        bail!("malformed request line");
+    };
+
+    let url = Url::parse("http://localhost/").expect("valid base URL").join(target).context("malformed request target")?;
+
+    let authorization = lines
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("authorization"))
+        .map(|(_, value)| value.trim().to_string());
+
+    Ok(Request {
+        method: method.to_string(),
+        path: url.path().to_string(),
+        remotes: url.query_pairs().filter(|(k, _)| k == "remote").map(|(_, v)| v.into_owned()).collect(),
+        authorization,
+    })
+}
+
+/// Whether the `Authorization` header carries `token`, compared in constant time
+fn is_authorized(authorization: Option<&str>, token: &str) -> bool {
+    let Some(presented) = authorization.and_then(|a| a.strip_prefix("Bearer ")) else {
+        return false;
+    };
+
+    presented.len() == token.len() && openssl::memcmp::eq(presented.as_bytes(), token.as_bytes())
+}
+
+async fn read_request(stream: &mut TcpStream) -> Result<Request> {
+    parse_request(&crate::http::read_request_head(stream, MAX_REQUEST_SIZE).await?)
+}
+
+async fn respond(stream: &mut TcpStream, status: u16, body: &Value) -> Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        _ => "",
+    };
+
+    let body = body.to_string();
+    let response = format!("HTTP/1.1 {status} {reason}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len());
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+/// Serve deploy requests until `shutdown` completes. A deploy in progress is finished first.
+///
+/// `deploy` is called with the requested remote names (empty for every remote), and returns
+/// the response's status and JSON body.
+pub async fn serve<F, Fut>(config: &Config, shutdown: impl Future<Output = ()>, mut deploy: F) -> Result<()>
+where
+    F: FnMut(Vec<String>) -> Fut,
+    Fut: Future<Output = (u16, Value)>,
+{
+    let token = config.token.as_deref().unwrap_or_default();
+
+    let listener = TcpListener::bind(config.listen).await
+        .with_context(|| format!("failed to listen on {}", config.listen))?;
+
+    info!("listening for deploy requests on {}", config.listen);
+    crate::systemd::ready(&format!("listening on {}", config.listen));
+
+    tokio::pin!(shutdown);
+
+    loop {
+        let (mut stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted.context("failed to accept connection")?,
+            () = &mut shutdown => return Ok(()),
+        };
+
+        let request = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+            Ok(Ok(request)) => request,
+            Ok(Err(e)) => {
+                debug!("bad request from {peer}: {e:#}");
+                let _ = respond(&mut stream, 400, &json!({ "error": format!("{e:#}") })).await;
+                continue;
+            },
+            Err(_) => {
+                debug!("request from {peer} timed out");
+                continue;
+            },
+        };
+
+        let (status, body) = match (request.method.as_str(), request.path.as_str()) {
+            (_, path) if path != "/deploy" => (404, json!({ "error": "not found" })),
+            (method, _) if method != "POST" => (405, json!({ "error": "only POST is supported" })),
+            _ if !is_authorized(request.authorization.as_deref(), token) => {
+                warn!("unauthorized deploy request from {peer}");
+                (401, json!({ "error": "unauthorized" }))
+            },
+            _ => {
+                info!("deploy requested by {peer} for {}", match request.remotes.is_empty() {
+                    true => "every remote".to_string(),
+                    false => request.remotes.join(", "),
+                });
+
+                deploy(request.remotes).await
+            },
+        };
+
+        if let Err(e) = respond(&mut stream, status, &body).await {
+            warn!("failed to respond to {peer}: {e:#}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        let request = parse_request(b"POST /deploy?remote=nginx.web&remote=megarac-bmc.bmc HTTP/1.1\r\nHost: localhost\r\nauthorization: Bearer hunter2\r\n\r\n").unwrap();
+        assert_eq!(request, Request {
+            method: "POST".to_string(),
+            path: "/deploy".to_string(),
+            remotes: vec!["nginx.web".to_string(), "megarac-bmc.bmc".to_string()],
+            authorization: Some("Bearer hunter2".to_string()),
+        });
+
+        let request = parse_request(b"GET /deploy HTTP/1.1\r\n\r\n").unwrap();
+        assert!(request.remotes.is_empty());
+        assert_eq!(request.authorization, None);
+
+        assert!(parse_request(b"\r\n\r\n").is_err());
+
+        assert!(is_authorized(Some("Bearer hunter2"), "hunter2"));
+        assert!(!is_authorized(Some("Bearer hunter"), "hunter2"));
+        assert!(!is_authorized(Some("Basic hunter2"), "hunter2"));
+        assert!(!is_authorized(None, "hunter2"));
+    }
+}
@@ -0,0 +1,401 @@
+//! A minimal SFTP (version 3) client for uploading files over an existing SSH connection.
+//!
+//! Only what's needed to write a file with a given mode and ownership is implemented.
+//! Requests are issued one at a time.
+
+use anyhow::{anyhow, bail, Context, Result};
+use russh::client::Handle;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::debug;
+
+use crate::{error::RciError, ssh::ClientHandler};
+
+const VERSION: u32 = 3;
+
+const FXP_INIT: u8 = 1;
+const FXP_VERSION: u8 = 2;
+const FXP_OPEN: u8 = 3;
+const FXP_CLOSE: u8 = 4;
+const FXP_WRITE: u8 = 6;
+const FXP_FSETSTAT: u8 = 10;
+const FXP_STATUS: u8 = 101;
+const FXP_HANDLE: u8 = 102;
+
+const FXF_WRITE: u32 = 0x02;
+const FXF_CREAT: u32 = 0x08;
+const FXF_TRUNC: u32 = 0x10;
+
+const ATTR_UIDGID: u32 = 0x02;
+const ATTR_PERMISSIONS: u32 = 0x04;
+
+const FX_OK: u32 = 0;
+
+/// Servers must accept writes of at least this size
+const MAX_WRITE: usize = 32 * 1024;
+
+/// Mode and (optionally) numeric ownership for an uploaded file
+#[derive(Debug, Clone, Copy)]
+pub struct FileAttributes {
+    pub mode: u32,
+    pub owner: Option<(u32, u32)>,
+}
+
+impl FileAttributes {
+    pub fn mode(mode: u32) -> Self {
+        FileAttributes { mode, owner: None }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let mut flags = ATTR_PERMISSIONS;
+        if self.owner.is_some() {
+            flags |= ATTR_UIDGID;
+        }
+
+        put_u32(buf, flags);
+
+        if let Some((uid, gid)) = self.owner {
+            put_u32(buf, uid);
+            put_u32(buf, gid);
+        }
+
+        put_u32(buf, self.mode);
+    }
+}
+
+/// Upload `data` to `path` on the remote, replacing any existing file.
+///
+/// The mode is also set after the file is opened, as the server's umask applies on creation.
+pub async fn upload(handle: &Handle<ClientHandler>, path: &str, data: &[u8], attributes: FileAttributes) -> Result<()> {
+    let channel = handle.channel_open_session().await?;
+    channel.request_subsystem(true, "sftp").await
+        .context("failed to start the SFTP subsystem (for hosts without an SFTP server, set `ssh.transfer` to \"scp\" or \"exec-cat\")")?;
+
+    let mut sftp = Sftp::init(channel.into_stream()).await?;
+
+    debug!("uploading {} bytes to {path}", data.len());
+    sftp.write_file(path, data, attributes).await
+        .with_context(|| format!("failed to upload {path}"))
+}
+
+struct Sftp<S> {
+    stream: S,
+    next_id: u32,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Sftp<S> {
+    async fn init(stream: S) -> Result<Self> {
+        let mut sftp = Sftp { stream, next_id: 0 };
+
+        let mut packet = Vec::new();
+        put_u32(&mut packet, VERSION);
+        sftp.send(FXP_INIT, &packet).await?;
+
+        let (kind, body) = sftp.receive().await?;
+        if kind != FXP_VERSION {
+            bail!("unexpected SFTP response to init (type {kind})");
+        }
+
+        let version = Reader(&body).u32()?;
+        if version < VERSION {
+            bail!("unsupported SFTP version {version}");
+        }
+
+        Ok(sftp)
+    }
+
+    async fn write_file(&mut self, path: &str, data: &[u8], attributes: FileAttributes) -> Result<()> {
+        let handle = self.open(path, attributes).await?;
+
+        let result = async {
+            let mut request = Vec::new();
+            put_string(&mut request, &handle);
+            attributes.encode(&mut request);
+            self.request(FXP_FSETSTAT, &request).await?.status()
+                .context("failed to set file attributes")?;
+
+            for (i, chunk) in data.chunks(MAX_WRITE).enumerate() {
+                let mut request = Vec::new();
+                put_string(&mut request, &handle);
+                put_u64(&mut request, (i * MAX_WRITE) as u64);
+                put_string(&mut request, chunk);
+                self.request(FXP_WRITE, &request).await?.status()
+                    .context("write failed")?;
+            }
+
+            Ok(())
+        }.await;
+
+        // closing also flushes the file, so its failure matters even if the writes succeeded
+        let mut request = Vec::new();
+        put_string(&mut request, &handle);
+        let closed = self.request(FXP_CLOSE, &request).await
+            .and_then(Response::status)
+            .context("failed to close file");
+
+        result.and(closed)
+    }
+
+    async fn open(&mut self, path: &str, attributes: FileAttributes) -> Result<Vec<u8>> {
+        let mut request = Vec::new();
+        put_string(&mut request, path.as_bytes());
+        put_u32(&mut request, FXF_WRITE | FXF_CREAT | FXF_TRUNC);
+        attributes.encode(&mut request);
+
+        match self.request(FXP_OPEN, &request).await? {
+            Response { kind: FXP_HANDLE, body } => Ok(Reader(&body).string()?.to_vec()),
+            response => {
+                response.status().context("failed to open file")?;
+                bail!("unexpected SFTP response to open");
+            },
+        }
+    }
+
+    /// Send a request and wait for its response
+    async fn request(&mut self, kind: u8, body: &[u8]) -> Result<Response> {
+        self.next_id = self.next_id.wrapping_add(1);
+        let id = self.next_id;
+
+        let mut packet = Vec::with_capacity(4 + body.len());
+        put_u32(&mut packet, id);
+        packet.extend_from_slice(body);
+        self.send(kind, &packet).await?;
+
+        let (kind, body) = self.receive().await?;
+
+        let mut reader = Reader(&body);
+        let response_id = reader.u32()?;
+        if response_id != id {
+            bail!("SFTP response id {response_id} doesn't match request id {id}");
+        }
+
+        Ok(Response { kind, body: reader.0.to_vec() })
+    }
+
+    async fn send(&mut self, kind: u8, body: &[u8]) -> Result<()> {
+        let mut packet = Vec::with_capacity(5 + body.len());
+        put_u32(&mut packet, (body.len() + 1) as u32);
+        packet.push(kind);
+        packet.extend_from_slice(body);
+
+        self.stream.write_all(&packet).await?;
+        self.stream.flush().await?;
+
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> Result<(u8, Vec<u8>)> {
+        let len = self.stream.read_u32().await.context("SFTP channel closed")? as usize;
+        if len == 0 || len > 256 * 1024 {
+            bail!("invalid SFTP packet length {len}");
+        }
+
+        let kind = self.stream.read_u8().await?;
+        let mut body = vec![0u8; len - 1];
+        self.stream.read_exact(&mut body).await?;
+
+        Ok((kind, body))
+    }
+}
+
+/// A response, minus its request id
+struct Response {
+    kind: u8,
+    body: Vec<u8>,
+}
+
+impl Response {
+    /// Succeed if the response is an `SSH_FX_OK` status
+    fn status(self) -> Result<()> {
+        if self.kind != FXP_STATUS {
+            bail!("unexpected SFTP response (type {})", self.kind);
+        }
+
+        let mut reader = Reader(&self.body);
+        let code = reader.u32()?;
+        let message = reader.string().map(|m| String::from_utf8_lossy(m).into_owned()).unwrap_or_default();
+
+        if code != FX_OK {
+            return Err(RciError::RemoteRejected(anyhow!("{} (SFTP status {code})", status_message(code, &message))).into());
+        }
+
+        Ok(())
+    }
+}
+
+fn status_message(code: u32, message: &str) -> &str {
+    if !message.is_empty() {
+        return message;
+    }
+
+    match code {
+        2 => "no such file",
+        3 => "permission denied",
+        4 => "failure",
+        8 => "operation unsupported",
+        _ => "error",
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn u32(&mut self) -> Result<u32> {
+        let (bytes, rest) = self.0.split_first_chunk::<4>().context("truncated SFTP packet")?;
+        self.0 = rest;
+
+        Ok(u32::from_be_bytes(*bytes))
+    }
+
+    fn string(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        if len > self.0.len() {
+            bail!("truncated SFTP packet");
+        }
+
+        let (s, rest) = self.0.split_at(len);
+        self.0 = rest;
+
+        Ok(s)
+    }
+}
+
+fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    buf.extend_from_slice(&v.to_be_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, v: u64) {
+    buf.extend_from_slice(&v.to_be_bytes());
+}
+
+fn put_string(buf: &mut Vec<u8>, s: &[u8]) {
+    put_u32(buf, s.len() as u32);
+    buf.extend_from_slice(s);
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use tokio::io::DuplexStream;
+
+    use super::*;
+
+    /// uploaded path -> (contents, mode)
+    type Files = HashMap<Vec<u8>, (Vec<u8>, u32)>;
+
+    /// Just enough of an SFTP server to accept uploads into memory
+    struct FakeServer {
+        stream: Sftp<DuplexStream>,
+        files: Files,
+        deny_open: bool,
+    }
+
+    impl FakeServer {
+        async fn run(mut self) -> Files {
+            let mut open: Option<Vec<u8>> = None;
+
+            while let Ok((kind, body)) = self.stream.receive().await {
+                let mut reader = Reader(&body);
+
+                if kind == FXP_INIT {
+                    let mut version = Vec::new();
+                    put_u32(&mut version, VERSION);
+                    self.stream.send(FXP_VERSION, &version).await.unwrap();
+                    continue;
+                }
+
+                let id = reader.u32().unwrap();
+                let mut response = Vec::new();
+                put_u32(&mut response, id);
+
+                let status = |response: &mut Vec<u8>, code| {
+                    put_u32(response, code);
+                    put_string(response, b"");
+                    put_string(response, b"");
+                };
+
+                match kind {
+                    FXP_OPEN if self.deny_open => {
+                        status(&mut response, 3);
+                        self.stream.send(FXP_STATUS, &response).await.unwrap();
+                    },
+                    FXP_OPEN => {
+                        let path = reader.string().unwrap().to_vec();
+                        assert_eq!(reader.u32().unwrap(), FXF_WRITE | FXF_CREAT | FXF_TRUNC);
+                        self.files.insert(path.clone(), (Vec::new(), 0));
+                        open = Some(path);
+
+                        put_string(&mut response, b"h1");
+                        self.stream.send(FXP_HANDLE, &response).await.unwrap();
+                    },
+                    FXP_FSETSTAT => {
+                        assert_eq!(reader.string().unwrap(), b"h1");
+                        let flags = reader.u32().unwrap();
+                        assert_eq!(flags, ATTR_PERMISSIONS);
+                        let mode = reader.u32().unwrap();
+                        self.files.get_mut(open.as_ref().unwrap()).unwrap().1 = mode;
+
+                        status(&mut response, FX_OK);
+                        self.stream.send(FXP_STATUS, &response).await.unwrap();
+                    },
+                    FXP_WRITE => {
+                        assert_eq!(reader.string().unwrap(), b"h1");
+                        let offset = ((reader.u32().unwrap() as u64) << 32) | reader.u32().unwrap() as u64;
+                        let data = reader.string().unwrap();
+
+                        let file = &mut self.files.get_mut(open.as_ref().unwrap()).unwrap().0;
+                        assert_eq!(offset as usize, file.len());
+                        file.extend_from_slice(data);
+
+                        status(&mut response, FX_OK);
+                        self.stream.send(FXP_STATUS, &response).await.unwrap();
+                    },
+                    FXP_CLOSE => {
+                        open = None;
+                        status(&mut response, FX_OK);
+                        self.stream.send(FXP_STATUS, &response).await.unwrap();
+                    },
+                    other => panic!("unexpected request type {other}"),
+                }
+            }
+
+            self.files
+        }
+    }
+
+    fn server(deny_open: bool) -> (DuplexStream, tokio::task::JoinHandle<Files>) {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+
+        let server = FakeServer { stream: Sftp { stream: server, next_id: 0 }, files: HashMap::new(), deny_open };
+
+        (client, tokio::spawn(server.run()))
+    }
+
+    #[tokio::test]
+    async fn test_write_file() {
+        let (client, server) = server(false);
+
+        // spans several writes, and isn't valid UTF-8
+        let data = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+        let mut sftp = Sftp::init(client).await.unwrap();
+        sftp.write_file("/etc/ssl/it's a file.pem", &data, FileAttributes::mode(0o600)).await.unwrap();
+        drop(sftp);
+
+        let files = server.await.unwrap();
+        let (written, mode) = &files[b"/etc/ssl/it's a file.pem".as_slice()];
+        assert_eq!(written, &data);
+        assert_eq!(*mode, 0o600);
+    }
+
+    #[tokio::test]
+    async fn test_write_file_denied() {
+        let (client, _server) = server(true);
+
+        let mut sftp = Sftp::init(client).await.unwrap();
+        let e = sftp.write_file("/etc/ssl/cert.pem", b"", FileAttributes::mode(0o600)).await.unwrap_err();
+
+        assert!(format!("{e:#}").contains("permission denied"), "{e:#}");
+        assert!(matches!(RciError::classify(e), RciError::RemoteRejected(_)));
+    }
+}
@@ -0,0 +1,261 @@
+//! A minimal SOCKS5 client (RFC 1928, with RFC 1929 username/password authentication)
+//!
+//! `socks5://` proxies are given a resolved address, `socks5h://` proxies resolve the hostname themselves.
+
+use std::net::IpAddr;
+
+use anyhow::{bail, Context, Result};
+use percent_encoding::percent_decode_str;
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{lookup_host, TcpStream}};
+use url::Url;
+
+const VERSION: u8 = 0x05;
+
+const AUTH_NONE: u8 = 0x00;
+const AUTH_USERNAME_PASSWORD: u8 = 0x02;
+const AUTH_NO_ACCEPTABLE: u8 = 0xff;
+
+const CMD_CONNECT: u8 = 0x01;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// Is `url` a proxy URL this module can connect through?
+pub fn is_supported(url: &Url) -> bool {
+    matches!(url.scheme(), "socks5" | "socks5h")
+}
+
+/// Open a TCP connection to `host`:`port` via the SOCKS5 proxy at `proxy`
+pub async fn connect(proxy: &Url, host: &str, port: u16) -> Result<TcpStream> {
+    let remote_dns = match proxy.scheme() {
+        "socks5" => false,
+        "socks5h" => true,
+        other => bail!("unsupported proxy protocol '{other}'"),
+    };
+
+    let proxy_host = proxy.host_str().context("proxy URL must include a host")?;
+    let proxy_port = proxy.port().unwrap_or(1080);
+
+    let mut stream = TcpStream::connect((proxy_host, proxy_port)).await
+        .with_context(|| format!("failed to connect to proxy {proxy_host}:{proxy_port}"))?;
+
+    // the target address, as sent in the CONNECT request
+    let target = match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => Target::Ip(ip),
+        Err(_) if remote_dns => Target::Domain(host),
+        Err(_) => {
+            let addr = lookup_host((host, port)).await
+                .with_context(|| format!("failed to lookup address for {host}"))?
+                .next()
+                .with_context(|| format!("no addresses found for {host}"))?;
+
+            Target::Ip(addr.ip())
+        }
+    };
+
+    handshake(&mut stream, proxy, &target, port).await
+        .with_context(|| format!("SOCKS5 negotiation with proxy {proxy_host}:{proxy_port} failed"))?;
+
+    Ok(stream)
+}
+
+enum Target<'a> {
+    Ip(IpAddr),
+    Domain(&'a str),
+}
+
+async fn handshake<S>(stream: &mut S, proxy: &Url, target: &Target<'_>, port: u16) -> Result<()>
+where
+    S: AsyncReadExt + AsyncWriteExt + Unpin
+{
+    let credentials = match proxy.username() {
+        "" => None,
+        username => Some((username, proxy.password().unwrap_or_default())),
+    };
+
+    // method selection
+    match credentials {
+        Some(_) => stream.write_all(&[VERSION, 2, AUTH_NONE, AUTH_USERNAME_PASSWORD]).await?,
+        None => stream.write_all(&[VERSION, 1, AUTH_NONE]).await?,
+    }
+
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+
+    if reply[0] != VERSION {
+        bail!("proxy is not a SOCKS5 server");
+    }
+
+    match (reply[1], credentials) {
+        (AUTH_NONE, _) => {},
+        (AUTH_USERNAME_PASSWORD, Some((username, password))) => {
+            let username = percent_decode_str(username).collect::<Vec<_>>();
+            let password = percent_decode_str(password).collect::<Vec<_>>();
+
+            if username.len() > 255 || password.len() > 255 {
+                bail!("proxy username and password must each be at most 255 bytes");
+            }
+
+            let mut request = vec![0x01, username.len() as u8];
+            request.extend_from_slice(&username);
+            request.push(password.len() as u8);
+            request.extend_from_slice(&password);
+            stream.write_all(&request).await?;
+
+            let mut reply = [0u8; 2];
+            stream.read_exact(&mut reply).await?;
+
+            if reply[1] != 0x00 {
+                bail!("proxy rejected the username/password");
+            }
+        },
+        (AUTH_NO_ACCEPTABLE, _) => bail!("proxy requires an authentication method that isn't supported"),
+        (other, _) => bail!("proxy selected an unexpected authentication method ({other:#04x})"),
+    }
+
+    // connect request
+    let mut request = vec![VERSION, CMD_CONNECT, 0x00];
+    match target {
+        Target::Ip(IpAddr::V4(ip)) => {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        },
+        Target::Ip(IpAddr::V6(ip)) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        },
+        Target::Domain(domain) => {
+            if domain.len() > 255 {
+                bail!("hostname too long for SOCKS5");
+            }
+
+            request.push(ATYP_DOMAIN);
+            request.push(domain.len() as u8);
+            request.extend_from_slice(domain.as_bytes());
+        },
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+
+    if reply[1] != 0x00 {
+        bail!("proxy refused the connection: {}", reply_message(reply[1]));
+    }
+
+    // discard the bound address
+    let address_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => stream.read_u8().await? as usize,
+        other => bail!("proxy replied with an unknown address type ({other:#04x})"),
+    };
+    let mut bound = vec![0u8; address_len + 2];
+    stream.read_exact(&mut bound).await?;
+
+    Ok(())
+}
+
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        0x01 => "general SOCKS server failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+/// The proxy to use for connections to `host` from the environment (`ALL_PROXY`),
+/// unless `host` is excluded by `NO_PROXY`.
+pub fn proxy_from_env(host: &str) -> Option<Url> {
+    let var = |name: &str| std::env::var(name).or_else(|_| std::env::var(name.to_lowercase())).ok();
+
+    let proxy = var("ALL_PROXY").filter(|p| !p.is_empty())?;
+    let proxy = Url::parse(&proxy).ok().filter(is_supported)?;
+
+    match var("NO_PROXY") {
+        Some(no_proxy) if no_proxy_matches(&no_proxy, host) => None,
+        _ => Some(proxy),
+    }
+}
+
+/// Does the comma-separated `NO_PROXY` list exclude `host`?
+pub(crate) fn no_proxy_matches(no_proxy: &str, host: &str) -> bool {
+    let host = host.trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase();
+
+    no_proxy.split(',')
+        .map(|entry| entry.trim().trim_start_matches('[').trim_end_matches(']').to_ascii_lowercase())
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| {
+            if entry == "*" {
+                return true;
+            }
+
+            let domain = entry.trim_start_matches('.');
+
+            host == domain || host.ends_with(&format!(".{domain}"))
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn test_no_proxy_matches() {
+        assert!(no_proxy_matches("*", "anything"));
+        assert!(no_proxy_matches("example.net", "example.net"));
+        assert!(no_proxy_matches("example.net", "host.example.net"));
+        assert!(no_proxy_matches(".example.net, other", "host.example.net"));
+        assert!(no_proxy_matches("::1", "[::1]"));
+        assert!(!no_proxy_matches("example.net", "badexample.net"));
+        assert!(!no_proxy_matches("", "example.net"));
+    }
+
+    #[tokio::test]
+    async fn test_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (mut s, _) = listener.accept().await.unwrap();
+
+            let mut greeting = [0u8; 4];
+            s.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [VERSION, 2, AUTH_NONE, AUTH_USERNAME_PASSWORD]);
+            s.write_all(&[VERSION, AUTH_USERNAME_PASSWORD]).await.unwrap();
+
+            let mut auth = [0u8; 1 + 1 + 4 + 1 + 6];
+            s.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth[..], b"\x01\x04user\x06p@ss w");
+            s.write_all(&[0x01, 0x00]).await.unwrap();
+
+            let mut request = [0u8; 4 + 1 + 11 + 2];
+            s.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request[..5], &[VERSION, CMD_CONNECT, 0x00, ATYP_DOMAIN, 11]);
+            assert_eq!(&request[5..16], b"example.net");
+            assert_eq!(&request[16..], &22u16.to_be_bytes());
+            s.write_all(&[VERSION, 0x00, 0x00, ATYP_IPV4, 10, 0, 0, 1, 0, 22]).await.unwrap();
+
+            s.write_all(b"SSH-2.0-test\r\n").await.unwrap();
+        });
+
+        let proxy = Url::parse(&format!("socks5h://user:p%40ss%20w@{proxy_addr}")).unwrap();
+        let mut stream = connect(&proxy, "example.net", 22).await.unwrap();
+
+        let mut banner = String::new();
+        stream.read_to_string(&mut banner).await.unwrap();
+        assert_eq!(banner, "SSH-2.0-test\r\n");
+
+        server.await.unwrap();
+    }
+}
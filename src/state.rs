@@ -0,0 +1,141 @@
+//! A local record of what was last deployed to each remote.
+//!
+//! When `state_file` is configured, the fingerprints and expiry of the certificates
+//! successfully installed on each remote are written to it as JSON.
+//! A remote whose configured certificates match its record, and whose certificate files haven't
+//! been modified since, is skipped without being contacted.
+
+use std::{collections::BTreeMap, path::Path, time::SystemTime};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::{config::CertificatePair, file::write_atomic};
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct State {
+    #[serde(default)]
+    remotes: BTreeMap<String, Deployed>,
+}
+
+/// The certificates last deployed to a remote
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Deployed {
+    /// of each certificate installed on the remote, in config order
+    pub sha256_fingerprints: Vec<String>,
+
+    /// the earliest expiry of those certificates
+    pub not_after: String,
+
+    /// when each certificate's files had last been modified, if they were read from files.
+    /// Empty in state files written before this was recorded.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub modified: Vec<Option<String>>,
+
+    pub deployed_at: String,
+}
+
+impl State {
+    /// Read the state file at `path`. A missing file is an empty state.
+    pub fn load(path: &Path) -> Result<State> {
+        let json = match std::fs::read(path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(State::default()),
+            Err(e) => return Err(e).with_context(|| format!("failed to read state file \"{}\"", path.display())),
+        };
+
+        serde_json::from_slice(&json).with_context(|| format!("failed to parse state file \"{}\"", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_vec_pretty(self).context("failed to serialize state")?;
+
+        write_atomic(path, &json, 0o644)
+    }
+
+    pub fn get(&self, remote: &str) -> Option<&Deployed> {
+        self.remotes.get(remote)
+    }
+
+    /// Record that `certificates` were deployed to `remote` just now
+    pub fn record(&mut self, remote: &str, certificates: &[&CertificatePair]) -> Result<()> {
+        let summaries = certificates.iter()
+            .map(|c| c.summary())
+            .collect::<Result<Vec<_>>>()?;
+
+        let not_after = certificates.iter()
+            .map(|c| c.not_after())
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .min()
+            .map(format_time)
+            .transpose()?
+            .unwrap_or_default();
+
+        self.remotes.insert(remote.to_string(), Deployed {
+            sha256_fingerprints: summaries.into_iter().map(|s| s.sha256_fingerprint).collect(),
+            not_after,
+            modified: modified(certificates)?,
+            deployed_at: OffsetDateTime::now_utc().format(&Rfc3339)?,
+        });
+
+        Ok(())
+    }
+}
+
+/// The fingerprints recorded for `certificates`, for comparison with [`Deployed::sha256_fingerprints`]
+pub fn fingerprints(certificates: &[&CertificatePair]) -> Result<Vec<String>> {
+    certificates.iter()
+        .map(|c| c.summary().map(|s| s.sha256_fingerprint))
+        .collect()
+}
+
+/// The file modification times recorded for `certificates`, for comparison with [`Deployed::modified`]
+pub fn modified(certificates: &[&CertificatePair]) -> Result<Vec<Option<String>>> {
+    certificates.iter()
+        .map(|c| c.modified.map(format_time).transpose())
+        .collect()
+}
+
+fn format_time(time: SystemTime) -> Result<String> {
+    Ok(OffsetDateTime::from(time).format(&Rfc3339)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_state_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+
+        let old = crate::test_util::certificate_pair(&["bmc.example.net"]);
+        let new = crate::test_util::certificate_pair(&["bmc.example.net"]);
+
+        let mut state = State::load(&path).unwrap();
+        assert!(state.get("megarac-bmc.bmc").is_none());
+
+        state.record("megarac-bmc.bmc", &[&old]).unwrap();
+        state.save(&path).unwrap();
+
+        let state = State::load(&path).unwrap();
+        let deployed = state.get("megarac-bmc.bmc").unwrap();
+        assert_eq!(deployed.sha256_fingerprints, fingerprints(&[&old]).unwrap());
+        assert_ne!(deployed.sha256_fingerprints, fingerprints(&[&new]).unwrap());
+        assert_eq!(deployed.not_after, old.summary().unwrap().not_after);
+
+        let touched = CertificatePair { modified: Some(SystemTime::now()), ..crate::test_util::certificate_pair(&["bmc.example.net"]) };
+        assert_eq!(deployed.modified, modified(&[&old]).unwrap());
+        assert_ne!(deployed.modified, modified(&[&touched]).unwrap());
+
+        // state files written before modification times were recorded
+        let mut state: State = serde_json::from_str(r#"{ "remotes": { "megarac-bmc.bmc": {
+            "sha256_fingerprints": [], "not_after": "", "deployed_at": "" } } }"#).unwrap();
+        assert!(state.get("megarac-bmc.bmc").unwrap().modified.is_empty());
+
+        state.record("megarac-bmc.bmc", &[&touched]).unwrap();
+        assert!(state.get("megarac-bmc.bmc").unwrap().modified[0].is_some());
+    }
+}
@@ -0,0 +1,65 @@
+//! systemd service manager notifications (see `sd_notify(3)`)
+//!
+//! When built with the `systemd` feature and run under a unit with `Type=notify`,
+//! readiness and `STATUS=` updates are sent to the socket named by `$NOTIFY_SOCKET`.
+//! Without the feature (or outside of systemd) these functions are no-ops.
+
+#[cfg(feature = "systemd")]
+mod imp {
+    use std::os::unix::net::UnixDatagram;
+
+    use anyhow::{Context, Result};
+    use tracing::{debug, warn};
+
+    fn send(state: &str) -> Result<()> {
+        let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+            return Ok(());
+        };
+
+        let socket = UnixDatagram::unbound().context("failed to create notify socket")?;
+
+        let bytes = path.as_encoded_bytes();
+
+        // a leading '@' denotes a socket in the abstract namespace
+        if let Some(name) = bytes.strip_prefix(b"@") {
+            use std::os::linux::net::SocketAddrExt;
+            use std::os::unix::net::SocketAddr;
+
+            let addr = SocketAddr::from_abstract_name(name).context("invalid abstract $NOTIFY_SOCKET")?;
+            socket.send_to_addr(state.as_bytes(), &addr)
+        } else {
+            socket.send_to(state.as_bytes(), &path)
+        }.with_context(|| format!("failed to send notification to {}", path.to_string_lossy()))?;
+
+        debug!("sd_notify: {}", state.replace('\n', " "));
+
+        Ok(())
+    }
+
+    pub fn notify(state: &str) {
+        // notifications are best-effort -- never fail a run because systemd couldn't be told about it
+        if let Err(e) = send(state) {
+            warn!("{e:#}");
+        }
+    }
+}
+
+#[cfg(not(feature = "systemd"))]
+mod imp {
+    pub fn notify(_state: &str) {}
+}
+
+/// Tell the service manager start-up is complete, along with an initial status.
+pub fn ready(status: &str) {
+    imp::notify(&format!("READY=1\nSTATUS={status}"))
+}
+
+/// Update the free-form status shown by `systemctl status`.
+pub fn status(status: &str) {
+    imp::notify(&format!("STATUS={status}"))
+}
+
+/// Tell the service manager we're shutting down, along with a final status.
+pub fn stopping(status: &str) {
+    imp::notify(&format!("STOPPING=1\nSTATUS={status}"))
+}
@@ -0,0 +1,76 @@
+//! Helpers for generating certificates in tests
+
+use openssl::{asn1::Asn1Time, bn::BigNum, ec::{EcGroup, EcKey}, hash::MessageDigest, nid::Nid, pkey::{PKey, Private}, x509::{extension::{BasicConstraints, SubjectAlternativeName}, X509Builder, X509NameBuilder, X509}};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use vec1::Vec1;
+
+use crate::config::CertificatePair;
+
+pub fn generate_key() -> PKey<Private> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+}
+
+/// Build a certificate for `subject_cn` with the given SANs, signed by `issuer` (or self-signed if `None`)
+pub fn generate_cert(subject_cn: &str, sans: &[&str], key: &PKey<Private>, issuer: Option<(&X509, &PKey<Private>)>, ca: bool) -> X509 {
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_nid(Nid::COMMONNAME, subject_cn).unwrap();
+    let name = name.build();
+
+    let mut builder = X509Builder::new().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_serial_number(&BigNum::from_u32(next_serial()).unwrap().to_asn1_integer().unwrap()).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(issuer.map(|(cert, _)| cert.subject_name()).unwrap_or(&name)).unwrap();
+    builder.set_pubkey(key).unwrap();
+    builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    builder.set_not_after(&Asn1Time::days_from_now(90).unwrap()).unwrap();
+
+    if ca {
+        builder.append_extension(BasicConstraints::new().critical().ca().build().unwrap()).unwrap();
+    }
+
+    if !sans.is_empty() {
+        let mut san = SubjectAlternativeName::new();
+        for s in sans {
+            san.dns(s);
+        }
+        let san = san.build(&builder.x509v3_context(issuer.map(|(cert, _)| cert.as_ref()), None)).unwrap();
+        builder.append_extension(san).unwrap();
+    }
+
+    let signing_key = issuer.map(|(_, key)| key).unwrap_or(key);
+    builder.sign(signing_key, MessageDigest::sha256()).unwrap();
+
+    builder.build()
+}
+
+fn next_serial() -> u32 {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    static SERIAL: AtomicU32 = AtomicU32::new(1);
+    SERIAL.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A leaf certificate for `sans` issued by a freshly generated CA, as a chain of leaf + CA
+pub fn certificate_pair(sans: &[&str]) -> CertificatePair {
+    let ca_key = generate_key();
+    let ca = generate_cert("Test CA", &[], &ca_key, None, true);
+
+    let key = generate_key();
+    let leaf = generate_cert(sans.first().copied().unwrap_or("test"), sans, &key, Some((&ca, &ca_key)), false);
+
+    pair_from(&[leaf, ca], &key)
+}
+
+pub fn pair_from(chain: &[X509], key: &PKey<Private>) -> CertificatePair {
+    let chain = chain.iter()
+        .map(|c| CertificateDer::from(c.to_der().unwrap()))
+        .collect::<Vec<_>>();
+
+    CertificatePair {
+        certificate_chain: Vec1::try_from_vec(chain).unwrap(),
+        private_key: PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.private_key_to_pkcs8().unwrap())),
+        modified: None,
+    }
+}
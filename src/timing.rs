@@ -0,0 +1,107 @@
+//! How long each phase of updating a remote takes (connecting, authenticating, uploading, ...)
+//!
+//! Phases are only recorded within [`collect`], so callers that don't want timings pay nothing
+//! for them.
+
+use std::{cell::RefCell, future::Future, time::{Duration, Instant}};
+
+/// Each phase and how long it took
+pub type Phases = Vec<(&'static str, Duration)>;
+
+tokio::task_local! {
+    static PHASES: RefCell<Phases>;
+}
+
+/// Run `f`, returning the phases timed within it, in the order they finished.
+/// A phase timed more than once (e.g., logging in again) is reported once, with the total.
+pub async fn collect<T>(f: impl Future<Output = T>) -> (T, Phases) {
+    PHASES.scope(RefCell::new(Vec::new()), async {
+        let result = f.await;
+
+        (result, PHASES.with(|phases| phases.take()))
+    }).await
+}
+
+/// Time `f` as `phase`, if within [`collect`]
+pub async fn phase<T>(phase: &'static str, f: impl Future<Output = T>) -> T {
+    let start = Instant::now();
+    let result = f.await;
+    let elapsed = start.elapsed();
+
+    let _ = PHASES.try_with(|phases| {
+        let mut phases = phases.borrow_mut();
+
+        match phases.iter_mut().find(|(name, _)| *name == phase) {
+            Some((_, total)) => *total += elapsed,
+            None => phases.push((phase, elapsed)),
+        }
+    });
+
+    result
+}
+
+/// A table of each remote's phases, with a column for every phase any remote had
+pub fn report(remotes: &[(&str, Phases, Duration)]) -> String {
+    let mut columns: Vec<&str> = Vec::new();
+    for (_, phases, _) in remotes {
+        for (phase, _) in phases {
+            if !columns.contains(phase) {
+                columns.push(phase);
+            }
+        }
+    }
+    columns.push("total");
+
+    let name_width = remotes.iter().map(|(name, _, _)| name.len()).chain(["remote".len()]).max().unwrap_or_default();
+    let widths = columns.iter().map(|c| c.len().max(7)).collect::<Vec<_>>();
+
+    let mut table = format!("{:name_width$}", "remote");
+    for (column, width) in columns.iter().zip(&widths) {
+        table += &format!("  {column:>width$}");
+    }
+
+    for (name, phases, total) in remotes {
+        table += &format!("\n{name:name_width$}");
+
+        for (column, width) in columns.iter().zip(&widths) {
+            let duration = match *column {
+                "total" => Some(*total),
+                column => phases.iter().find(|(phase, _)| *phase == column).map(|(_, d)| *d),
+            };
+
+            let cell = duration.map(|d| format!("{:.2}s", d.as_secs_f64())).unwrap_or_else(|| "-".to_string());
+            table += &format!("  {cell:>width$}");
+        }
+    }
+
+    table
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_collect() {
+        let ((), phases) = collect(async {
+            phase("auth", async {}).await;
+            phase("upload", tokio::time::sleep(Duration::from_millis(20))).await;
+            phase("auth", async {}).await;
+        }).await;
+
+        assert_eq!(phases.iter().map(|(p, _)| *p).collect::<Vec<_>>(), ["auth", "upload"]);
+        assert!(phases[1].1 >= Duration::from_millis(20));
+
+        // not collecting
+        assert_eq!(phase("auth", async { 1 }).await, 1);
+
+        let table = report(&[
+            ("megarac-bmc.bmc", vec![("auth", Duration::from_millis(50)), ("upload", Duration::from_millis(1500))], Duration::from_secs(2)),
+            ("nginx.web", vec![("connect", Duration::from_millis(120))], Duration::from_millis(400)),
+        ]);
+        assert_eq!(table, "\
+remote              auth   upload  connect    total
+megarac-bmc.bmc    0.05s    1.50s        -    2.00s
+nginx.web              -        -    0.12s    0.40s");
+    }
+}
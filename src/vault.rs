@@ -0,0 +1,313 @@
+//! Certificates stored in HashiCorp Vault's key/value secrets engine.
+//!
+//! A certificate pair with a `vault` table reads its certificate chain and private key from
+//! fields of a secret, rather than from files or environment variables. Each secret is only
+//! fetched once each time the config is loaded, however many certificate pairs refer to it.
+//!
+//! ```toml
+//! [certs.default]
+//! vault.address = "https://vault.example.net:8200"
+//! vault.token_file = "$CREDENTIALS_DIRECTORY/vault-token"
+//! vault.path = "certs/router"
+//! ```
+
+use std::{cell::RefCell, collections::HashMap, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, bail, Context, Result};
+use reqwest::{blocking::Client, Certificate};
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::debug;
+use url::Url;
+
+use crate::config::{CredentialPathBuf, SecretSource};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A secret is the same only if it's read from the same URL, in the same namespace, with the same token
+/// (which may be allowed to read a different version of it)
+type CacheKey = (String, Option<String>, String);
+
+thread_local! {
+    /// The secrets fetched while loading a config, within [`cached`]
+    static CACHE: RefCell<Option<HashMap<CacheKey, Arc<Secret>>>> = const { RefCell::new(None) };
+}
+
+/// Run `f`, e.g. loading a config, fetching each secret at most once however many certificate pairs refer to it.
+///
+/// Secrets aren't kept afterwards, so loading the config again (e.g., in `serve`) sees any that have been renewed.
+pub(crate) fn cached<T>(f: impl FnOnce() -> T) -> T {
+    let outermost = CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        let outermost = cache.is_none();
+        if outermost {
+            *cache = Some(HashMap::new());
+        }
+        outermost
+    });
+
+    let result = f();
+
+    if outermost {
+        CACHE.with(|cache| cache.borrow_mut().take());
+    }
+
+    result
+}
+
+/// The `vault` table of a certificate pair
+#[derive(Deserialize, Debug)]
+pub struct Config {
+    /// Defaults to `$VAULT_ADDR`
+    address: Option<Url>,
+
+    /// Defaults to `$VAULT_TOKEN`
+    token_file: Option<CredentialPathBuf>,
+
+    /// environment variable containing the token, instead of `token_file`
+    token_env: Option<String>,
+
+    /// Roots to verify Vault's certificate against, in addition to the system trust store
+    ca_file: Option<CredentialPathBuf>,
+
+    /// For Vault Enterprise namespaces
+    namespace: Option<String>,
+
+    /// Where the secrets engine is mounted
+    #[serde(default = "Config::default_mount")]
+    mount: String,
+
+    /// 1 or 2
+    #[serde(default = "Config::default_kv_version")]
+    kv_version: u8,
+
+    /// The secret's path within the mount
+    path: String,
+
+    #[serde(default = "Config::default_certificate_chain_field")]
+    pub certificate_chain_field: String,
+
+    #[serde(default = "Config::default_private_key_field")]
+    pub private_key_field: String,
+}
+
+impl Config {
+    fn default_mount() -> String {
+        "secret".to_string()
+    }
+
+    fn default_kv_version() -> u8 {
+        2
+    }
+
+    fn default_certificate_chain_field() -> String {
+        "certificate".to_string()
+    }
+
+    fn default_private_key_field() -> String {
+        "private_key".to_string()
+    }
+
+    fn url(&self) -> Result<Url> {
+        let address = match &self.address {
+            Some(address) => address.clone(),
+            None => std::env::var("VAULT_ADDR").context("`address` isn't set, nor is $VAULT_ADDR")?
+                .parse().context("$VAULT_ADDR isn't a valid URL")?,
+        };
+
+        secret_url(&address, &self.mount, &self.path, self.kv_version)
+    }
+
+    fn token(&self) -> Result<String> {
+        let source = SecretSource::from_keys(self.token_file.clone(), self.token_env.clone(), "token_file", "token_env")?
+            .unwrap_or_else(|| SecretSource::Env("VAULT_TOKEN".to_string()));
+
+        source.read_to_string()
+            .map(|t| t.trim().to_string())
+            .with_context(|| format!("failed to read token {source}"))
+    }
+
+    /// The secret, fetched from Vault unless it already has been while loading this config
+    pub fn secret(&self) -> Result<Arc<Secret>> {
+        let url = self.url()?;
+        let token = self.token()?;
+        let name = format!("{}/{}", self.mount, self.path);
+
+        let key = (url.to_string(), self.namespace.clone(), token.clone());
+        if let Some(secret) = CACHE.with(|cache| cache.borrow().as_ref().and_then(|cache| cache.get(&key).cloned())) {
+            debug!("using cached Vault secret {name}");
+            return Ok(secret);
+        }
+
+        debug!("fetching Vault secret {name}");
+        let response = self.fetch(url, token).with_context(|| format!("failed to read Vault secret {name}"))?;
+        let secret = Arc::new(Secret {
+            fields: secret_fields(response, self.kv_version).with_context(|| format!("unexpected response for Vault secret {name}"))?,
+            name,
+        });
+
+        CACHE.with(|cache| {
+            if let Some(cache) = cache.borrow_mut().as_mut() {
+                cache.insert(key, secret.clone());
+            }
+        });
+
+        Ok(secret)
+    }
+
+    fn fetch(&self, url: Url, token: String) -> Result<Value> {
+        let namespace = self.namespace.clone();
+
+        let ca = self.ca_file.as_ref()
+            .map(|path| {
+                let pem = std::fs::read(path).with_context(|| format!("failed to open \"{}\"", path.display()))?;
+                Certificate::from_pem(&pem).with_context(|| format!("failed to read certificate from \"{}\"", path.display()))
+            })
+            .transpose()?;
+
+        // the config may be loaded from within the async runtime, where the blocking client can't be used
+        std::thread::spawn(move || {
+            let mut builder = Client::builder().timeout(TIMEOUT);
+            if let Some(ca) = ca {
+                builder = builder.add_root_certificate(ca);
+            }
+
+            let mut request = builder.build().context("failed to build a Client")?
+                .get(url)
+                .header("X-Vault-Token", token);
+            if let Some(namespace) = namespace {
+                request = request.header("X-Vault-Namespace", namespace);
+            }
+
+            let response = request.send().context("failed to send request")?;
+            let status = response.status();
+            let body: Value = response.json().context("failed to decode JSON response")?;
+
+            if !status.is_success() {
+                let errors = body["errors"].as_array().into_iter().flatten()
+                    .filter_map(Value::as_str)
+                    .collect::<Vec<_>>();
+
+                bail!("Vault responded {status}: {}", errors.join("; "));
+            }
+
+            Ok(body)
+        })
+        .join().map_err(|_| anyhow!("the Vault request panicked"))?
+    }
+}
+
+/// The API URL of the secret at `path`
+fn secret_url(address: &Url, mount: &str, path: &str, kv_version: u8) -> Result<Url> {
+    let mount = mount.trim_matches('/');
+    let path = path.trim_matches('/');
+
+    let api_path = match kv_version {
+        1 => format!("v1/{mount}/{path}"),
+        2 => format!("v1/{mount}/data/{path}"),
+        other => bail!("unsupported `kv_version` {other}, expected 1 or 2"),
+    };
+
+    address.join(&api_path).context("invalid Vault URL")
+}
+
+/// The string fields of the secret in a read response
+fn secret_fields(mut response: Value, kv_version: u8) -> Result<HashMap<String, String>> {
+    let data = match kv_version {
+        1 => response["data"].take(),
+        _ => response["data"]["data"].take(),
+    };
+
+    let Value::Object(data) = data else {
+        bail!("no secret data");
+    };
+
+    Ok(data.into_iter()
+        .filter_map(|(k, v)| match v {
+            Value::String(s) => Some((k, s)),
+            _ => None,
+        })
+        .collect())
+}
+
+#[derive(Debug)]
+pub struct Secret {
+    /// the secret's mount and path, for display
+    pub name: String,
+
+    fields: HashMap<String, String>,
+}
+
+impl Secret {
+    pub fn field(&self, field: &str) -> Result<&str> {
+        self.fields.get(field)
+            .map(String::as_str)
+            .ok_or_else(|| anyhow!("no field `{field}` in Vault secret {}", self.name))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_secret_fields() {
+        let address = Url::parse("https://vault.example.net:8200").unwrap();
+        assert_eq!(secret_url(&address, "secret", "certs/router", 2).unwrap().as_str(), "https://vault.example.net:8200/v1/secret/data/certs/router");
+        assert_eq!(secret_url(&address, "/kv/", "/router", 1).unwrap().as_str(), "https://vault.example.net:8200/v1/kv/router");
+        assert!(secret_url(&address, "secret", "router", 3).is_err());
+
+        let v2 = json!({ "data": { "data": { "certificate": "chain", "private_key": "key", "version": 3 }, "metadata": { "version": 3 } } });
+        let fields = secret_fields(v2, 2).unwrap();
+        assert_eq!(fields["certificate"], "chain");
+        assert!(!fields.contains_key("version"));
+
+        let v1 = json!({ "data": { "certificate": "chain" } });
+        assert_eq!(secret_fields(v1.clone(), 1).unwrap()["certificate"], "chain");
+        assert!(secret_fields(v1, 2).is_err());
+    }
+
+    #[test]
+    fn test_cached() {
+        use std::{io::{BufRead, BufReader, Write}, net::TcpListener, sync::atomic::{AtomicUsize, Ordering}};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+
+        std::thread::spawn({
+            let requests = requests.clone();
+            move || for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+
+                let n = requests.fetch_add(1, Ordering::SeqCst);
+                let body = json!({ "data": { "data": { "certificate": format!("chain {n}") } } }).to_string();
+                write!(stream, "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}", body.len()).unwrap();
+            }
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("token"), "s.token\n").unwrap();
+        let config = |namespace: &str| -> Config {
+            figment::Figment::from(figment::providers::Serialized::defaults(json!({
+                "address": address, "token_file": dir.path().join("token"), "path": "certs/router", "namespace": namespace,
+            }))).extract().unwrap()
+        };
+        let chain = |config: &Config| config.secret().unwrap().fields["certificate"].clone();
+
+        // once while loading a config, however many certificate pairs share the secret, unless in another namespace
+        let (a, b, other) = cached(|| (chain(&config("team")), chain(&config("team")), chain(&config("other"))));
+        assert_eq!((a.as_str(), b.as_str(), other.as_str()), ("chain 0", "chain 0", "chain 1"));
+
+        // and again each time it's loaded, so a renewed certificate is seen
+        assert_eq!(cached(|| chain(&config("team"))), "chain 2");
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+}
@@ -1,10 +1,15 @@
-use rustls_pki_types::UnixTime;
-use serde::Deserialize;
+use reqwest::{header::CONTENT_TYPE, tls::TlsInfo};
+use tracing::{debug, info};
+use rustls_pki_types::{CertificateDer, ServerName, UnixTime};
+use serde::{Deserialize, Serialize};
 use url::Url;
-use anyhow::{anyhow, bail, Result};
+use std::{net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs}, path::Path, sync::{Arc, Mutex}, time::{Duration, SystemTime}};
+use anyhow::{anyhow, bail, Context, Result};
+use openssl::{hash::MessageDigest, pkey::PKey, ssl::{SslConnector, SslMethod, SslVerifyMode}, ocsp::{OcspCertId, OcspCertStatus, OcspFlag, OcspRequest, OcspResponse, OcspResponseStatus, OcspRevokedStatus}, stack::Stack, x509::{store::X509StoreBuilder, X509NameRef, X509StoreContext, X509VerifyResult, X509}};
 use webpki::{EndEntityCert, KeyUsage};
+use x509_cert::{der::Decode, ext::pkix::BasicConstraints};
 
-use crate::config::CertificatePair;
+use crate::config::{sha256_fingerprint, CertificatePair};
 
 
 enum VerifyProtocol {
@@ -12,16 +17,31 @@ enum VerifyProtocol {
     TcpTls
 }
 
+impl TryFrom<&Url> for VerifyProtocol {
+    type Error = anyhow::Error;
+
+    fn try_from(url: &Url) -> Result<Self> {
+        Ok(match url.scheme() {
+            "https" => VerifyProtocol::Https,
+            "tcp+tls" => VerifyProtocol::TcpTls,
+            other => bail!("unknown verification protocol {other}")
+        })
+    }
+}
+
 
 #[derive(Deserialize, Debug)]
+#[allow(dead_code)]
 pub struct RawConfig {
     url: Option<Url>
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 // #[serde(try_from = "RawConfig")]
 pub struct Config {
-    url: Url
+    /// Where the remote serves the installed certificate, if not the remote's default.
+    /// Either `https://`, or `tcp+tls://host:port` for other implicit TLS services (e.g., IMAPS or LDAPS)
+    pub url: Url
 }
 
 // impl TryFrom<RawConfig> for Config {
@@ -40,22 +60,873 @@ pub struct Config {
 //     }
 // }
 
-pub fn precheck_certificate(certificate: &CertificatePair) -> Result<()> {
+/// Signature algorithms accepted when verifying certificate chains
+static SUPPORTED_SIG_ALGS: &[&dyn webpki::types::SignatureVerificationAlgorithm] = &[
+    webpki::ring::ECDSA_P256_SHA256,
+    webpki::ring::ECDSA_P256_SHA384,
+    webpki::ring::ECDSA_P384_SHA256,
+    webpki::ring::ECDSA_P384_SHA384,
+    webpki::ring::ED25519,
+    webpki::ring::RSA_PKCS1_2048_8192_SHA256,
+    webpki::ring::RSA_PKCS1_2048_8192_SHA384,
+    webpki::ring::RSA_PKCS1_2048_8192_SHA512,
+    webpki::ring::RSA_PKCS1_3072_8192_SHA384,
+    webpki::ring::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    webpki::ring::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    webpki::ring::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+];
+
+/// What a remote uses its certificate for, which the certificate's extended key usage must allow
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Usage {
+    /// TLS server authentication, as almost every remote serves its certificate
+    #[default]
+    Server,
+
+    /// TLS client authentication, e.g. for a device that presents its certificate to a RADIUS or syslog server
+    Client,
+
+    /// both of the above
+    Both,
+}
+
+impl Usage {
+    fn key_usages(self) -> Vec<(KeyUsage, &'static str)> {
+        let server = (KeyUsage::server_auth(), "TLS server authentication");
+        let client = (KeyUsage::client_auth(), "TLS client authentication");
+
+        match self {
+            Usage::Server => vec![server],
+            Usage::Client => vec![client],
+            Usage::Both => vec![server, client],
+        }
+    }
+}
+
+pub fn precheck_certificate(certificate: &CertificatePair, usage: Usage) -> Result<()> {
+    let chain = &certificate.certificate_chain;
+
+    check_leaf_first(certificate)?;
+    check_private_key(certificate)?;
+
+    let end_entity_cert: EndEntityCert = chain.first().try_into()?;
+
+    // the certificate pair is checked for internal consistency (validity, usage, and that each
+    // certificate is issued by the next), so the top of the supplied chain is treated as the anchor.
+    let anchors = [webpki::anchor_from_trusted_cert(chain.last())?];
+    let intermediates = match chain.len() {
+        1 => &[][..],
+        n => &chain[1..n - 1],
+    };
+
+    for (key_usage, description) in usage.key_usages() {
+        end_entity_cert.verify_for_usage(SUPPORTED_SIG_ALGS, &anchors, intermediates, UnixTime::now(), key_usage, None, None)
+            .map_err(|e| match e {
+                webpki::Error::RequiredEkuNotFound => anyhow!("the certificate's extended key usage doesn't allow {description} (see `key_usage`)"),
+                e => e.into(),
+            })?;
+    }
+
+    Ok(())
+}
+
+/// Whether the DER certificate is a CA certificate (basicConstraints CA:TRUE)
+fn is_ca(der: &[u8]) -> Result<bool> {
+    let cert = x509_cert::Certificate::from_der(der).context("failed to decode certificate")?;
+
+    Ok(cert.tbs_certificate.get::<BasicConstraints>().context("failed to decode basicConstraints")?
+        .is_some_and(|(_, constraints)| constraints.ca))
+}
+
+/// Refuse a private key that isn't the end-entity certificate's, which no remote could serve
+fn check_private_key(certificate: &CertificatePair) -> Result<()> {
+    let public_key = X509::from_der(certificate.certificate_chain.first()).context("failed to decode the certificate")?.public_key()?;
+    let private_key = PKey::private_key_from_der(certificate.private_key.secret_der()).context("failed to decode the private key")?;
+
+    if !public_key.public_eq(&private_key) {
+        bail!("the private key doesn't match the certificate");
+    }
+
+    Ok(())
+}
+
+/// Refuse chains that don't start with the end-entity certificate, as webpki would otherwise report
+/// an unhelpful error about the CA certificate it was given
+fn check_leaf_first(certificate: &CertificatePair) -> Result<()> {
+    let chain = &certificate.certificate_chain;
+
+    if !is_ca(chain.first())? {
+        return Ok(());
+    }
+
+    let first = X509::from_der(chain.first()).context("failed to decode certificate")?;
+    let first = name_string(first.subject_name());
+
+    let leaf = chain.iter().skip(1).position(|der| is_ca(der).is_ok_and(|ca| !ca));
+
+    match leaf {
+        Some(i) => bail!("the chain looks reversed: the first certificate, {first}, is a CA certificate, and the end-entity certificate is at position {} -- \
+            the end-entity certificate must come first, followed by the certificates that issued it", i + 2),
+        None => bail!("the chain has no end-entity certificate: the first certificate, {first}, is a CA certificate -- \
+            was the CA's certificate given instead of the server's?"),
+    }
+}
+
+/// The intermediates in the chain: every certificate after the end-entity certificate, other than a self-signed root
+fn intermediates(chain: &[X509]) -> usize {
+    chain.iter().skip(1).filter(|cert| cert.issued(cert) != X509VerifyResult::OK).count()
+}
+
+/// Check the chain has at least `min_intermediates` intermediates, and at most `max_length` certificates.
+///
+/// A CA-issued end-entity certificate alone in the chain is always refused. [`precheck_certificate`] would
+/// reject it too, but only as an unknown issuer.
+pub fn check_chain_length(certificate: &CertificatePair, min_intermediates: usize, max_length: usize) -> Result<()> {
+    let chain = certificate.certificate_chain.iter()
+        .map(|der| X509::from_der(der))
+        .collect::<Result<Vec<_>, _>>()
+        .context("failed to decode certificate chain")?;
+
+    let leaf = &chain[0];
+    if chain.len() == 1 && leaf.issued(leaf) != X509VerifyResult::OK {
+        bail!("only a leaf is present, no intermediates: {} was issued by {}, which isn't in the chain -- is this the full chain (e.g., fullchain.pem rather than cert.pem)?",
+            name_string(leaf.subject_name()), name_string(leaf.issuer_name()));
+    }
+
+    let count = intermediates(&chain);
+
+    if count < min_intermediates {
+        bail!("the chain has {count} intermediate certificate(s), but at least {min_intermediates} are required -- is this the full chain (e.g., fullchain.pem rather than cert.pem)?");
+    }
+
+    if chain.len() > max_length {
+        bail!("the chain has {} certificates, more than the maximum of {max_length}", chain.len());
+    }
+
+    Ok(())
+}
+
+/// Refuse a self-signed end-entity certificate (e.g., a leftover test certificate), or a chain in which a
+/// certificate after the end-entity certificate isn't a CA certificate, so there's no path to a CA
+pub fn check_not_self_signed(certificate: &CertificatePair) -> Result<()> {
+    let chain = certificate.certificate_chain.iter()
+        .map(|der| X509::from_der(der))
+        .collect::<Result<Vec<_>, _>>()
+        .context("failed to decode certificate chain")?;
+
+    let leaf = &chain[0];
+    if leaf.issued(leaf) == X509VerifyResult::OK {
+        bail!("the certificate is self-signed: it was issued by {}, which is its own subject {}",
+            name_string(leaf.issuer_name()), name_string(leaf.subject_name()));
+    }
+
+    for (i, (cert, der)) in chain.iter().zip(certificate.certificate_chain.iter()).enumerate().skip(1) {
+        if !is_ca(der)? {
+            bail!("certificate {i} ({}) isn't a CA certificate, so the chain has no path to a CA", name_string(cert.subject_name()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Check the end-entity certificate is valid for each of `hostnames` (DNS names, including via
+/// wildcards, or IP addresses), listing every one that isn't
+pub fn check_hostnames(certificate: &CertificatePair, hostnames: &[String]) -> Result<()> {
     let end_entity_cert: EndEntityCert = certificate.certificate_chain.first().try_into()?;
 
-    end_entity_cert.verify_for_usage(&[], &[], &[], UnixTime::now(), KeyUsage::server_auth(), None, None)?;
+    let mut missing = Vec::new();
+    for hostname in hostnames {
+        let name = ServerName::try_from(hostname.as_str()).map_err(|_| anyhow!("invalid hostname \"{hostname}\""))?;
+
+        if end_entity_cert.verify_is_valid_for_subject_name(&name).is_err() {
+            missing.push(hostname.as_str());
+        }
+    }
+
+    if !missing.is_empty() {
+        bail!("the certificate doesn't cover {}", missing.join(", "));
+    }
 
     Ok(())
 }
 
-fn check_remote_certificate() {
+/// `X509_V_ERR_UNABLE_TO_GET_ISSUER_CERT_LOCALLY`
+const UNABLE_TO_GET_ISSUER_CERT_LOCALLY: i32 = 20;
+
+/// Check that the chain links from the end-entity certificate, through each intermediate, to a
+/// trusted root -- one from `ca_file` if given, otherwise from the system trust store.
+///
+/// Unlike [`precheck_certificate`], the top of the chain isn't trusted just for being there,
+/// so a missing intermediate is caught before a broken chain is installed.
+pub fn verify_chain(certificate: &CertificatePair, ca_file: Option<&Path>) -> Result<()> {
+    let chain = certificate.certificate_chain.iter()
+        .map(|der| X509::from_der(der))
+        .collect::<Result<Vec<_>, _>>()
+        .context("failed to decode certificate chain")?;
+
+    // report links that don't join up precisely, rather than as a generic verification failure
+    for (i, link) in chain.windows(2).enumerate() {
+        let (cert, next) = (&link[0], &link[1]);
+
+        if next.issued(cert) != X509VerifyResult::OK {
+            bail!("certificate {i} ({}) was issued by {}, but is followed in the chain by {} -- is an intermediate missing or out of order?",
+                name_string(cert.subject_name()), name_string(cert.issuer_name()), name_string(next.subject_name()));
+        }
+    }
+
+    let mut store = X509StoreBuilder::new()?;
+    match ca_file {
+        Some(path) => {
+            let pem = std::fs::read(path).with_context(|| format!("failed to read CA file \"{}\"", path.display()))?;
+
+            for ca in X509::stack_from_pem(&pem).with_context(|| format!("failed to read certificates from CA file \"{}\"", path.display()))? {
+                store.add_cert(ca)?;
+            }
+        },
+        None => store.set_default_paths().context("failed to load the system trust store")?,
+    }
+    let store = store.build();
+
+    let mut untrusted = Stack::new()?;
+    for cert in &chain[1..] {
+        untrusted.push(cert.clone())?;
+    }
+
+    let mut context = X509StoreContext::new()?;
+    let failure = context.init(&store, &chain[0], &untrusted, |context| {
+        if context.verify_cert()? {
+            return Ok(None);
+        }
+
+        let cert = context.current_cert()
+            .map(|cert| (name_string(cert.subject_name()), name_string(cert.issuer_name())));
+
+        Ok(Some((context.error(), context.error_depth(), cert)))
+    })?;
+
+    match failure {
+        None => Ok(()),
+        Some((error, depth, Some((subject, issuer)))) if error.as_raw() == UNABLE_TO_GET_ISSUER_CERT_LOCALLY => {
+            bail!("no trusted issuer for certificate {depth} ({subject}): {issuer} isn't in the chain or the trust store")
+        },
+        Some((error, depth, Some((subject, _)))) => bail!("certificate {depth} ({subject}) failed verification: {error}"),
+        Some((error, depth, None)) => bail!("certificate {depth} failed verification: {error}"),
+    }
+}
+
+/// A readable distinguished name, e.g. `CN=R11, O=Let's Encrypt, C=US`
+fn name_string(name: &X509NameRef) -> String {
+    name.entries()
+        .map(|entry| {
+            let key = entry.object().nid().short_name().unwrap_or("?");
+            let value = entry.data().as_utf8().map(|v| v.to_string()).unwrap_or_default();
+
+            format!("{key}={value}")
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The OCSP status of an end-entity certificate
+#[derive(Debug, PartialEq, Eq)]
+pub enum RevocationStatus {
+    Good,
+    Revoked { reason: String, time: String },
+}
+
+/// How often OCSP responses are allowed to be out of date, in seconds, allowing for clock skew
+const OCSP_MAX_SKEW: u32 = 300;
+
+/// How long to wait for an OCSP responder, after which the status is taken as undetermined
+const OCSP_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Ask the OCSP responder named in the end-entity certificate's AIA extension whether it has been revoked.
+///
+/// An error means the status couldn't be determined (no responder, no issuer in the chain,
+/// the responder was unreachable or its response couldn't be verified).
+pub async fn check_revocation(certificate: &CertificatePair, user_agent: Option<&str>) -> Result<RevocationStatus> {
+    let leaf = X509::from_der(certificate.certificate_chain.first()).context("failed to decode certificate")?;
+    let issuer = match certificate.certificate_chain.get(1) {
+        Some(der) => X509::from_der(der).context("failed to decode issuer certificate")?,
+        None => bail!("the chain doesn't include the issuer certificate, which is needed for an OCSP request"),
+    };
+
+    // a certificate without an AIA extension is reported as an error rather than an empty list
+    let responder = leaf.ocsp_responders().ok()
+        .and_then(|urls| urls.iter().next().map(|url| url.to_string()))
+        .ok_or_else(|| anyhow!("the certificate doesn't name an OCSP responder"))?;
+
+    let cert_id = || OcspCertId::from_cert(MessageDigest::sha1(), &leaf, &issuer);
+
+    let mut request = OcspRequest::new()?;
+    request.add_id(cert_id()?)?;
+
+    debug!("checking revocation status with {responder}");
+    let response = crate::http::client_builder(user_agent).timeout(OCSP_TIMEOUT).build().context("failed to build a Client")?
+        .post(&responder)
+        .header(CONTENT_TYPE, "application/ocsp-request")
+        .body(request.to_der()?)
+        .send().await.with_context(|| format!("failed to contact OCSP responder {responder}"))?
+        .error_for_status().with_context(|| format!("OCSP responder {responder} returned an error"))?
+        .bytes().await.with_context(|| format!("failed to read response from OCSP responder {responder}"))?;
+
+    let response = OcspResponse::from_der(&response).context("invalid OCSP response")?;
+    if response.status() != OcspResponseStatus::SUCCESSFUL {
+        bail!("OCSP responder {responder} declined the request (status {})", response.status().as_raw());
+    }
+
+    let basic = response.basic().context("invalid OCSP response")?;
+
+    // the response is signed by the issuer, or by a responder certificate it issued (and included)
+    let mut store = X509StoreBuilder::new()?;
+    store.add_cert(issuer.clone())?;
+    let store = store.build();
+
+    let mut certs = Stack::new()?;
+    certs.push(issuer.clone())?;
+    basic.verify(&certs, &store, OcspFlag::empty()).context("OCSP response failed verification")?;
+
+    let cert_id = cert_id()?;
+    let status = basic.find_status(&cert_id)
+        .ok_or_else(|| anyhow!("OCSP response doesn't include the certificate"))?;
+    status.check_validity(OCSP_MAX_SKEW, None).context("OCSP response is out of date")?;
+
+    match status.status {
+        OcspCertStatus::GOOD => Ok(RevocationStatus::Good),
+        OcspCertStatus::REVOKED => Ok(RevocationStatus::Revoked {
+            reason: revocation_reason(status.reason).to_string(),
+            time: status.revocation_time.map(|t| t.to_string()).unwrap_or_default(),
+        }),
+        _ => bail!("OCSP responder {responder} doesn't know the certificate"),
+    }
+}
+
+fn revocation_reason(reason: OcspRevokedStatus) -> &'static str {
+    match reason {
+        OcspRevokedStatus::KEY_COMPROMISE => "key compromise",
+        OcspRevokedStatus::CA_COMPROMISE => "CA compromise",
+        OcspRevokedStatus::AFFILIATION_CHANGED => "affiliation changed",
+        OcspRevokedStatus::STATUS_SUPERSEDED => "superseded",
+        OcspRevokedStatus::STATUS_CESSATION_OF_OPERATION => "cessation of operation",
+        OcspRevokedStatus::STATUS_CERTIFICATE_HOLD => "certificate hold",
+        OcspRevokedStatus::REMOVE_FROM_CRL => "removed from CRL",
+        _ => "unspecified",
+    }
+}
+
+/// An `https://` URL for `host`, for remotes that serve the installed certificate on their management interface
+pub fn https_url(host: &str, port: Option<u16>) -> Url {
+    let host = match host.parse::<std::net::Ipv6Addr>() {
+        Ok(_) => format!("[{host}]"),
+        Err(_) => host.to_string(),
+    };
+
+    let url = match port {
+        Some(port) => format!("https://{host}:{port}/"),
+        None => format!("https://{host}/"),
+    };
+
+    Url::parse(&url).expect("valid verify URL")
+}
+
+/// Fetch the end-entity certificate presented by the service at `url`, connecting to `address` if given, and
+/// identifying as `user_agent` over HTTPS.
+///
+/// The certificate is not validated -- the point is to see what is installed,
+/// which may well be an expired or self-signed certificate.
+pub async fn fetch_remote_certificate(url: &Url, address: Option<IpAddr>, user_agent: Option<&str>) -> Result<CertificateDer<'static>> {
+    match VerifyProtocol::try_from(url)? {
+        VerifyProtocol::Https => {
+            let client = crate::http::resolve_to(crate::http::client_builder(user_agent), url, address)
+                .https_only(true)
+                .tls_info(true)
+                .danger_accept_invalid_certs(true)
+                .build().context("failed to build a Client")?;
+
+            let response = match client.get(url.clone()).send().await {
+                Ok(response) => response,
+                // the HTTP client doesn't say what was presented in a failed handshake, so look again
+                Err(e) if e.is_connect() => match handshake_failure(url, address).await {
+                    Some(failed) => return Err(failed).with_context(|| format!("failed to connect to {url}")),
+                    None => return Err(e).with_context(|| format!("failed to connect to {url}")),
+                },
+                Err(e) => return Err(e).with_context(|| format!("failed to connect to {url}")),
+            };
+
+            let tls_info: &TlsInfo = response.extensions().get()
+                .ok_or_else(|| anyhow!("no TLS information available for {url}"))?;
+
+            let cert = tls_info.peer_certificate()
+                .ok_or_else(|| anyhow!("{url} did not present a certificate"))?;
+
+            Ok(CertificateDer::from(cert.to_vec()))
+        },
+        VerifyProtocol::TcpTls => {
+            let host = url.host_str().ok_or_else(|| anyhow!("{url} has no host"))?.trim_matches(['[', ']']).to_string();
+            let port = url.port().ok_or_else(|| anyhow!("{url} has no port"))?;
+
+            // openssl's streams are blocking
+            let url = url.clone();
+            tokio::task::spawn_blocking(move || fetch_tls_certificate(&host, port, address).with_context(|| format!("failed to connect to {url}")))
+                .await.context("the TLS connection panicked")?
+        },
+    }
+}
+
+/// The certificate presented by the HTTPS service at `url` before its handshake failed, if it did
+async fn handshake_failure(url: &Url, address: Option<IpAddr>) -> Option<HandshakeFailed> {
+    let host = url.host_str()?.trim_matches(['[', ']']).to_string();
+    let port = url.port_or_known_default()?;
+
+    let result = tokio::task::spawn_blocking(move || fetch_tls_certificate(&host, port, address)).await.ok()?;
+
+    result.err()?.downcast::<HandshakeFailed>().ok()
+}
+
+/// A TLS handshake that failed after the service presented its certificate,
+/// e.g., as one without the private key for that certificate can't complete it
+#[derive(Debug, thiserror::Error)]
+#[error("TLS handshake failed after the certificate with SHA-256 fingerprint {fingerprint} was presented: {reason}")]
+pub struct HandshakeFailed {
+    pub presented: CertificateDer<'static>,
+    fingerprint: String,
+    reason: String,
+}
 
+/// The expiry of the end-entity certificate presented by the service at `url`
+pub async fn fetch_remote_expiry(url: &Url, address: Option<IpAddr>, user_agent: Option<&str>) -> Result<SystemTime> {
+    not_after(&fetch_remote_certificate(url, address, user_agent).await?)
+}
+
+/// The expiry of a DER-encoded certificate, e.g. one presented by a remote
+pub fn not_after(der: &[u8]) -> Result<SystemTime> {
+    let certificate = x509_cert::Certificate::from_der(der).context("failed to decode the presented certificate")?;
+
+    Ok(certificate.tbs_certificate.validity.not_after.to_system_time())
+}
+
+/// How long to wait for each step of a `tcp+tls` connection
+const TLS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The end-entity certificate presented by an implicit TLS service (e.g., IMAPS or LDAPS) at `host`:`port`
+fn fetch_tls_certificate(host: &str, port: u16, address: Option<IpAddr>) -> Result<CertificateDer<'static>> {
+    let addrs = match address {
+        Some(address) => vec![SocketAddr::new(address, port)],
+        None => (host, port).to_socket_addrs().with_context(|| format!("failed to resolve {host}"))?.collect(),
+    };
+
+    let mut last_error = anyhow!("{host} has no addresses");
+    let Some(stream) = addrs.iter().find_map(|addr| {
+        TcpStream::connect_timeout(addr, TLS_TIMEOUT)
+            .map_err(|e| last_error = anyhow::Error::new(e).context(format!("failed to connect to {addr}")))
+            .ok()
+    }) else {
+        return Err(last_error);
+    };
+    stream.set_read_timeout(Some(TLS_TIMEOUT))?;
+    stream.set_write_timeout(Some(TLS_TIMEOUT))?;
+
+    // every certificate is accepted, but kept, as it's checked before the handshake fails for any other reason
+    let presented = Arc::new(Mutex::new(None));
+    let mut connector = SslConnector::builder(SslMethod::tls_client())?;
+    connector.set_verify_callback(SslVerifyMode::PEER, {
+        let presented = presented.clone();
+        move |_, context| {
+            if context.error_depth() == 0 {
+                *presented.lock().unwrap() = context.current_cert().and_then(|cert| cert.to_der().ok());
+            }
+            true
+        }
+    });
+
+    let mut ssl = connector.build().configure()?;
+    ssl.set_verify_hostname(false);
+    // SNI isn't sent for IP addresses
+    ssl.set_use_server_name_indication(host.parse::<IpAddr>().is_err());
+
+    let stream = match ssl.connect(host, stream) {
+        Ok(stream) => stream,
+        Err(e) => return Err(match presented.lock().unwrap().take() {
+            Some(der) => HandshakeFailed { fingerprint: sha256_fingerprint(&der)?, presented: CertificateDer::from(der), reason: e.to_string() }.into(),
+            None => anyhow!("TLS handshake failed: {e}"),
+        }),
+    };
+
+    let cert = stream.ssl().peer_certificate()
+        .ok_or_else(|| anyhow!("{host}:{port} did not present a certificate"))?;
+
+    Ok(CertificateDer::from(cert.to_der()?))
+}
+
+const RESTART_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Wait for the service at `url` to serve `certificate` after restarting, within `timeout`.
+/// Polling only starts after `grace`, so the service isn't mistaken for being back before it went away
+pub async fn wait_for_certificate(url: &Url, address: Option<IpAddr>, user_agent: Option<&str>, certificate: &CertificatePair, grace: Duration, timeout: Duration) -> Result<()> {
+    let deadline = std::time::Instant::now() + timeout;
+
+    tokio::time::sleep(grace).await;
+
+    loop {
+        // a service that accepts connections while starting may not finish the handshake, or respond, until it's up
+        let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+        let presented = tokio::time::timeout(remaining, compare_remote_certificate(url, address, user_agent, certificate)).await
+            .unwrap_or_else(|elapsed| Err(anyhow::Error::new(elapsed)));
+
+        match presented {
+            Ok(Presented::Installed) => {
+                info!("{url} is serving the new certificate");
+                return Ok(());
+            },
+            Ok(Presented::KeyMismatch) => bail!("{url} presents the new certificate, but fails the TLS handshake, so doesn't have its private key \
+                -- the remote may have kept its previous key"),
+            Ok(Presented::Other) => debug!("{url} is still serving the previous certificate"),
+            Err(e) => debug!("{url} isn't back yet: {e:#}"),
+        }
+
+        if std::time::Instant::now() + RESTART_POLL_INTERVAL > deadline {
+            bail!("{url} wasn't serving the new certificate within {}s of restarting", timeout.as_secs());
+        }
+
+        tokio::time::sleep(RESTART_POLL_INTERVAL).await;
+    }
+}
+
+/// What a service presents, compared with a certificate pair
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Presented {
+    /// the pair's end-entity certificate
+    Installed,
+    /// the pair's end-entity certificate, but the handshake then fails, so the service doesn't have the pair's private key,
+    /// e.g., from firmware that stored the new certificate but kept its previous key
+    KeyMismatch,
+    /// a different certificate entirely
+    Other,
+}
+
+/// Compare the end-entity certificate `presented` (DER) with `certificate`
+pub fn compare_presented(presented: &[u8], certificate: &CertificatePair) -> Presented {
+    match presented == certificate.certificate_chain.first().as_ref() {
+        true => Presented::Installed,
+        false => Presented::Other,
+    }
+}
+
+/// Fetch the end-entity certificate the service at `url` presents, and compare it with `certificate`.
+/// A handshake that fails after presenting it still says what was presented
+pub async fn fetch_and_compare_certificate(url: &Url, address: Option<IpAddr>, user_agent: Option<&str>, certificate: &CertificatePair) -> Result<(Presented, CertificateDer<'static>)> {
+    let remote = match fetch_remote_certificate(url, address, user_agent).await {
+        Ok(remote) => remote,
+        Err(e) => match e.downcast_ref::<HandshakeFailed>() {
+            Some(failed) if compare_presented(&failed.presented, certificate) == Presented::Installed => {
+                debug!("{url} presents the installed certificate, but: {e:#}");
+                return Ok((Presented::KeyMismatch, failed.presented.clone()));
+            },
+            _ => return Err(e),
+        },
+    };
+
+    debug!("{url} presents the certificate with SHA-256 fingerprint {}; the installed one's is {}",
+        sha256_fingerprint(&remote)?, sha256_fingerprint(certificate.certificate_chain.first())?);
+
+    Ok((compare_presented(&remote, certificate), remote))
+}
+
+/// Compare the end-entity certificate the service at `url` presents with `certificate`
+pub async fn compare_remote_certificate(url: &Url, address: Option<IpAddr>, user_agent: Option<&str>, certificate: &CertificatePair) -> Result<Presented> {
+    Ok(fetch_and_compare_certificate(url, address, user_agent, certificate).await?.0)
+}
+
+/// Check whether the service at `url` already presents the end-entity certificate of `certificate`, and completes a handshake with it
+pub async fn check_remote_certificate(url: &Url, address: Option<IpAddr>, user_agent: Option<&str>, certificate: &CertificatePair) -> Result<bool> {
+    Ok(compare_remote_certificate(url, address, user_agent, certificate).await? == Presented::Installed)
 }
 
 #[cfg(test)]
 mod test {
-    use reqwest::{tls::TlsInfo, Client};
-    use x509_cert::{der::{Decode, EncodePem}, Certificate};
+    // use reqwest::{tls::TlsInfo, Client};
+    // use x509_cert::{der::{Decode, EncodePem}, Certificate};
+
+    use crate::test_util;
+
+    use super::*;
+
+    #[test]
+    fn test_precheck_certificate() {
+        let pair = test_util::certificate_pair(&["device.example.net"]);
+        precheck_certificate(&pair, Usage::Server).unwrap();
+
+        // a lone self-signed certificate
+        let key = test_util::generate_key();
+        let cert = test_util::generate_cert("device.example.net", &["device.example.net"], &key, None, false);
+        precheck_certificate(&test_util::pair_from(&[cert], &key), Usage::Server).unwrap();
+
+        // a chain that doesn't link up
+        let other = test_util::certificate_pair(&["other.example.net"]);
+        let key = test_util::generate_key();
+        let cert = test_util::generate_cert("device.example.net", &["device.example.net"], &key, None, false);
+        let broken = CertificatePair {
+            certificate_chain: vec1::vec1![
+                cert.to_der().unwrap().into(),
+                other.certificate_chain.last().clone(),
+            ],
+            private_key: pair.private_key.clone_key(),
+            modified: None,
+        };
+        assert!(precheck_certificate(&broken, Usage::Server).is_err());
+
+        // a private key for another certificate
+        let mismatched = test_util::pair_from(&[X509::from_der(pair.certificate_chain.first()).unwrap()], &test_util::generate_key());
+        let e = precheck_certificate(&mismatched, Usage::Server).unwrap_err();
+        assert_eq!(e.to_string(), "the private key doesn't match the certificate");
+
+        // root-first
+        let reversed = CertificatePair {
+            certificate_chain: vec1::Vec1::try_from_vec(pair.certificate_chain.iter().rev().cloned().collect()).unwrap(),
+            private_key: pair.private_key.clone_key(),
+            modified: None,
+        };
+        let e = precheck_certificate(&reversed, Usage::Server).unwrap_err();
+        assert!(e.to_string().starts_with("the chain looks reversed: the first certificate, CN=Test CA, is a CA certificate, and the end-entity certificate is at position 2"), "{e}");
+    }
+
+    #[test]
+    fn test_precheck_certificate_usage() {
+        use openssl::x509::extension::ExtendedKeyUsage;
+
+        // a certificate without extended key usage may be used for anything
+        let pair = test_util::certificate_pair(&["device.example.net"]);
+        for usage in [Usage::Server, Usage::Client, Usage::Both] {
+            precheck_certificate(&pair, usage).unwrap();
+        }
+
+        let key = test_util::generate_key();
+        let cert = test_util::generate_cert("device.example.net", &["device.example.net"], &key, None, false);
+        let mut builder = openssl::x509::X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_serial_number(cert.serial_number()).unwrap();
+        builder.set_subject_name(cert.subject_name()).unwrap();
+        builder.set_issuer_name(cert.issuer_name()).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(cert.not_before()).unwrap();
+        builder.set_not_after(cert.not_after()).unwrap();
+        builder.append_extension(ExtendedKeyUsage::new().client_auth().build().unwrap()).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        let client = test_util::pair_from(&[builder.build()], &key);
+
+        precheck_certificate(&client, Usage::Client).unwrap();
+
+        let e = precheck_certificate(&client, Usage::Server).unwrap_err();
+        assert_eq!(e.to_string(), "the certificate's extended key usage doesn't allow TLS server authentication (see `key_usage`)");
+        assert!(precheck_certificate(&client, Usage::Both).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_check_revocation_undetermined() {
+        let pair = test_util::certificate_pair(&["device.example.net"]);
+        let e = check_revocation(&pair, None).await.unwrap_err();
+        assert!(e.to_string().contains("doesn't name an OCSP responder"), "{e}");
+
+        let key = test_util::generate_key();
+        let cert = test_util::generate_cert("device.example.net", &["device.example.net"], &key, None, false);
+        let e = check_revocation(&test_util::pair_from(&[cert], &key), None).await.unwrap_err();
+        assert!(e.to_string().contains("doesn't include the issuer"), "{e}");
+    }
+
+    #[test]
+    fn test_verify_chain() {
+        let root_key = test_util::generate_key();
+        let root = test_util::generate_cert("Test Root", &[], &root_key, None, true);
+
+        let intermediate_key = test_util::generate_key();
+        let intermediate = test_util::generate_cert("Test Intermediate", &[], &intermediate_key, Some((&root, &root_key)), true);
+
+        let key = test_util::generate_key();
+        let leaf = test_util::generate_cert("device.example.net", &["device.example.net"], &key, Some((&intermediate, &intermediate_key)), false);
+
+        let ca_file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(ca_file.path(), root.to_pem().unwrap()).unwrap();
+
+        // leaf + intermediate, with the root in the CA file
+        let pair = test_util::pair_from(&[leaf.clone(), intermediate.clone()], &key);
+        verify_chain(&pair, Some(ca_file.path())).unwrap();
+
+        // the root isn't in the system trust store
+        assert!(verify_chain(&pair, None).is_err());
+
+        // missing the intermediate
+        let pair = test_util::pair_from(std::slice::from_ref(&leaf), &key);
+        let e = verify_chain(&pair, Some(ca_file.path())).unwrap_err();
+        assert!(e.to_string().contains("no trusted issuer for certificate 0 (CN=device.example.net): CN=Test Intermediate"), "{e}");
+
+        // followed by the wrong certificate
+        let pair = test_util::pair_from(&[leaf, root], &key);
+        let e = verify_chain(&pair, Some(ca_file.path())).unwrap_err();
+        assert!(e.to_string().contains("certificate 0 (CN=device.example.net) was issued by CN=Test Intermediate, but is followed in the chain by CN=Test Root"), "{e}");
+    }
+
+    #[test]
+    fn test_check_chain_length() {
+        let root_key = test_util::generate_key();
+        let root = test_util::generate_cert("Test Root", &[], &root_key, None, true);
+
+        let intermediate_key = test_util::generate_key();
+        let intermediate = test_util::generate_cert("Test Intermediate", &[], &intermediate_key, Some((&root, &root_key)), true);
+
+        let key = test_util::generate_key();
+        let leaf = test_util::generate_cert("device.example.net", &["device.example.net"], &key, Some((&intermediate, &intermediate_key)), false);
+
+        // a trailing root isn't an intermediate
+        let pair = test_util::pair_from(&[leaf.clone(), intermediate.clone(), root.clone()], &key);
+        check_chain_length(&pair, 1, 5).unwrap();
+        let e = check_chain_length(&pair, 2, 5).unwrap_err();
+        assert!(e.to_string().contains("has 1 intermediate certificate(s), but at least 2 are required"), "{e}");
+        let e = check_chain_length(&pair, 0, 2).unwrap_err();
+        assert!(e.to_string().contains("has 3 certificates, more than the maximum of 2"), "{e}");
+
+        // a stripped chain
+        let pair = test_util::pair_from(std::slice::from_ref(&leaf), &key);
+        let e = check_chain_length(&pair, 0, 5).unwrap_err();
+        assert!(e.to_string().contains("only a leaf is present, no intermediates: CN=device.example.net was issued by CN=Test Intermediate"), "{e}");
+
+        // a lone self-signed certificate has nothing missing
+        let cert = test_util::generate_cert("device.example.net", &["device.example.net"], &key, None, false);
+        check_chain_length(&test_util::pair_from(&[cert], &key), 0, 5).unwrap();
+    }
+
+    #[test]
+    fn test_check_not_self_signed() {
+        let root_key = test_util::generate_key();
+        let root = test_util::generate_cert("Test Root", &[], &root_key, None, true);
+
+        let key = test_util::generate_key();
+        let leaf = test_util::generate_cert("device.example.net", &["device.example.net"], &key, Some((&root, &root_key)), false);
+
+        check_not_self_signed(&test_util::pair_from(&[leaf.clone(), root.clone()], &key)).unwrap();
+
+        let cert = test_util::generate_cert("device.example.net", &["device.example.net"], &key, None, false);
+        let e = check_not_self_signed(&test_util::pair_from(std::slice::from_ref(&cert), &key)).unwrap_err();
+        assert_eq!(e.to_string(), "the certificate is self-signed: it was issued by CN=device.example.net, which is its own subject CN=device.example.net");
+
+        // followed by a certificate that can't issue others
+        let e = check_not_self_signed(&test_util::pair_from(&[leaf, cert], &key)).unwrap_err();
+        assert!(e.to_string().starts_with("certificate 1 (CN=device.example.net) isn't a CA certificate"), "{e}");
+    }
+
+    #[test]
+    fn test_check_hostnames() {
+        let pair = test_util::certificate_pair(&["nexus.example.net", "*.lan.example.net"]);
+
+        check_hostnames(&pair, &["nexus.example.net".to_string(), "nexus.lan.example.net".to_string()]).unwrap();
+
+        let hostnames = ["nexus.example.net", "nexus.lan", "a.b.lan.example.net", "192.0.2.1"].map(str::to_string);
+        let e = check_hostnames(&pair, &hostnames).unwrap_err();
+        assert_eq!(e.to_string(), "the certificate doesn't cover nexus.lan, a.b.lan.example.net, 192.0.2.1");
+
+        assert!(check_hostnames(&pair, &["not a hostname".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_fetch_tls_certificate() {
+        use openssl::ssl::SslAcceptor;
+
+        let key = test_util::generate_key();
+        let cert = test_util::generate_cert("mail.example.net", &["mail.example.net"], &key, None, false);
+
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server()).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        acceptor.set_certificate(&cert).unwrap();
+        let acceptor = acceptor.build();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let _ = acceptor.accept(stream);
+        });
+
+        // a self-signed certificate for a different name is still read
+        let presented = fetch_tls_certificate("localhost", port, None).unwrap();
+        assert_eq!(presented.as_ref(), cert.to_der().unwrap());
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_compare_presented() {
+        let pair = test_util::certificate_pair(&["device.example.net"]);
+        assert_eq!(compare_presented(pair.certificate_chain.first(), &pair), Presented::Installed);
+
+        let other = test_util::certificate_pair(&["device.example.net"]);
+        assert_eq!(compare_presented(other.certificate_chain.first(), &pair), Presented::Other);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_and_compare_certificate_handshake_failure() {
+        use openssl::ssl::{SslAcceptor, SslVersion};
+
+        let pair = test_util::certificate_pair(&["device.example.net"]);
+        let cert = X509::from_der(pair.certificate_chain.first()).unwrap();
+        let key = PKey::private_key_from_der(pair.private_key.secret_der()).unwrap();
+
+        // a TLS 1.2 server that presents its certificate, then fails the handshake for want of a client certificate
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server()).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        acceptor.set_certificate(&cert).unwrap();
+        acceptor.set_max_proto_version(Some(SslVersion::TLS1_2)).unwrap();
+        acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        let acceptor = acceptor.build();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let _ = acceptor.accept(stream.unwrap());
+            }
+        });
+
+        for url in [format!("tcp+tls://localhost:{port}"), format!("https://localhost:{port}/")] {
+            let url = Url::parse(&url).unwrap();
+
+            let (presented, der) = fetch_and_compare_certificate(&url, None, None, &pair).await.unwrap();
+            assert_eq!(presented, Presented::KeyMismatch, "{url}");
+            assert_eq!(der.as_ref(), pair.certificate_chain.first().as_ref());
+
+            // any other certificate is just a failed connection
+            let other = test_util::certificate_pair(&["device.example.net"]);
+            let e = fetch_and_compare_certificate(&url, None, None, &other).await.unwrap_err();
+            assert!(e.downcast_ref::<HandshakeFailed>().is_some(), "{url}: {e:#}");
+        }
+    }
+
+    #[test]
+    fn test_https_url() {
+        assert_eq!(https_url("device.example.net", None).as_str(), "https://device.example.net/");
+        assert_eq!(https_url("2001:db8::1", Some(8443)).as_str(), "https://[2001:db8::1]:8443/");
+    }
+
+
+    #[tokio::test]
+    async fn test_wait_for_certificate_bounds_polls() {
+        // accepts connections, but never completes a handshake
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("https://{}/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            let mut streams = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                streams.push(stream);
+            }
+        });
+
+        let certificate = test_util::certificate_pair(&["device.example.net"]);
+
+        let started = std::time::Instant::now();
+        let e = wait_for_certificate(&url, None, None, &certificate, Duration::ZERO, Duration::from_secs(1)).await.unwrap_err();
+        assert!(e.to_string().contains("wasn't serving the new certificate within 1s"), "{e:#}");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
 
 
     #[tokio::test]
@@ -0,0 +1,412 @@
+//! A minimal YAML reader, for kubeconfigs
+//!
+//! Only the block style that `kubectl` writes is understood: mappings, sequences (including ones at the
+//! indentation of their key), plain and quoted scalars, one-line flow collections of scalars, and
+//! comments. Anchors, tags, block scalars (`|`, `>`), multi-line scalars and multiple documents aren't.
+//! Plain scalars other than `null`, `~`, `true` and `false` are read as strings.
+
+use anyhow::{bail, Context, Result};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+
+/// Parses `contents` as YAML, or as JSON (which is nearly a subset of it)
+pub fn from_slice<T: DeserializeOwned>(contents: &[u8]) -> Result<T> {
+    let contents = std::str::from_utf8(contents).context("not UTF-8")?;
+
+    let value = match serde_json::from_str::<Value>(contents) {
+        Ok(value) => value,
+        Err(_) => parse(contents)?,
+    };
+
+    Ok(serde_json::from_value(value)?)
+}
+
+#[derive(Clone, Copy)]
+struct Line<'a> {
+    number: usize,
+    indent: usize,
+    text: &'a str,
+}
+
+/// The lines with content, without comments or document markers
+fn lines(contents: &str) -> Result<Vec<Line<'_>>> {
+    let mut lines = Vec::new();
+
+    for (i, line) in contents.trim_start_matches('\u{feff}').lines().enumerate() {
+        let number = i + 1;
+        let line = strip_comment(line).trim_end();
+        let text = line.trim_start_matches(' ');
+        let indent = line.len() - text.len();
+
+        if text.is_empty() {
+            continue;
+        }
+        if text.starts_with('\t') {
+            bail!("line {number}: tabs can't be used for indentation");
+        }
+
+        if indent == 0 {
+            if text == "---" || text.starts_with("--- ") {
+                if !lines.is_empty() {
+                    bail!("line {number}: multiple documents aren't supported");
+                }
+                continue;
+            }
+            if text == "..." {
+                break;
+            }
+            if text.starts_with('%') && lines.is_empty() {
+                continue;
+            }
+        }
+
+        lines.push(Line { number, indent, text });
+    }
+
+    Ok(lines)
+}
+
+/// `line` up to its comment, if it has one
+fn strip_comment(line: &str) -> &str {
+    let bytes = line.as_bytes();
+    let mut quote = None;
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        match quote {
+            Some(b'"') if c == b'\\' => i += 1,
+            Some(b'\'') if c == b'\'' && bytes.get(i + 1) == Some(&b'\'') => i += 1,
+            Some(q) if c == q => quote = None,
+            Some(_) => (),
+            None if c == b'#' && (i == 0 || matches!(bytes[i - 1], b' ' | b'\t')) => return &line[..i],
+            None if matches!(c, b'"' | b'\'') && (i == 0 || b" \t[{,".contains(&bytes[i - 1])) => quote = Some(c),
+            None => (),
+        }
+        i += 1;
+    }
+
+    line
+}
+
+fn parse(contents: &str) -> Result<Value> {
+    let mut parser = Parser { lines: lines(contents)?, pos: 0 };
+
+    let Some(first) = parser.peek() else {
+        return Ok(Value::Null);
+    };
+    let value = parser.node(first.indent)?;
+
+    if let Some(line) = parser.peek() {
+        bail!("line {}: unexpected indentation (multi-line scalars aren't supported)", line.number);
+    }
+
+    Ok(value)
+}
+
+struct Parser<'a> {
+    lines: Vec<Line<'a>>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<Line<'a>> {
+        self.lines.get(self.pos).copied()
+    }
+
+    /// The node starting at the current line, which is indented by `indent`
+    fn node(&mut self, indent: usize) -> Result<Value> {
+        let line = self.lines[self.pos];
+
+        if is_sequence_item(line.text) {
+            self.sequence(indent)
+        } else if split_key(line.text, line.number)?.is_some() {
+            self.mapping(indent)
+        } else {
+            self.pos += 1;
+            scalar(line.text, line.number)
+        }
+    }
+
+    /// The value of a `key:` or `-` with nothing after it: the following, further indented lines, if any.
+    /// Sequences in mappings may also be at the key's indentation
+    fn block(&mut self, indent: usize, in_mapping: bool) -> Result<Value> {
+        match self.peek() {
+            Some(line) if line.indent > indent => self.node(line.indent),
+            Some(line) if in_mapping && line.indent == indent && is_sequence_item(line.text) => self.sequence(indent),
+            _ => Ok(Value::Null),
+        }
+    }
+
+    fn sequence(&mut self, indent: usize) -> Result<Value> {
+        let mut items = Vec::new();
+
+        while let Some(line) = self.peek().filter(|line| line.indent == indent && is_sequence_item(line.text)) {
+            let rest = line.text[1..].trim_start_matches(' ');
+
+            if rest.is_empty() {
+                self.pos += 1;
+                items.push(self.block(indent, false)?);
+            } else {
+                // the item starts on the dash's line, so it's read as if it were on a line of its own
+                let indent = indent + line.text.len() - rest.len();
+                self.lines[self.pos] = Line { indent, text: rest, ..line };
+                items.push(self.node(indent)?);
+            }
+        }
+
+        Ok(Value::Array(items))
+    }
+
+    fn mapping(&mut self, indent: usize) -> Result<Value> {
+        let mut map = Map::new();
+
+        while let Some(line) = self.peek().filter(|line| line.indent == indent) {
+            if is_sequence_item(line.text) {
+                bail!("line {}: expected a `key: value`, not a sequence item", line.number);
+            }
+            let Some((key, rest)) = split_key(line.text, line.number)? else {
+                bail!("line {}: expected a `key: value`", line.number);
+            };
+            self.pos += 1;
+
+            let value = match rest {
+                "" => self.block(indent, true)?,
+                rest => scalar(rest, line.number)?,
+            };
+            map.insert(key, value);
+        }
+
+        Ok(Value::Object(map))
+    }
+}
+
+fn is_sequence_item(text: &str) -> bool {
+    text == "-" || text.starts_with("- ")
+}
+
+/// The key of a `key: value` and what follows it, or `None` if `text` isn't one
+fn split_key(text: &str, number: usize) -> Result<Option<(String, &str)>> {
+    let (key, rest) = match text.as_bytes()[0] {
+        b'"' | b'\'' => {
+            let (key, rest) = quoted(text, number)?;
+            match rest.trim_start_matches(' ').strip_prefix(':') {
+                Some(rest) if rest.is_empty() || rest.starts_with(' ') => (key, rest),
+                _ => return Ok(None),
+            }
+        },
+        b'[' | b'{' => return Ok(None),
+        _ => match text.find(": ") {
+            Some(i) => (text[..i].trim_end().to_string(), &text[i + 1..]),
+            None => match text.strip_suffix(':') {
+                Some(key) => (key.trim_end().to_string(), ""),
+                None => return Ok(None),
+            },
+        },
+    };
+
+    Ok(Some((key, rest.trim())))
+}
+
+/// The quoted scalar at the start of `text`, and what follows it
+fn quoted(text: &str, number: usize) -> Result<(String, &str)> {
+    let quote = text.as_bytes()[0] as char;
+    let mut value = String::new();
+
+    let mut chars = text.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\'' if quote == '\'' => {
+                if !text[i + 1..].starts_with('\'') {
+                    return Ok((value, &text[i + 1..]));
+                }
+                chars.next();
+                value.push('\'');
+            },
+            '"' if quote == '"' => return Ok((value, &text[i + 1..])),
+            '\\' if quote == '"' => {
+                let Some((_, escape)) = chars.next() else { break };
+                let hex_digits = match escape {
+                    'x' => 2,
+                    'u' => 4,
+                    'U' => 8,
+                    _ => 0,
+                };
+                if hex_digits > 0 {
+                    let hex: String = chars.by_ref().take(hex_digits).map(|(_, c)| c).collect();
+                    let c = u32::from_str_radix(&hex, 16).ok().filter(|_| hex.len() == hex_digits).and_then(char::from_u32)
+                        .with_context(|| format!("line {number}: invalid escape \\{escape}{hex}"))?;
+                    value.push(c);
+                    continue;
+                }
+                value.push(match escape {
+                    '0' => '\0',
+                    'a' => '\x07',
+                    'b' => '\x08',
+                    't' | '\t' => '\t',
+                    'n' => '\n',
+                    'v' => '\x0b',
+                    'f' => '\x0c',
+                    'r' => '\r',
+                    'e' => '\x1b',
+                    ' ' | '"' | '/' | '\\' => escape,
+                    _ => bail!("line {number}: invalid escape \\{escape}"),
+                });
+            },
+            c => value.push(c),
+        }
+    }
+
+    bail!("line {number}: unterminated quoted scalar (multi-line scalars aren't supported)")
+}
+
+fn scalar(text: &str, number: usize) -> Result<Value> {
+    match text.as_bytes()[0] {
+        b'"' | b'\'' => {
+            let (value, rest) = quoted(text, number)?;
+            if !rest.trim().is_empty() {
+                bail!("line {number}: unexpected \"{}\" after a quoted scalar", rest.trim());
+            }
+            Ok(Value::String(value))
+        },
+        b'[' | b'{' => flow(text, number),
+        b'|' | b'>' => bail!("line {number}: block scalars (`|`, `>`) aren't supported"),
+        b'&' | b'*' | b'!' => bail!("line {number}: anchors, aliases and tags aren't supported"),
+        _ => Ok(match text {
+            "null" | "Null" | "NULL" | "~" => Value::Null,
+            "true" | "True" | "TRUE" => Value::Bool(true),
+            "false" | "False" | "FALSE" => Value::Bool(false),
+            text => Value::String(text.to_string()),
+        }),
+    }
+}
+
+/// A flow sequence (`[a, b]`) or mapping (`{a: b}`) of scalars, on one line
+fn flow(text: &str, number: usize) -> Result<Value> {
+    let is_mapping = text.starts_with('{');
+    let Some(inner) = text[1..].strip_suffix(if is_mapping { '}' } else { ']' }) else {
+        bail!("line {number}: flow collections must be on one line");
+    };
+
+    let mut items = Vec::new();
+    let mut quote = None;
+    let mut start = 0;
+    for (i, c) in inner.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => (),
+            None => match c {
+                '"' | '\'' => quote = Some(c),
+                '[' | '{' | ']' | '}' => bail!("line {number}: nested flow collections aren't supported"),
+                ',' => {
+                    items.push(inner[start..i].trim());
+                    start = i + 1;
+                },
+                _ => (),
+            },
+        }
+    }
+    items.push(inner[start..].trim());
+    if items.last() == Some(&"") {
+        items.pop();
+    }
+
+    if is_mapping {
+        let mut map = Map::new();
+        for item in items {
+            let (key, value) = match split_key(item, number)? {
+                Some((key, "")) => (key, Value::Null),
+                Some((key, value)) => (key, scalar(value, number)?),
+                None => bail!("line {number}: expected a `key: value` in a flow mapping"),
+            };
+            map.insert(key, value);
+        }
+        Ok(Value::Object(map))
+    } else {
+        Ok(Value::Array(items.into_iter()
+            .map(|item| match item {
+                "" => bail!("line {number}: empty item in a flow sequence"),
+                item => scalar(item, number),
+            })
+            .collect::<Result<_>>()?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let kubeconfig = r#"apiVersion: v1
+clusters:
+- cluster:
+    certificate-authority-data: LS0tLS1CRUdJTg==
+    server: https://k8s.example.net:6443
+  name: prod
+contexts:
+- context:
+    cluster: prod
+    namespace: "web"   # a comment
+    user: admin
+  name: admin@prod
+current-context: admin@prod
+kind: Config
+preferences: {}
+users:
+- name: admin
+  user:
+    token: 'it''s # not a comment'
+- name: sso
+  user:
+    exec:
+      apiVersion: client.authentication.k8s.io/v1beta1
+      args: [get-token, "--login", 'azurecli']
+      command: kubelogin
+      env: null
+      provideClusterInfo: false
+"#;
+
+        assert_eq!(parse(kubeconfig).unwrap(), json!({
+            "apiVersion": "v1",
+            "clusters": [{ "cluster": { "certificate-authority-data": "LS0tLS1CRUdJTg==", "server": "https://k8s.example.net:6443" }, "name": "prod" }],
+            "contexts": [{ "context": { "cluster": "prod", "namespace": "web", "user": "admin" }, "name": "admin@prod" }],
+            "current-context": "admin@prod",
+            "kind": "Config",
+            "preferences": {},
+            "users": [
+                { "name": "admin", "user": { "token": "it's # not a comment" } },
+                { "name": "sso", "user": { "exec": {
+                    "apiVersion": "client.authentication.k8s.io/v1beta1",
+                    "args": ["get-token", "--login", "azurecli"],
+                    "command": "kubelogin",
+                    "env": null,
+                    "provideClusterInfo": false,
+                } } },
+            ],
+        }));
+
+        assert_eq!(parse("---\nitems:\n  -\n    - a\n    - \"b\\u00e9\\n\"\n  - c: [1, 2]\n    d:\n").unwrap(), json!({
+            "items": [["a", "bé\n"], { "c": ["1", "2"], "d": null }],
+        }));
+
+        assert_eq!(from_slice::<Value>(br#"{"kind": "Config"}"#).unwrap(), json!({ "kind": "Config" }));
+
+        let e = parse("a: b\n  c: d\n").unwrap_err();
+        assert_eq!(e.to_string(), "line 2: unexpected indentation (multi-line scalars aren't supported)");
+
+        let e = parse("a:\n\tb: c\n").unwrap_err();
+        assert_eq!(e.to_string(), "line 2: tabs can't be used for indentation");
+
+        let e = parse("a: |\n  text\n").unwrap_err();
+        assert!(e.to_string().contains("block scalars"), "{e}");
+
+        let e = parse("a: b\n- c\n").unwrap_err();
+        assert!(e.to_string().contains("not a sequence item"), "{e}");
+
+        let e = parse("a: b\n---\nc: d\n").unwrap_err();
+        assert!(e.to_string().contains("multiple documents"), "{e}");
+    }
+}